@@ -0,0 +1,91 @@
+//! Which commit of the stack a `fixup!`, `squash!` or `amend!` commit
+//! will be squashed into, following the same rules as
+//! `git rebase --autosquash`.
+
+/// The subject prefixes that autosquash moves commits by.
+const PREFIXES: &[&str] = &["fixup! ", "squash! ", "amend! "];
+
+/// Where a commit ends up once the stack is autosquashed.
+pub enum Target<'a, 'r> {
+    /// The commit itself, or the commit of the stack that it will be
+    /// squashed into.
+    Commit(&'a git2::Commit<'r>),
+    /// A commit outside of the stack, by the subject or object name
+    /// that its fixups refer to it by.
+    Subject(String),
+}
+
+/// Strips every autosquash prefix from `summary`, eg `foo` for
+/// `fixup! fixup! foo`. None if it has no prefix.
+pub fn strip_prefixes(summary: &str) -> Option<&str> {
+    let mut rest = summary;
+    while let Some(stripped) = PREFIXES.iter().find_map(|prefix| rest.strip_prefix(prefix)) {
+        rest = stripped;
+    }
+    (rest.len() != summary.len()).then_some(rest)
+}
+
+/// Follows `commit` through any chain of fixups to the commit it will
+/// be squashed into. `stack` is ordered from newest to oldest, like the
+/// absorb stack.
+pub fn target<'a, 'r>(
+    commit: &'a git2::Commit<'r>,
+    stack: &[&'a git2::Commit<'r>],
+) -> Target<'a, 'r> {
+    let mut commit = commit;
+    loop {
+        let subject = match strip_prefixes(commit.summary().unwrap_or("")) {
+            Some(subject) => subject,
+            None => return Target::Commit(commit),
+        };
+        // a fixup can only be squashed into an older commit, and like
+        // git, the oldest one with the subject wins
+        let position = stack
+            .iter()
+            .position(|c| c.id() == commit.id())
+            .unwrap_or(stack.len());
+        let older = || stack.get(position + 1..).unwrap_or_default().iter().rev();
+        let found = older()
+            .find(|c| c.summary() == Some(subject))
+            .or_else(|| {
+                older().find(|c| subject.len() >= 4 && c.id().to_string().starts_with(subject))
+            })
+            .or_else(|| older().find(|c| c.summary().is_some_and(|s| s.starts_with(subject))));
+        match found {
+            Some(found) => commit = found,
+            None => return Target::Subject(subject.to_owned()),
+        }
+    }
+}
+
+/// The message that `commit` will have once the stack is autosquashed,
+/// which is the body of the newest `amend!` commit for it, if any.
+pub fn final_message(commit: &git2::Commit, stack: &[&git2::Commit]) -> String {
+    let amended = stack.iter().find(|c| {
+        c.summary().is_some_and(|s| s.starts_with("amend! "))
+            && matches!(target(c, stack), Target::Commit(t) if t.id() == commit.id())
+    });
+    match amended {
+        Some(amend) => {
+            let message = amend.message().unwrap_or("");
+            message
+                .split_once("\n\n")
+                .map_or("", |(_, body)| body)
+                .to_owned()
+        }
+        None => commit.message().unwrap_or("").to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_prefixes() {
+        assert_eq!(strip_prefixes("fixup! foo"), Some("foo"));
+        assert_eq!(strip_prefixes("fixup! fixup! foo"), Some("foo"));
+        assert_eq!(strip_prefixes("squash! amend! foo"), Some("foo"));
+        assert_eq!(strip_prefixes("foo"), None);
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+
+use crate::{owned, repo_path};
+use std::collections::HashMap;
+
+/// Finds destination commits by blaming the lines a hunk touches,
+/// rather than by commuting it.
+pub struct Blamer<'repo> {
+    repo: &'repo git2::Repository,
+    head: git2::Oid,
+    blames: HashMap<Vec<u8>, Option<git2::Blame<'repo>>>,
+}
+
+impl<'repo> Blamer<'repo> {
+    pub fn new(repo: &'repo git2::Repository) -> Result<Self> {
+        Ok(Blamer {
+            repo,
+            head: repo.head()?.peel_to_commit()?.id(),
+            blames: HashMap::new(),
+        })
+    }
+
+    /// Returns the index into `stack` of the newest commit that last
+    /// touched the lines removed by `hunk`, or for a pure addition,
+    /// the lines around it. `hunk` must be relative to HEAD.
+    pub fn newest_commit<'a, I>(
+        &mut self,
+        path: &[u8],
+        hunk: &owned::Hunk,
+        stack: I,
+    ) -> Result<Option<usize>>
+    where
+        I: IntoIterator<Item = &'a git2::Commit<'a>>,
+    {
+        let blame = match self.blame(path)? {
+            Some(blame) => blame,
+            None => return Ok(None),
+        };
+
+        let lines = if hunk.removed.lines.is_empty() {
+            // an addition after line N touches lines N and N+1
+            hunk.removed.start..hunk.removed.start + 2
+        } else {
+            hunk.removed.start..hunk.removed.start + hunk.removed.lines.len()
+        };
+        let blamed: Vec<git2::Oid> = lines
+            .filter(|&line| line > 0)
+            .filter_map(|line| blame.get_line(line))
+            .map(|blame_hunk| blame_hunk.final_commit_id())
+            .collect();
+
+        // the stack is ordered from newest to oldest
+        Ok(stack
+            .into_iter()
+            .position(|commit| blamed.contains(&commit.id())))
+    }
+
+    fn blame(&mut self, path: &[u8]) -> Result<Option<&git2::Blame<'repo>>> {
+        if !self.blames.contains_key(path) {
+            let blame = match repo_path::to_path(path) {
+                Some(path) => {
+                    let mut options = git2::BlameOptions::new();
+                    options.newest_commit(self.head);
+                    Some(self.repo.blame_file(path, Some(&mut options))?)
+                }
+                None => None,
+            };
+            self.blames.insert(path.to_vec(), blame);
+        }
+        Ok(self.blames[path].as_ref())
+    }
+}
@@ -1,5 +1,7 @@
 use crate::owned;
 
+use std::rc::Rc;
+
 /// Tests if all elements of the iterator are equal to each other.
 ///
 /// An empty iterator returns `true`.
@@ -18,7 +20,20 @@ where
     }
 }
 
-pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk, owned::Hunk)> {
+/// Refinements that let more hunks commute than plain line-based
+/// commutation does.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// Commute hunks that replace the same lines, if the lines that each
+    /// of them actually changes are apart from each other.
+    pub interleave_hunks: bool,
+}
+
+pub fn commute(
+    first: &owned::Hunk,
+    second: &owned::Hunk,
+    options: Options,
+) -> Option<(owned::Hunk, owned::Hunk)> {
     let (_, _, first_upper, first_lower) = first.anchors();
     let (second_upper, second_lower, _, _) = second.anchors();
 
@@ -29,6 +44,11 @@ pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk
         } else if second_lower <= first_upper {
             (false, second, first)
         } else {
+            // the newline at the end of the file belongs to whichever
+            // hunk changed it last, so it can't be interleaved either
+            if changes_trailing_newline(first) || changes_trailing_newline(second) {
+                return None;
+            }
             // if both hunks are exclusively adding or removing, and
             // both hunks are composed entirely of the same line being
             // repeated, then they commute no matter what their
@@ -45,6 +65,9 @@ pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk
                 // tweaked here
                 return Some((second.clone(), first.clone()));
             }
+            if options.interleave_hunks {
+                return interleave(first, second);
+            }
             // these hunks overlap and cannot be interleaved, so they
             // do not commute
             return None;
@@ -65,7 +88,206 @@ pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk
     })
 }
 
-pub fn commute_diff_before<'a, I>(after: &owned::Hunk, before: I) -> Option<owned::Hunk>
+/// Whether `hunk` adds or removes the newline at the end of the file.
+fn changes_trailing_newline(hunk: &owned::Hunk) -> bool {
+    hunk.removed.trailing_newline != hunk.added.trailing_newline
+}
+
+/// The number of unchanged lines on each side of a hunk that are used
+/// to re-anchor it.
+const REANCHOR_CONTEXT: usize = 3;
+
+/// Finds where `hunk`, which applies after `patch`, would apply before
+/// it, by searching the file before `patch` (`pre`) for the lines that
+/// the hunk removes and the unchanged lines around it in the file after
+/// `patch` (`post`). Up to `fuzz` of the surrounding lines may differ.
+///
+/// This lets a hunk commute past changes right next to it, which strict
+/// commutation does not allow. Returns `None` if there is no single
+/// best place for the hunk, or if that place overlaps the patch.
+pub fn reanchor(
+    hunk: &owned::Hunk,
+    patch: &[owned::Hunk],
+    pre: &[u8],
+    post: &[u8],
+    fuzz: usize,
+) -> Option<owned::Hunk> {
+    let pre: Vec<&[u8]> = pre.split_inclusive(|&byte| byte == b'\n').collect();
+    let post: Vec<&[u8]> = post.split_inclusive(|&byte| byte == b'\n').collect();
+    let removed: Vec<&[u8]> = hunk.removed.lines.iter().map(Vec::as_slice).collect();
+
+    // the index of the first removed line, or of the line that the
+    // added lines go before
+    let position = |block: &owned::Block| {
+        if block.lines.is_empty() {
+            block.start
+        } else {
+            block.start - 1
+        }
+    };
+    let at = position(&hunk.removed);
+    if post.get(at..at + removed.len())? != removed {
+        return None;
+    }
+    let above = &post[at.saturating_sub(REANCHOR_CONTEXT)..at];
+    let below = &post[at + removed.len()..(at + removed.len() + REANCHOR_CONTEXT).min(post.len())];
+
+    let mismatches = |start: usize| {
+        let above_mismatches = above
+            .iter()
+            .rev()
+            .enumerate()
+            .filter(|(i, line)| start.checked_sub(i + 1).and_then(|i| pre.get(i)) != Some(line))
+            .count();
+        let below_mismatches = below
+            .iter()
+            .enumerate()
+            .filter(|(i, line)| pre.get(start + removed.len() + i) != Some(line))
+            .count();
+        above_mismatches + below_mismatches
+    };
+
+    let mut best: Option<(usize, usize)> = None;
+    let mut best_is_unique = false;
+    for start in 0..=pre.len().saturating_sub(removed.len()) {
+        if pre.get(start..start + removed.len()) != Some(&removed) {
+            continue;
+        }
+        let count = mismatches(start);
+        if count > fuzz {
+            continue;
+        }
+        match best {
+            Some((_, best_count)) if best_count < count => {}
+            Some((_, best_count)) if best_count == count => best_is_unique = false,
+            _ => {
+                best = Some((start, count));
+                best_is_unique = true;
+            }
+        }
+    }
+    let start = match best {
+        Some((start, _)) if best_is_unique => start,
+        _ => return None,
+    };
+
+    // the patch must not touch the lines the hunk removes, or the
+    // place where it adds lines
+    let end = start + removed.len();
+    let overlaps = patch.iter().any(|patch_hunk| {
+        let patch_start = position(&patch_hunk.removed);
+        let patch_end = patch_start + patch_hunk.removed.lines.len();
+        if removed.is_empty() {
+            patch_start < start && start < patch_end
+        } else {
+            patch_start < end && start < patch_end
+        }
+    });
+    if overlaps {
+        return None;
+    }
+    // a line without a newline can only end the file, so a hunk that
+    // adds one must stay at the end, and nothing can be added after one
+    let pre_ends_without_newline = pre.last().is_some_and(|line| !line.ends_with(b"\n"));
+    if (!hunk.added.trailing_newline && end != pre.len())
+        || (removed.is_empty()
+            && !hunk.added.lines.is_empty()
+            && end == pre.len()
+            && pre_ends_without_newline)
+    {
+        return None;
+    }
+
+    let removed_start = if removed.is_empty() { start } else { start + 1 };
+    let offset = removed_start as isize - hunk.removed.start as isize;
+    let mut reanchored = hunk.clone();
+    reanchored.removed.start = removed_start;
+    reanchored.added.start = (reanchored.added.start as isize + offset) as usize;
+    Some(reanchored)
+}
+
+/// Commutes two hunks that replace the same lines, but change
+/// different ones of those lines.
+///
+/// Both hunks must replace the same number of lines, and the second
+/// hunk must remove exactly the lines that the first one added. The
+/// first hunk is still picked by a line-based rebase after the
+/// commuted second one, so they only commute if that merges cleanly.
+fn interleave(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk, owned::Hunk)> {
+    let len = first.removed.lines.len();
+    if first.added.start != second.removed.start
+        || first.added.lines != second.removed.lines
+        || first.added.lines.len() != len
+        || second.added.lines.len() != len
+        || !uniform([
+            first.removed.trailing_newline,
+            first.added.trailing_newline,
+            second.added.trailing_newline,
+        ])
+    {
+        return None;
+    }
+
+    // the lines as they would be if the second hunk were applied first
+    let mut middle = Vec::with_capacity(len);
+    for ((before, between), after) in first
+        .removed
+        .lines
+        .iter()
+        .zip(&*first.added.lines)
+        .zip(&*second.added.lines)
+    {
+        middle.push(if before == between {
+            after.clone()
+        } else if between == after {
+            before.clone()
+        } else {
+            // both hunks change this line
+            return None;
+        });
+    }
+    if !merges_cleanly(&first.removed.lines, &middle, &first.added.lines) {
+        return None;
+    }
+    let middle = Rc::new(middle);
+
+    let commuted_second = owned::Hunk {
+        removed: first.removed.clone(),
+        added: owned::Block {
+            start: second.added.start,
+            lines: middle.clone(),
+            trailing_newline: second.added.trailing_newline,
+        },
+    };
+    let commuted_first = owned::Hunk {
+        removed: owned::Block {
+            start: first.removed.start,
+            lines: middle,
+            trailing_newline: first.removed.trailing_newline,
+        },
+        added: second.added.clone(),
+    };
+    Some((commuted_second, commuted_first))
+}
+
+/// Returns true if git's line-based merge of `ours` and `theirs`, which
+/// both change the lines of `base`, has no conflicts. Like git, changes
+/// to the same or to adjacent lines conflict.
+fn merges_cleanly(base: &[Vec<u8>], ours: &[Vec<u8>], theirs: &[Vec<u8>]) -> bool {
+    let changed = |lines: &[Vec<u8>]| -> Vec<usize> {
+        (0..base.len()).filter(|&i| lines[i] != base[i]).collect()
+    };
+    let theirs = changed(theirs);
+    changed(ours)
+        .into_iter()
+        .all(|i| theirs.iter().all(|&j| i.abs_diff(j) > 1))
+}
+
+pub fn commute_diff_before<'a, I>(
+    after: &owned::Hunk,
+    before: I,
+    options: Options,
+) -> Option<owned::Hunk>
 where
     I: IntoIterator<Item = &'a owned::Hunk>,
     <I as IntoIterator>::IntoIter: DoubleEndedIterator,
@@ -78,14 +300,85 @@ where
         // happens to be reverse of the order they're stored
         .rev()
         .try_fold(after.clone(), |after, next| {
-            commute(next, &after).map(|(commuted_after, _)| commuted_after)
+            commute(next, &after, options).map(|(commuted_after, _)| commuted_after)
         })
 }
 
+/// The positions of a patch's hunks, prepared so that hunks which
+/// don't overlap any of them can be commuted past the whole patch
+/// without folding over every hunk.
+///
+/// Planning commutes every staged hunk of a file past the same patches,
+/// so these are built once per patch and reused.
+pub struct Shifts {
+    /// The added side anchors of each hunk, in order.
+    anchors: Vec<(usize, usize)>,
+    /// How far each hunk, and all of the hunks above it, move the
+    /// lines below them.
+    offsets: Vec<i64>,
+    /// Whether the anchors are in order, which the binary search
+    /// depends on.
+    sorted: bool,
+}
+
+impl Shifts {
+    pub fn new(before: &[owned::Hunk]) -> Self {
+        let anchors: Vec<_> = before
+            .iter()
+            .map(|hunk| {
+                let (_, _, upper, lower) = hunk.anchors();
+                (upper, lower)
+            })
+            .collect();
+        let sorted = anchors
+            .windows(2)
+            .all(|pair| pair[0].0 <= pair[1].0 && pair[0].1 <= pair[1].1);
+        let offsets = before
+            .iter()
+            .scan(0i64, |total, hunk| {
+                *total += hunk.changed_offset() as i64;
+                Some(*total)
+            })
+            .collect();
+        Shifts {
+            anchors,
+            offsets,
+            sorted,
+        }
+    }
+
+    /// Commutes `after` past the patch like `commute_diff_before`, if
+    /// it doesn't overlap any of the patch's hunks. Returns `None` if it
+    /// does, and the hunks have to be commuted one by one.
+    pub fn commute_before(&self, after: &owned::Hunk) -> Option<owned::Hunk> {
+        if !self.sorted {
+            return None;
+        }
+        let (upper, lower, _, _) = after.anchors();
+        // the hunks above `after`, which shift it up or down
+        let above = self
+            .anchors
+            .partition_point(|&(_, hunk_lower)| hunk_lower <= upper);
+        // the rest must be entirely below it
+        if let Some(&(hunk_upper, _)) = self.anchors.get(above) {
+            if lower > hunk_upper {
+                return None;
+            }
+        }
+        let offset = match above {
+            0 => 0,
+            above => self.offsets[above - 1],
+        };
+        let mut after = after.clone();
+        after.added.start = (after.added.start as i64 - offset) as usize;
+        after.removed.start = (after.removed.start as i64 - offset) as usize;
+        Some(after)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::rc::Rc;
 
     #[test]
     fn test_commute() {
@@ -126,7 +419,7 @@ mod tests {
         // bar
         // EOF
 
-        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        let (new1, new2) = commute(&hunk1, &hunk2, Options::default()).unwrap();
         assert_eq!(new1.added.start, 1);
         assert_eq!(new2.added.start, 3);
     }
@@ -159,7 +452,7 @@ mod tests {
             },
         };
 
-        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        let (new1, new2) = commute(&hunk1, &hunk2, Options::default()).unwrap();
         assert_eq!(new1.added.lines.len(), 2);
         assert_eq!(new2.added.lines.len(), 4);
     }
@@ -192,7 +485,7 @@ mod tests {
             },
         };
 
-        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        let (new1, new2) = commute(&hunk1, &hunk2, Options::default()).unwrap();
         assert_eq!(new1.removed.lines.len(), 2);
         assert_eq!(new2.removed.lines.len(), 4);
     }
@@ -255,7 +548,199 @@ mod tests {
         // bar
         // EOF
 
-        let commuted = commute_diff_before(&hunk, &patch).unwrap();
+        let commuted = commute_diff_before(&hunk, &patch, Options::default()).unwrap();
         assert_eq!(commuted.added.start, 3);
     }
+
+    #[test]
+    fn test_shifts() {
+        let block = |start, len, line: &[u8]| owned::Block {
+            start,
+            lines: Rc::new(vec![line.to_vec(); len]),
+            trailing_newline: true,
+        };
+        let hunk = |removed_start, removed_len, added_start, added_len, line: &[u8]| owned::Hunk {
+            removed: block(removed_start, removed_len, line),
+            added: block(added_start, added_len, line),
+        };
+        // replaces a line with two, removes two lines, adds a line, and
+        // replaces three lines with one
+        let patch = vec![
+            hunk(3, 1, 3, 2, b"patch\n"),
+            hunk(8, 2, 8, 0, b"patch\n"),
+            hunk(15, 0, 16, 1, b"patch\n"),
+            hunk(20, 3, 21, 1, b"patch\n"),
+        ];
+        let shifts = Shifts::new(&patch);
+
+        let mut fast = 0;
+        for start in 1..30 {
+            for (removed_len, added_len) in [(0, 1), (1, 0), (1, 1), (2, 1), (1, 3)] {
+                let added_start = if removed_len == 0 { start + 1 } else { start };
+                let after = hunk(start, removed_len, added_start, added_len, b"after\n");
+                let commuted = commute_diff_before(&after, &patch, Options::default());
+                // without any overlap, the hunks always commute, and
+                // the fast path must agree with commuting one by one
+                match shifts.commute_before(&after) {
+                    Some(shifted) => {
+                        let commuted = commuted.unwrap();
+                        assert_eq!(shifted.removed.start, commuted.removed.start);
+                        assert_eq!(shifted.added.start, commuted.added.start);
+                        fast += 1;
+                    }
+                    None => assert!(commuted.is_none()),
+                }
+            }
+        }
+        assert_eq!(fast, 85);
+    }
+
+    #[test]
+    fn test_interleave_hunks() {
+        let lines = |start, lines: &[&[u8]]| owned::Block {
+            start,
+            lines: Rc::new(lines.iter().map(|line| line.to_vec()).collect()),
+            trailing_newline: true,
+        };
+        let options = Options {
+            interleave_hunks: true,
+        };
+
+        // the lines that each hunk changes are apart from each other
+        let first = owned::Hunk {
+            removed: lines(2, &[b"a x\n", b"b\n", b"c\n", b"d\n", b"e x\n"]),
+            added: lines(2, &[b"a y\n", b"b\n", b"c\n", b"d\n", b"e y\n"]),
+        };
+        let second = owned::Hunk {
+            removed: first.added.clone(),
+            added: lines(2, &[b"a y\n", b"b\n", b"c z\n", b"d\n", b"e y\n"]),
+        };
+        assert!(commute(&first, &second, Options::default()).is_none());
+        let (new_second, new_first) = commute(&first, &second, options).unwrap();
+        assert_eq!(
+            *new_second.added.lines,
+            lines(2, &[b"a x\n", b"b\n", b"c z\n", b"d\n", b"e x\n"]).lines[..]
+        );
+        assert_eq!(new_first.removed.lines, new_second.added.lines);
+        assert_eq!(new_first.added.lines, second.added.lines);
+
+        // git's merge conflicts on changes to adjacent lines
+        let second = owned::Hunk {
+            removed: first.added.clone(),
+            added: lines(2, &[b"a y\n", b"b z\n", b"c\n", b"d\n", b"e y\n"]),
+        };
+        assert!(commute(&first, &second, options).is_none());
+
+        // and on changes to the same line, even to different words of it
+        let second = owned::Hunk {
+            removed: first.added.clone(),
+            added: lines(2, &[b"z y\n", b"b\n", b"c\n", b"d\n", b"e y\n"]),
+        };
+        assert!(commute(&first, &second, options).is_none());
+    }
+
+    #[test]
+    fn test_reanchor() {
+        // the patch changes "b", right next to the hunk's "c"
+        let pre = b"a\nb\nc\nd\n";
+        let post = b"a\nB\nc\nd\n";
+        let block = |start, lines: &[&[u8]]| owned::Block {
+            start,
+            lines: Rc::new(lines.iter().map(|line| line.to_vec()).collect()),
+            trailing_newline: true,
+        };
+        let patch = vec![owned::Hunk {
+            removed: block(2, &[b"b\n"]),
+            added: block(2, &[b"B\n"]),
+        }];
+        let hunk = owned::Hunk {
+            removed: block(3, &[b"c\n"]),
+            added: block(3, &[b"C\n"]),
+        };
+        assert!(commute_diff_before(&hunk, &patch, Options::default()).is_none());
+
+        assert!(reanchor(&hunk, &patch, pre, post, 0).is_none());
+        let reanchored = reanchor(&hunk, &patch, pre, post, 1).unwrap();
+        assert_eq!(reanchored.removed.start, 3);
+        assert_eq!(reanchored.added.start, 3);
+
+        // the hunk cannot be re-anchored onto the lines the patch changes
+        let hunk = owned::Hunk {
+            removed: block(2, &[b"B\n"]),
+            added: block(2, &[b"b2\n"]),
+        };
+        assert!(reanchor(&hunk, &patch, pre, post, 3).is_none());
+    }
+
+    #[test]
+    fn test_commute_newline_status() {
+        let block = |start, lines: &[&[u8]], trailing_newline| owned::Block {
+            start,
+            lines: Rc::new(lines.iter().map(|line| line.to_vec()).collect()),
+            trailing_newline,
+        };
+
+        // the same line added twice at the end commutes, unless one of
+        // them leaves the file without a newline
+        let add = |trailing_newline| {
+            let line: &[u8] = if trailing_newline { b"x\n" } else { b"x" };
+            owned::Hunk {
+                removed: block(1, &[], true),
+                added: block(2, &[line], trailing_newline),
+            }
+        };
+        assert!(commute(&add(true), &add(true), Options::default()).is_some());
+        assert!(commute(&add(false), &add(false), Options::default()).is_none());
+
+        // a hunk that only adds the newline to a line that the other
+        // hunk changed
+        let first = owned::Hunk {
+            removed: block(2, &[b"a b"], false),
+            added: block(2, &[b"a c"], false),
+        };
+        let second = owned::Hunk {
+            removed: block(2, &[b"a c"], false),
+            added: block(2, &[b"a c\n"], true),
+        };
+        let options = Options {
+            interleave_hunks: true,
+        };
+        assert!(commute(&first, &second, options).is_none());
+    }
+
+    #[test]
+    fn test_reanchor_missing_newline_at_end() {
+        let block = |start, lines: &[&[u8]], trailing_newline| owned::Block {
+            start,
+            lines: Rc::new(lines.iter().map(|line| line.to_vec()).collect()),
+            trailing_newline,
+        };
+
+        // the patch removes the last line, so the hunk, which adds a
+        // line without a newline at the end, would end up above "x"
+        let pre = b"a\nb\nc\nx\n";
+        let post = b"a\nb\nc\n";
+        let patch = vec![owned::Hunk {
+            removed: block(4, &[b"x\n"], true),
+            added: block(3, &[], true),
+        }];
+        let hunk = owned::Hunk {
+            removed: block(3, &[], true),
+            added: block(4, &[b"z"], false),
+        };
+        assert!(reanchor(&hunk, &patch, pre, post, 1).is_none());
+
+        // the patch adds the newline that the hunk adds lines after
+        let pre = b"a\nb\nc";
+        let post = b"a\nb\nc\n";
+        let patch = vec![owned::Hunk {
+            removed: block(3, &[b"c"], false),
+            added: block(3, &[b"c\n"], true),
+        }];
+        let hunk = owned::Hunk {
+            removed: block(3, &[], true),
+            added: block(4, &[b"d\n"], true),
+        };
+        assert!(reanchor(&hunk, &patch, pre, post, 1).is_none());
+    }
 }
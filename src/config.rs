@@ -1,3 +1,5 @@
+use crate::{Config, Error, Strategy};
+
 pub const MAX_STACK_CONFIG_NAME: &str = "absorb.maxStack";
 pub const MAX_STACK: usize = 10;
 
@@ -10,6 +12,169 @@ pub const AUTO_STAGE_IF_NOTHING_STAGED_DEFAULT: bool = false;
 pub const FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME: &str = "absorb.fixupTargetAlwaysSHA";
 pub const FIXUP_TARGET_ALWAYS_SHA_DEFAULT: bool = false;
 
+pub const CONVENTIONAL_COMMITS_CONFIG_NAME: &str = "absorb.conventionalCommits";
+pub const CONVENTIONAL_COMMITS_DEFAULT: bool = false;
+
+pub const TRAILERS_CONFIG_NAME: &str = "absorb.trailers";
+
+pub const EXIT_CODE_CONFIG_NAME: &str = "absorb.exitCode";
+pub const EXIT_CODE_DEFAULT: bool = false;
+
+pub const AUTO_DETECT_BASE_CONFIG_NAME: &str = "absorb.autoDetectBase";
+pub const AUTO_DETECT_BASE_DEFAULT: bool = false;
+
+pub const DEFAULT_BASE_CONFIG_NAME: &str = "absorb.defaultBase";
+
+pub const MAX_FILE_SIZE_CONFIG_NAME: &str = "absorb.maxFileSize";
+
+pub const REPLACE_BINARY_FILES_CONFIG_NAME: &str = "absorb.replaceBinaryFiles";
+pub const REPLACE_BINARY_FILES_DEFAULT: bool = false;
+
+pub const FIND_COPIES_CONFIG_NAME: &str = "absorb.findCopies";
+pub const FIND_COPIES_DEFAULT: bool = false;
+
+pub const CONTEXT_LINES_CONFIG_NAME: &str = "absorb.contextLines";
+pub const CONTEXT_LINES_DEFAULT: usize = 0;
+
+pub const SPLIT_HUNKS_CONFIG_NAME: &str = "absorb.splitHunks";
+pub const SPLIT_HUNKS_DEFAULT: bool = false;
+
+pub const AUTO_EXTEND_STACK_CONFIG_NAME: &str = "absorb.autoExtendStack";
+pub const AUTO_EXTEND_STACK_DEFAULT: bool = false;
+/// How far absorb.autoExtendStack widens the stack, at most.
+pub const AUTO_EXTEND_STACK_LIMIT: usize = 1000;
+
+pub const STRATEGY_CONFIG_NAME: &str = "absorb.strategy";
+pub const STRATEGY_DEFAULT: Strategy = Strategy::Commute;
+
+pub const INTERLEAVE_HUNKS_CONFIG_NAME: &str = "absorb.interleaveHunks";
+pub const INTERLEAVE_HUNKS_DEFAULT: bool = false;
+
+pub const FUZZ_CONFIG_NAME: &str = "absorb.fuzz";
+pub const FUZZ_DEFAULT: usize = 0;
+
+pub const DIFF_ALGORITHM_CONFIG_NAME: &str = "absorb.diffAlgorithm";
+pub const GIT_DIFF_ALGORITHM_CONFIG_NAME: &str = "diff.algorithm";
+
+pub const FIXUP_AUTHOR_IS_TARGET_AUTHOR_CONFIG_NAME: &str = "absorb.fixupAuthorIsTargetAuthor";
+pub const FIXUP_AUTHOR_IS_TARGET_AUTHOR_DEFAULT: bool = false;
+
+pub const ALLOWED_AUTHORS_CONFIG_NAME: &str = "absorb.allowedAuthors";
+
+pub const AUTHOR_MATCH_CONFIG_NAME: &str = "absorb.authorMatch";
+pub const AUTHOR_MATCH_DEFAULT: AuthorMatch = AuthorMatch::Both;
+
+pub const CROSS_MERGES_CONFIG_NAME: &str = "absorb.crossMerges";
+pub const CROSS_MERGES_DEFAULT: bool = false;
+
+pub const STOP_AT_SIGNED_COMMITS_CONFIG_NAME: &str = "absorb.stopAtSignedCommits";
+pub const STOP_AT_SIGNED_COMMITS_DEFAULT: bool = false;
+
+pub const GIT_REBASE_UPDATE_REFS_CONFIG_NAME: &str = "rebase.updateRefs";
+pub const GIT_REBASE_UPDATE_REFS_DEFAULT: bool = false;
+
+pub const REBASE_NON_INTERACTIVE_CONFIG_NAME: &str = "absorb.rebaseNonInteractive";
+pub const REBASE_NON_INTERACTIVE_DEFAULT: bool = false;
+
+// unlike git, which defaults both to false, since absorbing is mostly
+// pointless without them
+pub const GIT_REBASE_AUTOSQUASH_CONFIG_NAME: &str = "rebase.autoSquash";
+pub const GIT_REBASE_AUTOSQUASH_DEFAULT: bool = true;
+
+pub const GIT_REBASE_AUTOSTASH_CONFIG_NAME: &str = "rebase.autoStash";
+pub const GIT_REBASE_AUTOSTASH_DEFAULT: bool = true;
+
+pub const FORBID_PUSHED_TARGETS_CONFIG_NAME: &str = "absorb.forbidPushedTargets";
+pub const FORBID_PUSHED_TARGETS_DEFAULT: bool = false;
+
+pub const RUN_HOOKS_CONFIG_NAME: &str = "absorb.runHooks";
+pub const RUN_HOOKS_DEFAULT: bool = false;
+
+pub const PREPARE_MESSAGE_CONFIG_NAME: &str = "absorb.prepareMessage";
+pub const PREPARE_MESSAGE_DEFAULT: bool = false;
+
+pub const IDENTITY_TRAILERS_CONFIG_NAME: &str = "absorb.identityTrailers";
+pub const IDENTITY_TRAILERS_DEFAULT: &[&str] = &["Change-Id"];
+
+pub const COPY_IDENTITY_TRAILERS_CONFIG_NAME: &str = "absorb.copyIdentityTrailers";
+pub const COPY_IDENTITY_TRAILERS_DEFAULT: bool = false;
+
+pub const REFLOG_MESSAGE_CONFIG_NAME: &str = "absorb.reflogMessage";
+pub const REFLOG_MESSAGE_DEFAULT: &str = "absorb: created {count} fixups for {range}";
+
+pub const PRE_ABSORB_REF_CONFIG_NAME: &str = "absorb.preAbsorbRef";
+pub const PRE_ABSORB_REF_DEFAULT: &str = "PRE_ABSORB_HEAD";
+
+pub const CONFIRM_CONFIG_NAME: &str = "absorb.confirm";
+pub const CONFIRM_DEFAULT: bool = false;
+
+pub const AUTO_STAGE_PATHSPEC_CONFIG_NAME: &str = "absorb.autoStagePathspec";
+
+pub const KEEP_AUTO_STAGED_LEFTOVERS_CONFIG_NAME: &str = "absorb.keepAutoStagedLeftovers";
+pub const KEEP_AUTO_STAGED_LEFTOVERS_DEFAULT: bool = false;
+
+pub const AUTO_STAGE_UNTRACKED_CONFIG_NAME: &str = "absorb.autoStageUntracked";
+pub const AUTO_STAGE_UNTRACKED_DEFAULT: bool = false;
+
+pub const PATH_TARGET_CONFIG_NAME: &str = "absorb.pathTarget";
+
+pub const MATCHER_COMMAND_CONFIG_NAME: &str = "absorb.matcherCommand";
+
+pub const WHOLE_FILE_PATHS_CONFIG_NAME: &str = "absorb.wholeFilePaths";
+
+pub const EXCLUDE_COMMITS_CONFIG_NAME: &str = "absorb.excludeCommits";
+
+/// Which parts of a commit's author must match the user for the commit
+/// to be considered theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorMatch {
+    Email,
+    Name,
+    Both,
+}
+
+/// Fills in the options that were not given on the command line from
+/// the git config. Fails if --confirm was given without a way to ask.
+pub fn unify<'a>(config: &Config<'a>, repo: &git2::Repository) -> Result<Config<'a>, Error> {
+    if config.confirm && config.ask.is_none() {
+        return Err(Error::NobodyToConfirm);
+    }
+    Ok(Config {
+        // here, we default to the git config value,
+        // if the flag was not provided in the CLI.
+        //
+        // in the future, we'd likely want to differentiate between
+        // a "non-provided" option, vs an explicit --no-<option>
+        // that disables a behavior, much like git does.
+        // e.g. user may want to overwrite a config value with
+        // --no-one-fixup-per-commit -- then, defaulting to the config value
+        // like we do here is no longer sufficient. but until then, this is fine.
+        one_fixup_per_commit: config.one_fixup_per_commit || one_fixup_per_commit(repo),
+        // patches are written instead of commits, which is what a dry run
+        // leaves out
+        dry_run: config.dry_run || config.format_patch.is_some(),
+        max_stack: config.max_stack.or_else(|| Some(max_stack(repo))),
+        exit_code: config.exit_code || exit_code(repo),
+        cross_merges: config.cross_merges || cross_merges(repo),
+        update_refs: config.update_refs || update_refs(repo),
+        strategy: config.strategy.or_else(|| Some(strategy(repo))),
+        interleave_hunks: config.interleave_hunks || interleave_hunks(repo),
+        fuzz: config.fuzz.or_else(|| Some(fuzz(repo))),
+        fixup_author_is_target_author: config
+            .fixup_author_is_target_author
+            .or_else(|| Some(fixup_author_is_target_author(repo))),
+        run_hooks: config.run_hooks.or_else(|| Some(run_hooks(repo))),
+        rebase_non_interactive: config.rebase_non_interactive || rebase_non_interactive(repo),
+        include_untracked: config.include_untracked || auto_stage_untracked(repo),
+        autosquash: config.autosquash.or_else(|| Some(autosquash(repo))),
+        autostash: config.autostash.or_else(|| Some(autostash(repo))),
+        // the config only asks when there is someone to answer, so that
+        // it doesn't make scripts hang
+        confirm: config.confirm || (confirm(repo) && config.ask.is_some()),
+        ..*config
+    })
+}
+
 pub fn max_stack(repo: &git2::Repository) -> usize {
     match repo
         .config()
@@ -40,6 +205,23 @@ pub fn auto_stage_if_nothing_staged(repo: &git2::Repository) -> bool {
     }
 }
 
+/// Whether absorb.autoStageIfNothingStaged is set at all, either way.
+pub fn auto_stage_if_nothing_staged_is_set(repo: &git2::Repository) -> bool {
+    repo.config()
+        .and_then(|config| config.get_bool(AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME))
+        .is_ok()
+}
+
+pub fn conventional_commits(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(CONVENTIONAL_COMMITS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => CONVENTIONAL_COMMITS_DEFAULT,
+    }
+}
+
 pub fn fixup_target_always_sha(repo: &git2::Repository) -> bool {
     match repo
         .config()
@@ -49,3 +231,416 @@ pub fn fixup_target_always_sha(repo: &git2::Repository) -> bool {
         _ => FIXUP_TARGET_ALWAYS_SHA_DEFAULT,
     }
 }
+
+/// The base to use when --base isn't given, eg `origin/main`.
+pub fn default_base(repo: &git2::Repository) -> Option<String> {
+    repo.config()
+        .and_then(|config| config.get_string(DEFAULT_BASE_CONFIG_NAME))
+        .ok()
+        .filter(|base| !base.is_empty())
+}
+
+/// The size in bytes above which files are left out of the plan, so
+/// that they aren't loaded into memory. Like other git sizes, it can
+/// end in k, m or g.
+pub fn max_file_size(repo: &git2::Repository) -> Option<u64> {
+    match repo
+        .config()
+        .and_then(|config| config.get_i64(MAX_FILE_SIZE_CONFIG_NAME))
+    {
+        Ok(size) if size > 0 => Some(size as u64),
+        _ => None,
+    }
+}
+
+/// Whether a changed binary file is absorbed whole into the commit that
+/// added it, if no later commit changed it.
+pub fn replace_binary_files(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(REPLACE_BINARY_FILES_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => REPLACE_BINARY_FILES_DEFAULT,
+    }
+}
+
+/// Whether the stack's diffs detect files that a commit copied from
+/// another file.
+pub fn find_copies(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(FIND_COPIES_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => FIND_COPIES_DEFAULT,
+    }
+}
+
+/// How many lines around each hunk must be the same in the commit it is
+/// absorbed into as in HEAD.
+pub fn context_lines(repo: &git2::Repository) -> usize {
+    match repo
+        .config()
+        .and_then(|config| config.get_i64(CONTEXT_LINES_CONFIG_NAME))
+    {
+        Ok(lines) if lines >= 0 => lines as usize,
+        _ => CONTEXT_LINES_DEFAULT,
+    }
+}
+
+/// Whether a hunk that only partly conflicts with a commit is split, so
+/// that the rest of it can be absorbed into older commits.
+pub fn split_hunks(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(SPLIT_HUNKS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => SPLIT_HUNKS_DEFAULT,
+    }
+}
+
+/// Whether the stack is widened when no change finds a commit within
+/// absorb.maxStack.
+pub fn auto_extend_stack(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(AUTO_EXTEND_STACK_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => AUTO_EXTEND_STACK_DEFAULT,
+    }
+}
+
+pub fn auto_detect_base(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(AUTO_DETECT_BASE_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => AUTO_DETECT_BASE_DEFAULT,
+    }
+}
+
+pub fn exit_code(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(EXIT_CODE_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => EXIT_CODE_DEFAULT,
+    }
+}
+
+pub fn strategy(repo: &git2::Repository) -> Strategy {
+    match repo
+        .config()
+        .and_then(|config| config.get_string(STRATEGY_CONFIG_NAME))
+    {
+        Ok(name) => Strategy::from_name(&name).unwrap_or(STRATEGY_DEFAULT),
+        _ => STRATEGY_DEFAULT,
+    }
+}
+
+pub fn interleave_hunks(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(INTERLEAVE_HUNKS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => INTERLEAVE_HUNKS_DEFAULT,
+    }
+}
+
+pub fn fuzz(repo: &git2::Repository) -> usize {
+    match repo
+        .config()
+        .and_then(|config| config.get_i64(FUZZ_CONFIG_NAME))
+    {
+        Ok(fuzz) if fuzz > 0 => fuzz as usize,
+        _ => FUZZ_DEFAULT,
+    }
+}
+
+/// The diff algorithm to use for both the index and the stack, from
+/// absorb.diffAlgorithm, or else diff.algorithm.
+pub fn diff_algorithm(repo: &git2::Repository) -> Option<String> {
+    let config = repo.config().ok()?;
+    config
+        .get_string(DIFF_ALGORITHM_CONFIG_NAME)
+        .or_else(|_| config.get_string(GIT_DIFF_ALGORITHM_CONFIG_NAME))
+        .ok()
+}
+
+pub fn fixup_author_is_target_author(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(FIXUP_AUTHOR_IS_TARGET_AUTHOR_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => FIXUP_AUTHOR_IS_TARGET_AUTHOR_DEFAULT,
+    }
+}
+
+/// The pathspecs of files that should be matched as if --whole-file
+/// was given. The config can be set more than once.
+pub fn whole_file_paths(repo: &git2::Repository) -> Vec<String> {
+    multivar(repo, WHOLE_FILE_PATHS_CONFIG_NAME)
+}
+
+/// The pathspecs that auto-staging is limited to, relative to the root
+/// of the repository. The config can be set more than once.
+pub fn auto_stage_pathspec(repo: &git2::Repository) -> Vec<String> {
+    multivar(repo, AUTO_STAGE_PATHSPEC_CONFIG_NAME)
+}
+
+pub fn auto_stage_untracked(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(AUTO_STAGE_UNTRACKED_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => AUTO_STAGE_UNTRACKED_DEFAULT,
+    }
+}
+
+pub fn keep_auto_staged_leftovers(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(KEEP_AUTO_STAGED_LEFTOVERS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => KEEP_AUTO_STAGED_LEFTOVERS_DEFAULT,
+    }
+}
+
+/// The rules that send the hunks of some paths to a given commit, as
+/// (pathspec, commit-ish or summary regex). Each value of the config is
+/// a pathspec and a target separated by whitespace, and it can be set
+/// more than once.
+pub fn path_targets(repo: &git2::Repository) -> Vec<(String, String)> {
+    multivar(repo, PATH_TARGET_CONFIG_NAME)
+        .into_iter()
+        .filter_map(|rule| {
+            let (pathspec, target) = rule.trim().split_once(char::is_whitespace)?;
+            Some((pathspec.to_owned(), target.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// The command that may reassign the planned hunks, if any.
+pub fn matcher_command(repo: &git2::Repository) -> Option<String> {
+    repo.config()
+        .and_then(|config| config.get_string(MATCHER_COMMAND_CONFIG_NAME))
+        .ok()
+        .filter(|command| !command.is_empty())
+}
+
+/// The `Key: Value` trailers to add to every fixup. The config can be
+/// set more than once.
+pub fn trailers(repo: &git2::Repository) -> Vec<String> {
+    multivar(repo, TRAILERS_CONFIG_NAME)
+        .into_iter()
+        .filter_map(|trailer| {
+            let (key, value) = trailer.split_once(':')?;
+            Some(format!("{}: {}", key.trim(), value.trim()))
+        })
+        .collect()
+}
+
+/// The regexes of commit summaries that should never be fixup targets.
+/// The config can be set more than once.
+pub fn exclude_commits(repo: &git2::Repository) -> Vec<String> {
+    multivar(repo, EXCLUDE_COMMITS_CONFIG_NAME)
+}
+
+pub fn cross_merges(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(CROSS_MERGES_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => CROSS_MERGES_DEFAULT,
+    }
+}
+
+pub fn stop_at_signed_commits(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(STOP_AT_SIGNED_COMMITS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => STOP_AT_SIGNED_COMMITS_DEFAULT,
+    }
+}
+
+pub fn confirm(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(CONFIRM_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => CONFIRM_DEFAULT,
+    }
+}
+
+pub fn rebase_non_interactive(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(REBASE_NON_INTERACTIVE_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => REBASE_NON_INTERACTIVE_DEFAULT,
+    }
+}
+
+/// Whether --and-rebase squashes the fixups, per git's own
+/// rebase.autoSquash if it is set.
+pub fn autosquash(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(GIT_REBASE_AUTOSQUASH_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => GIT_REBASE_AUTOSQUASH_DEFAULT,
+    }
+}
+
+/// Whether --and-rebase stashes unstaged changes, per git's own
+/// rebase.autoStash if it is set.
+pub fn autostash(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(GIT_REBASE_AUTOSTASH_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => GIT_REBASE_AUTOSTASH_DEFAULT,
+    }
+}
+
+/// Whether rebases update stacked branches, like git's own
+/// rebase.updateRefs.
+pub fn update_refs(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(GIT_REBASE_UPDATE_REFS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => GIT_REBASE_UPDATE_REFS_DEFAULT,
+    }
+}
+
+pub fn forbid_pushed_targets(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(FORBID_PUSHED_TARGETS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => FORBID_PUSHED_TARGETS_DEFAULT,
+    }
+}
+
+/// Whether the pre-commit and commit-msg hooks run for fixup commits.
+pub fn run_hooks(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(RUN_HOOKS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => RUN_HOOKS_DEFAULT,
+    }
+}
+
+/// Whether fixup messages get `commit.template` and go through the
+/// prepare-commit-msg hook.
+pub fn prepare_message(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(PREPARE_MESSAGE_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => PREPARE_MESSAGE_DEFAULT,
+    }
+}
+
+/// The keys of trailers that identify a commit, like Gerrit's
+/// Change-Id. The config can be set more than once.
+pub fn identity_trailers(repo: &git2::Repository) -> Vec<String> {
+    let keys = multivar(repo, IDENTITY_TRAILERS_CONFIG_NAME);
+    if keys.is_empty() {
+        IDENTITY_TRAILERS_DEFAULT
+            .iter()
+            .map(|&key| key.to_owned())
+            .collect()
+    } else {
+        keys
+    }
+}
+
+/// Whether fixup commits get the identity trailers of their target.
+pub fn copy_identity_trailers(repo: &git2::Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool(COPY_IDENTITY_TRAILERS_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => COPY_IDENTITY_TRAILERS_DEFAULT,
+    }
+}
+
+/// The reflog message for an absorb run, where `{count}` stands for the
+/// number of fixups and `{range}` for the commits in the stack.
+pub fn reflog_message(repo: &git2::Repository) -> String {
+    match repo
+        .config()
+        .and_then(|config| config.get_string(REFLOG_MESSAGE_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => REFLOG_MESSAGE_DEFAULT.to_owned(),
+    }
+}
+
+/// The reference that points at HEAD from before the last absorb run.
+pub fn pre_absorb_ref(repo: &git2::Repository) -> String {
+    match repo
+        .config()
+        .and_then(|config| config.get_string(PRE_ABSORB_REF_CONFIG_NAME))
+    {
+        Ok(val) => val,
+        _ => PRE_ABSORB_REF_DEFAULT.to_owned(),
+    }
+}
+
+pub fn author_match(repo: &git2::Repository) -> AuthorMatch {
+    match repo
+        .config()
+        .and_then(|config| config.get_string(AUTHOR_MATCH_CONFIG_NAME))
+        .as_deref()
+    {
+        Ok("email") => AuthorMatch::Email,
+        Ok("name") => AuthorMatch::Name,
+        Ok("both") => AuthorMatch::Both,
+        _ => AUTHOR_MATCH_DEFAULT,
+    }
+}
+
+/// The authors, besides the user, whose commits can be fixed up without
+/// --force. The config can be set more than once.
+pub fn allowed_authors(repo: &git2::Repository) -> Vec<String> {
+    multivar(repo, ALLOWED_AUTHORS_CONFIG_NAME)
+}
+
+fn multivar(repo: &git2::Repository, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    if let Ok(config) = repo.config() {
+        if let Ok(entries) = config.multivar(name, None) {
+            let _ = entries.for_each(|entry| {
+                if let Some(value) = entry.value() {
+                    values.push(value.to_owned());
+                }
+            });
+        }
+    }
+    values
+}
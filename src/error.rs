@@ -0,0 +1,74 @@
+use std::process::ExitStatus;
+
+use crate::config;
+
+/// Why absorbing failed.
+///
+/// Running out of commits or having nothing staged are not errors,
+/// unless there were changes or commits asked for explicitly (eg with
+/// --patch-from, --from or --target); see `Outcome::absorbed` for those.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("HEAD is not a branch, use --force to override")]
+    DetachedHead,
+    #[error("--target {0} is not in the absorb stack, use --base to include it")]
+    TargetNotInStack(String),
+    #[error("--target {0} is a merge commit")]
+    TargetIsMergeCommit(String),
+    #[error("there are no changes to absorb")]
+    NothingStaged,
+    #[error("no commits available to fix up, use --base to specify a base commit")]
+    StackEmpty,
+    #[error("{0} is a merge commit, which can't be fixed up or split")]
+    MergeCommitBoundary(git2::Oid),
+    #[error(
+        "commit {0} has already been pushed, and fixing it up would \
+         require a force-push (see {config})",
+        config = config::FORBID_PUSHED_TARGETS_CONFIG_NAME
+    )]
+    PushedTarget(git2::Oid),
+    #[error("{hook} hook exited with {status}")]
+    HookFailed { hook: String, status: ExitStatus },
+    #[error("aborting due to empty commit message")]
+    EmptyMessage,
+    #[error("aborting, the fixups were not confirmed")]
+    NotConfirmed,
+    #[error("--confirm needs someone to answer, but there is no way to ask")]
+    NobodyToConfirm,
+    #[error(
+        "{} exists, so another git-absorb is running in this repository; \
+         if not, remove it",
+        .0.display()
+    )]
+    Locked(std::path::PathBuf),
+    #[error(
+        "a {0} is in progress, finish or abort it first, or use \
+         --force-in-progress to override"
+    )]
+    OperationInProgress(&'static str),
+    #[error("the plan could not be verified, {0}; nothing was committed")]
+    PlanNotVerified(String),
+    #[error("interrupted")]
+    Interrupted,
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    /// Recovers the typed error, if there is one, from errors that were
+    /// passed up through `anyhow`.
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast::<git2::Error>() {
+                Ok(err) => Error::Git(err),
+                Err(err) => Error::Other(err),
+            },
+        }
+    }
+}
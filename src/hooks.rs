@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::Error;
+
+/// The file that `commit-msg` hooks are given, like `git commit` does.
+const COMMIT_MESSAGE_FILE: &str = "COMMIT_EDITMSG";
+
+/// The index that `pre-commit` hooks see, holding the fixup's tree.
+const HOOK_INDEX_FILE: &str = "ABSORB_HOOK_INDEX";
+
+/// Finds the directory that hooks live in, respecting `core.hooksPath`
+/// the same way git does.
+fn hooks_dir(repo: &git2::Repository) -> PathBuf {
+    let configured = repo
+        .config()
+        .and_then(|config| config.get_path("core.hooksPath"));
+    match configured {
+        Ok(path) if path.is_absolute() => path,
+        // relative paths are relative to where hooks run from
+        Ok(path) => repo.workdir().unwrap_or(repo.path()).join(path),
+        Err(_) => repo.commondir().join("hooks"),
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// Runs the hook called `name`, if it exists, from the top of the work
+/// tree. Fails if the hook exits unsuccessfully.
+pub fn run<I, S>(
+    repo: &git2::Repository,
+    name: &str,
+    args: I,
+    envs: &[(&str, &OsStr)],
+    stdin: Option<&[u8]>,
+) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let hook = hooks_dir(repo).join(name);
+    if !is_executable(&hook) {
+        return Ok(());
+    }
+
+    let mut command = Command::new(&hook);
+    command.args(args);
+    command.current_dir(repo.workdir().unwrap_or(repo.path()));
+    command.envs(envs.iter().copied());
+    command.stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("could not run {} hook: {}", name, e))?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        use std::io::Write;
+        // the hook doesn't have to read its input
+        let _ = pipe.write_all(input);
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::HookFailed {
+            hook: name.to_owned(),
+            status,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Runs the `pre-commit` hook for a commit of `tree`.
+pub fn pre_commit(repo: &git2::Repository, tree: &git2::Tree) -> Result<()> {
+    // the hook looks at the index, so give it one that contains exactly
+    // what is about to be committed
+    let index_path = repo.path().join(HOOK_INDEX_FILE);
+    let mut index = git2::Index::open(&index_path)?;
+    index.read_tree(tree)?;
+    index.write()?;
+
+    let result = run(
+        repo,
+        "pre-commit",
+        None::<&str>,
+        &[("GIT_INDEX_FILE", index_path.as_os_str())],
+        None,
+    );
+    let _ = std::fs::remove_file(&index_path);
+    result
+}
+
+/// Runs the `prepare-commit-msg` hook on `message`, telling it where
+/// the message came from, and returns the message as the hook left it.
+pub fn prepare_commit_msg(repo: &git2::Repository, message: &str, source: &str) -> Result<String> {
+    let message_path = repo.path().join(COMMIT_MESSAGE_FILE);
+    std::fs::write(&message_path, message)?;
+    run(
+        repo,
+        "prepare-commit-msg",
+        [message_path.as_os_str(), OsStr::new(source)],
+        &[],
+        None,
+    )?;
+    Ok(std::fs::read_to_string(&message_path)?)
+}
+
+/// Runs the `commit-msg` hook on `message`, and returns the message as
+/// the hook left it.
+pub fn commit_msg(repo: &git2::Repository, message: &str) -> Result<String> {
+    let message_path = repo.path().join(COMMIT_MESSAGE_FILE);
+    std::fs::write(&message_path, message)?;
+    run(repo, "commit-msg", [&message_path], &[], None)?;
+    Ok(std::fs::read_to_string(&message_path)?)
+}
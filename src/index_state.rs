@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::repo_path;
+
+/// Per-entry index state that is lost when the index is rewritten
+/// with `add_all` or `read_tree`.
+///
+/// - assume-unchanged (`git update-index --assume-unchanged`)
+/// - skip-worktree (`git update-index --skip-worktree`, sparse checkouts)
+/// - intent-to-add (`git add -N`)
+#[derive(Default)]
+pub struct Snapshot {
+    entries: HashMap<Vec<u8>, git2::IndexEntry>,
+}
+
+const PRESERVED_FLAGS: u16 = git2::IndexEntryFlag::VALID.bits();
+const PRESERVED_EXTENDED_FLAGS: u16 = git2::IndexEntryExtendedFlag::INTENT_TO_ADD.bits()
+    | git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+
+impl Snapshot {
+    /// Records every entry in the index that carries preserved state.
+    pub fn new(index: &git2::Index) -> Self {
+        let entries = index
+            .iter()
+            .filter(|entry| {
+                entry.flags & PRESERVED_FLAGS != 0
+                    || entry.flags_extended & PRESERVED_EXTENDED_FLAGS != 0
+            })
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        Snapshot { entries }
+    }
+
+    /// Puts the recorded state back onto the entries of `index`.
+    ///
+    /// Entries that are still present get their flags restored.
+    /// Intent-to-add entries that were dropped (eg because the index
+    /// was reset to a tree that does not have them) are re-added.
+    /// The index is not written to disk.
+    pub fn restore(&self, index: &mut git2::Index) -> Result<()> {
+        let mut missing: HashMap<&[u8], &git2::IndexEntry> = self
+            .entries
+            .iter()
+            .map(|(path, entry)| (path.as_slice(), entry))
+            .collect();
+
+        let mut updated = Vec::new();
+        for mut entry in index.iter() {
+            let saved = match missing.remove(entry.path.as_slice()) {
+                Some(saved) => saved,
+                None => continue,
+            };
+            let flags = (entry.flags & !PRESERVED_FLAGS) | (saved.flags & PRESERVED_FLAGS);
+            let flags_extended = (entry.flags_extended & !PRESERVED_EXTENDED_FLAGS)
+                | (saved.flags_extended & PRESERVED_EXTENDED_FLAGS);
+            if flags != entry.flags || flags_extended != entry.flags_extended {
+                entry.flags = flags;
+                entry.flags_extended = flags_extended;
+                updated.push(entry);
+            }
+        }
+        for entry in updated {
+            index.add(&entry)?;
+        }
+
+        for saved in missing.into_values() {
+            let intent_to_add =
+                git2::IndexEntryExtendedFlag::from_bits_truncate(saved.flags_extended)
+                    .contains(git2::IndexEntryExtendedFlag::INTENT_TO_ADD);
+            if intent_to_add {
+                index.add(saved)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds an intent-to-add entry for the file at `path`, like
+/// `git add -N`. The index is not written to disk.
+pub fn intent_to_add(repo: &git2::Repository, index: &mut git2::Index, path: &Path) -> Result<()> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("repository has no working tree"))?;
+    let metadata = std::fs::symlink_metadata(workdir.join(path))?;
+    let mode = if metadata.is_symlink() {
+        0o120000
+    } else if is_executable(&metadata) {
+        0o100755
+    } else {
+        0o100644
+    };
+    index.add(&git2::IndexEntry {
+        ctime: git2::IndexTime::new(0, 0),
+        mtime: git2::IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: repo.blob(b"")?,
+        flags: 0,
+        flags_extended: git2::IndexEntryExtendedFlag::INTENT_TO_ADD.bits(),
+        path: repo_path::from_path(path),
+    })?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Updates the entries of the index that matched `old_tree` to match
+/// `new_tree` instead, so that nothing seems to be staged for them after
+/// HEAD moved from one to the other. Entries with staged changes are left
+/// alone.
+pub fn follow_head(
+    repo: &git2::Repository,
+    old_tree: &git2::Tree,
+    new_tree: &git2::Tree,
+) -> Result<()> {
+    let diff = repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), None)?;
+    let mut index = repo.index()?;
+    let mut updated = false;
+    for delta in diff.deltas() {
+        let (old, new) = (delta.old_file(), delta.new_file());
+        let path = match new.path_bytes().and_then(crate::repo_path::to_path) {
+            Some(path) if !new.id().is_zero() => path,
+            _ => continue,
+        };
+        let mut entry = match index.get_path(path, 0) {
+            Some(entry) if entry.id == old.id() => entry,
+            _ => continue,
+        };
+        entry.id = new.id();
+        entry.mode = new.mode().into();
+        // the stat data was for the old content, so git has to look at
+        // the file again
+        entry.mtime = git2::IndexTime::new(0, 0);
+        entry.ctime = git2::IndexTime::new(0, 0);
+        entry.file_size = 0;
+        index.add(&entry)?;
+        updated = true;
+    }
+    if updated {
+        index.write()?;
+    }
+    Ok(())
+}
+
+/// Everything that was staged because nothing else was.
+///
+/// If this is dropped before `unstage` is called, eg because the run
+/// failed, it is unstaged anyway, so the index is left as it was found.
+pub struct Autostaged<'r> {
+    repo: &'r git2::Repository,
+    snapshot: Snapshot,
+    unstaged: bool,
+}
+
+impl<'r> Autostaged<'r> {
+    /// `snapshot` is the state of the index before everything was staged.
+    pub fn new(repo: &'r git2::Repository, snapshot: Snapshot) -> Self {
+        Autostaged {
+            repo,
+            snapshot,
+            unstaged: false,
+        }
+    }
+
+    /// Unstages whatever HEAD doesn't contain, ie whatever was not
+    /// absorbed.
+    pub fn unstage(mut self) -> Result<()> {
+        self.unstaged = true;
+        self.reset()
+    }
+
+    /// Leaves whatever was not absorbed staged.
+    pub fn keep(mut self) {
+        self.unstaged = true;
+    }
+
+    fn reset(&self) -> Result<()> {
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        let mut index = self.repo.index()?;
+        index.read_tree(&head_tree)?;
+        self.snapshot.restore(&mut index)?;
+        index.write()?;
+        Ok(())
+    }
+}
+
+impl Drop for Autostaged<'_> {
+    fn drop(&mut self) {
+        if self.unstaged {
+            return;
+        }
+        if let Err(e) = self.reset() {
+            warn!(err = %e, "could not unstage the auto-staged changes");
+        }
+    }
+}
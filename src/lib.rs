@@ -1,135 +1,952 @@
 #[macro_use]
-extern crate slog;
-use anyhow::{anyhow, Result};
+extern crate tracing;
+use anyhow::{anyhow, Context, Result};
 
+mod autosquash;
+mod blame;
 mod commute;
 mod config;
+mod error;
+mod hooks;
+mod index_state;
+mod lock;
+mod matcher;
+mod mbox;
+mod message;
+mod objects;
 mod owned;
+mod plan_edit;
+mod porcelain;
+mod preview;
+mod promisor;
+mod remote;
+mod repo_path;
+mod report;
+mod select;
+mod sign;
+mod squash;
 mod stack;
+mod summary;
 
-use std::io::Write;
+pub use error::Error;
+pub use report::Reporter;
+pub use select::{Ask, Prompt, Proposal, Selection, Selector};
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone, Copy)]
 pub struct Config<'a> {
     pub dry_run: bool,
     pub force: bool,
+    pub force_in_progress: bool,
     pub base: Option<&'a str>,
+    /// Use the commit that HEAD forked from the base branch (or from
+    /// the upstream) as the base, per the branch's reflog.
+    pub fork_point: bool,
+    /// Hide these commit-ishes or globs of refs, and everything they
+    /// contain, from the stack.
+    pub not: &'a [String],
     pub and_rebase: bool,
     pub whole_file: bool,
     pub one_fixup_per_commit: bool,
-    pub logger: &'a slog::Logger,
+    pub no_gpg_sign: bool,
+    pub max_stack: Option<usize>,
+    pub target: Option<&'a str>,
+    pub squash: bool,
+    pub amend: bool,
+    pub edit: bool,
+    pub explain: bool,
+    pub porcelain: bool,
+    pub exit_code: bool,
+    /// With dry_run, print the patch of each fixup on stdout. It would
+    /// be mixed into the porcelain output, so don't set both.
+    pub show_diff: bool,
+    /// Write each fixup to a patch file in this directory instead of
+    /// committing it.
+    pub format_patch: Option<&'a Path>,
+    /// Absorb this unified diff, which must apply to HEAD, instead of
+    /// the index.
+    pub patch: Option<&'a [u8]>,
+    /// Absorb the changes of this commit, which must be HEAD, instead of
+    /// the index, keeping what is left of it on top of the fixups.
+    pub from: Option<&'a str>,
+    /// Absorb the changes in the working tree instead of the index, for
+    /// the files that have nothing staged.
+    pub unstaged: bool,
+    /// Auto-stage untracked files as intent-to-add, instead of leaving
+    /// them out.
+    pub include_untracked: bool,
+    pub strategy: Option<Strategy>,
+    pub commit_grep: Option<&'a str>,
+    pub interleave_hunks: bool,
+    pub fuzz: Option<usize>,
+    pub ignore_space_change: bool,
+    pub ignore_all_space: bool,
+    pub skip_whitespace_only: bool,
+    pub fixup_author_is_target_author: Option<bool>,
+    pub committer_date_is_author_date: bool,
+    pub reset_author_date: bool,
+    pub cross_merges: bool,
+    pub update_refs: bool,
+    pub quiet: bool,
+    pub run_hooks: Option<bool>,
+    pub rebase_non_interactive: bool,
+    pub and_push: bool,
+    pub autosquash: Option<bool>,
+    pub autostash: Option<bool>,
+    pub one_reflog_entry: bool,
+    /// Check that squashing the fixups gives the staged changes before
+    /// committing them.
+    pub verify_plan: bool,
+    /// Print the plan and ask for confirmation before committing.
+    pub confirm: bool,
+    /// Add a Signed-off-by trailer for the committer to every fixup.
+    pub signoff: bool,
+    /// Open the plan in an editor to change or drop its assignments
+    /// before committing.
+    pub edit_plan: bool,
+    /// Decides which hunks are absorbed, for --patch. Every hunk is
+    /// absorbed if unset.
+    pub selector: Option<&'a dyn Selector>,
+    /// Answers --confirm and the offer to stage everything when nothing
+    /// is staged. Nothing is asked if unset, and --confirm is then an
+    /// error.
+    pub ask: Option<&'a dyn Ask>,
+    /// Receives progress events and warnings, if set.
+    pub reporter: Option<&'a dyn Reporter>,
+    /// Stops the run as soon as it is safe to, once set (eg by a
+    /// Ctrl-C handler).
+    pub interrupt: Option<&'a AtomicBool>,
+}
+
+/// How destination commits are found for staged hunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Absorb each hunk into the newest commit it does not commute
+    /// with.
+    Commute,
+    /// Absorb each hunk into the newest commit that last touched the
+    /// lines it changes, according to blame.
+    Blame,
+    /// Commute, and fall back to blame for hunks that commute with the
+    /// whole stack.
+    CommuteThenBlame,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "commute" => Some(Strategy::Commute),
+            "blame" => Some(Strategy::Blame),
+            "commute-then-blame" => Some(Strategy::CommuteThenBlame),
+            _ => None,
+        }
+    }
+}
+
+/// How much of the index a run absorbed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Absorbed {
+    /// Every staged change was absorbed.
+    All,
+    /// Some staged changes were absorbed, and the rest were left in
+    /// the index.
+    Some,
+    /// Changes were staged, but none of them could be absorbed.
+    None,
+    /// Nothing was staged.
+    NothingStaged,
+}
+
+/// The result of a successful run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub absorbed: Absorbed,
+    summary: Option<String>,
+    exit_code: bool,
+}
+
+impl Outcome {
+    /// What was absorbed where, and what is left to do, for the user to
+    /// read. None if there is nothing to say, or if `quiet` was set.
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// The code the process should exit with. This is always 0, unless
+    /// distinct exit codes were requested with --exit-code or
+    /// absorb.exitCode.
+    pub fn exit_code(&self) -> i32 {
+        if !self.exit_code {
+            return 0;
+        }
+        match self.absorbed {
+            Absorbed::All => 0,
+            // 1 is reserved for errors
+            Absorbed::NothingStaged => 2,
+            Absorbed::None => 3,
+            Absorbed::Some => 4,
+        }
+    }
 }
 
-pub fn run(config: &mut Config) -> Result<()> {
+pub fn run(config: &Config) -> Result<Outcome, Error> {
     let repo = git2::Repository::open_from_env()?;
-    debug!(config.logger, "repository found"; "path" => repo.path().to_str());
-
-    // here, we default to the git config value,
-    // if the flag was not provided in the CLI.
-    //
-    // in the future, we'd likely want to differentiate between
-    // a "non-provided" option, vs an explicit --no-<option>
-    // that disables a behavior, much like git does.
-    // e.g. user may want to overwrite a config value with
-    // --no-one-fixup-per-commit -- then, defaulting to the config value
-    // like we do here is no longer sufficient. but until then, this is fine.
-    //
-    config.one_fixup_per_commit |= config::one_fixup_per_commit(&repo);
+    debug!(path = ?repo.path(), "repository found");
 
     run_with_repo(config, &repo)
 }
 
-fn run_with_repo(config: &Config, repo: &git2::Repository) -> Result<()> {
-    let stack = stack::working_stack(repo, config.base, config.force, config.logger)?;
+fn run_with_repo(config: &Config, repo: &git2::Repository) -> Result<Outcome, Error> {
+    plan(repo, config)?.apply()
+}
+
+/// Works out which commit each staged change belongs in, without
+/// creating any commits. If nothing is staged and
+/// absorb.autoStageIfNothingStaged is set, this stages everything, and
+/// applying the plan unstages what was not absorbed.
+pub fn plan<'a>(repo: &'a git2::Repository, config: &Config<'a>) -> Result<Plan<'a>, Error> {
+    let mut plan = plan_stack(repo, config)?;
+    let mut extended = false;
+    // with absorb.autoExtendStack, a stack that stopped at its size
+    // limit is widened until something finds a commit
+    while plan.can_extend
+        && plan.hunks.is_empty()
+        && !plan.unabsorbed.is_empty()
+        && plan
+            .unabsorbed
+            .iter()
+            .all(|unabsorbed| matches!(unabsorbed.reason, Reason::StackExhausted))
+    {
+        let max_stack = (plan.stack.len() * 2).min(config::AUTO_EXTEND_STACK_LIMIT);
+        debug!(
+            max_stack,
+            "nothing found a commit to fix up, extending the stack"
+        );
+        let config = Config {
+            max_stack: Some(max_stack),
+            ..plan.config
+        };
+        // the next plan takes over what this one staged
+        let autostaged = plan.autostaged.take();
+        let we_added_everything_to_index = plan.we_added_everything_to_index;
+        let index_was_empty = plan.index_was_empty;
+        drop(plan);
+        plan = plan_stack(repo, &config)?;
+        if autostaged.is_some() {
+            plan.autostaged = autostaged;
+            plan.we_added_everything_to_index = we_added_everything_to_index;
+            plan.index_was_empty = index_was_empty;
+        }
+        extended = true;
+    }
+    if extended && !plan.hunks.is_empty() {
+        info!(
+            commits = plan.stack.len(),
+            "Extended the stack to find commits to fix up"
+        );
+    }
+    Ok(plan)
+}
+
+/// Plans the absorb into the stack that `config` describes.
+fn plan_stack<'a>(repo: &'a git2::Repository, config: &Config<'a>) -> Result<Plan<'a>, Error> {
+    let config = config::unify(config, repo)?;
+    let reporter = config.reporter.unwrap_or(&report::NoReporter);
+    let lock = lock::Lock::acquire(repo)?;
+    check_repo_state(repo, config.force || config.force_in_progress, reporter)?;
+    let split = match config.from {
+        Some(from) => Some(split_commit(repo, from)?),
+        None => None,
+    };
+    // the fixups go on top of this commit
+    let head_commit = match &split {
+        Some(split) => split.parent(0)?,
+        None => repo.head()?.peel_to_commit()?,
+    };
+    // a stale absorb.defaultBase shouldn't stop every run, so it falls
+    // back to finding the base without one
+    let default_base = match config.base {
+        Some(_) => None,
+        None => config::default_base(repo).filter(|base| {
+            let resolved = stack::resolve_base(repo, base).is_ok();
+            if !resolved {
+                warn!(base, "Could not resolve absorb.defaultBase, ignoring it");
+                reporter.on_warning(&format!("Could not resolve absorb.defaultBase {}", base));
+            }
+            resolved
+        }),
+    };
+    let base = config.base.or(default_base.as_deref());
+    let fork_point = match config.fork_point {
+        true => Some(stack::fork_point(repo, base)?.to_string()),
+        false => None,
+    };
+    let stack = debug_span!("stack").in_scope(|| {
+        stack::working_stack(
+            repo,
+            &stack::StackOptions {
+                base: fork_point.as_deref().or(base),
+                not: config.not,
+                max_stack: config.max_stack.unwrap_or(config::MAX_STACK),
+                force: config.force,
+                cross_merges: config.cross_merges,
+                update_refs: config.update_refs,
+                skip_head: split.is_some(),
+            },
+            reporter,
+        )
+    })?;
     if stack.is_empty() {
-        crit!(config.logger, "No commits available to fix up, exiting");
-        return Ok(());
+        // the commits or changes that were asked for can't go anywhere
+        if config.target.is_some() || split.is_some() {
+            return Err(Error::StackEmpty);
+        }
+        error!("No commits available to fix up, exiting");
+        let index_was_empty = nothing_to_absorb(repo, &config)?;
+        return Ok(Plan {
+            repo,
+            config,
+            stack: Vec::new(),
+            summary_counts: HashMap::new(),
+            index: owned::Diff::default(),
+            head_tree: head_commit.tree()?,
+            head_commit,
+            hunks: Vec::new(),
+            unabsorbed: Vec::new(),
+            patches_considered: 0,
+            index_was_empty,
+            we_added_everything_to_index: false,
+            autostaged: None,
+            split,
+            stack_was_empty: true,
+            can_extend: false,
+            lock,
+        });
     }
 
-    let autostage_enabled = config::auto_stage_if_nothing_staged(repo);
-    let index_was_empty = nothing_left_in_index(repo)?;
+    let index_was_empty = nothing_to_absorb(repo, &config)?;
+    if index_was_empty && config.patch.is_some() {
+        return Err(Error::NothingStaged);
+    }
+    let can_autostage =
+        index_was_empty && config.patch.is_none() && split.is_none() && !config.unstaged;
+    let autostage_enabled = match config::auto_stage_if_nothing_staged_is_set(repo) {
+        true => config::auto_stage_if_nothing_staged(repo),
+        // without the config, ask whether to, if there is someone to ask
+        false => match config.ask {
+            Some(ask) if can_autostage && !config.dry_run && !config.porcelain => {
+                repo.diff_index_to_workdir(None, None)?.deltas().len() > 0
+                    && ask.ask("Nothing staged, stage all modified files and continue?")?
+            }
+            _ => false,
+        },
+    };
     let mut we_added_everything_to_index = false;
-    if autostage_enabled && index_was_empty {
+    let mut autostaged = None;
+    if autostage_enabled && can_autostage {
         we_added_everything_to_index = true;
 
         // no matter from what subdirectory we're executing,
         // "." will still refer to the root workdir.
-        let pathspec = ["."];
+        let mut pathspec = config::auto_stage_pathspec(repo);
+        if pathspec.is_empty() {
+            pathspec.push(".".to_owned());
+        }
         let mut index = repo.index()?;
-        index.add_all(pathspec.iter(), git2::IndexAddOption::DEFAULT, None)?;
+        let index_snapshot = index_state::Snapshot::new(&index);
+        let mut filtered = Vec::new();
+        let mut untracked = Vec::new();
+        index.add_all(
+            pathspec.iter(),
+            git2::IndexAddOption::DEFAULT,
+            Some(&mut |path: &Path, _: &[u8]| {
+                // new files can't be absorbed, so there's no point in
+                // hashing them
+                let is_new = repo.status_file(path).is_ok_and(|status| {
+                    status.is_wt_new() && !status.is_index_new() && !status.is_index_modified()
+                });
+                if is_new {
+                    untracked.push(path.to_path_buf());
+                    1
+                } else if uses_filter_driver(repo, path) {
+                    let changed = repo
+                        .status_file(path)
+                        .is_ok_and(|status| status.is_wt_modified() || status.is_wt_new());
+                    if changed {
+                        filtered.push(path.to_path_buf());
+                    }
+                    1
+                } else {
+                    0
+                }
+            }),
+        )?;
+        index_snapshot.restore(&mut index)?;
+        if config.include_untracked {
+            // like git add -N, so that they are listed as left over
+            for path in &untracked {
+                index_state::intent_to_add(repo, &mut index, path)?;
+            }
+        }
         index.write()?;
+        // from here on, failing unstages everything again
+        autostaged = Some(index_state::Autostaged::new(repo, index_snapshot));
+        for path in filtered {
+            warn!(
+                path = %path.display(),
+                "Not auto-staging file that uses a filter driver (eg Git LFS), \
+                 stage it with git add to absorb it",
+            );
+            reporter.on_warning(&format!(
+                "Not auto-staging {}, which uses a filter driver",
+                path.display()
+            ));
+        }
     }
 
+    let max_file_size = config::max_file_size(repo);
     let mut diff_options = Some({
         let mut ret = git2::DiffOptions::new();
+        // a file that became a symlink, or the other way around, is one
+        // change rather than a deletion and an addition
+        ret.include_typechange(true);
+        // larger files are treated as binary, so their contents are
+        // never loaded
+        if let Some(size) = max_file_size {
+            ret.max_size(size as i64);
+        }
         ret.context_lines(0)
             .id_abbrev(40)
-            .ignore_filemode(true)
+            .ignore_filemode(false)
             .ignore_submodules(true);
+        match config::diff_algorithm(repo).as_deref() {
+            None | Some("default") | Some("myers") => {}
+            Some("minimal") => {
+                ret.minimal(true);
+            }
+            Some("patience") => {
+                ret.patience(true);
+            }
+            // libgit2 does not implement histogram, which is an
+            // extension of patience
+            Some("histogram") => {
+                debug!("using patience instead of histogram diff");
+                ret.patience(true);
+            }
+            Some(algorithm) => {
+                warn!(%algorithm, "unknown diff algorithm, using the default");
+                reporter.on_warning(&format!(
+                    "Unknown diff algorithm {:?}, using the default",
+                    algorithm
+                ));
+            }
+        }
         ret
     });
 
+    // whitespace changes are only ignored in the index, so that staged
+    // changes that only touch whitespace are left there
+    if let Some(diff_options) = diff_options.as_mut() {
+        diff_options
+            .ignore_whitespace_change(config.ignore_space_change && config.skip_whitespace_only)
+            .ignore_whitespace(config.ignore_all_space && config.skip_whitespace_only);
+    }
+    let head_tree = head_commit.tree()?;
+    let span = debug_span!("parse").entered();
+    let index = match config.patch {
+        _ if split.is_some() => owned::Diff::new(&repo.diff_tree_to_tree(
+            Some(&head_tree),
+            Some(&split.as_ref().expect("checked above").tree()?),
+            diff_options.as_mut(),
+        )?)?,
+        _ if config.unstaged => {
+            owned::Diff::new(&unstaged_diff(repo, &head_tree, diff_options.as_mut())?)?
+        }
+        Some(patch) => {
+            // the patch is diffed again from HEAD, so that it has no
+            // context lines, like the index's diff
+            let patch = git2::Diff::from_buffer(patch)?;
+            let patched = repo
+                .apply_to_tree(&head_tree, &patch, None)
+                .map_err(|e| anyhow!("the patch does not apply to HEAD: {}", e.message()))?;
+            owned::Diff::new(&repo.diff_tree_to_index(
+                Some(&head_tree),
+                Some(&patched),
+                diff_options.as_mut(),
+            )?)?
+        }
+        None => owned::Diff::new(&repo.diff_tree_to_index(
+            Some(&head_tree),
+            None,
+            diff_options.as_mut(),
+        )?)?,
+    };
+    trace!(?index, "parsed index");
+    span.exit();
+
+    // only the paths changed in the index matter, so the stack's diffs
+    // can skip the rest of the tree, which is much faster in large
+    // repositories. the paths on both sides are the same, unless a
+    // commit moved one of them (see below)
+    if let Some(diff_options) = diff_options.as_mut() {
+        // but not in the stack: a hunk that commuted past a whitespace
+        // change would conflict with it when the fixup is squashed
+        diff_options
+            .ignore_whitespace_change(false)
+            .ignore_whitespace(false);
+        // with no pathspec at all, everything would be diffed
+        if index.iter().next().is_some() {
+            diff_options.disable_pathspec_match(true);
+            for patch in index.iter() {
+                diff_options.pathspec(patch.new_path.as_slice());
+            }
+        }
+    }
     let (stack, summary_counts): (Vec<_>, _) = {
-        let mut diffs = Vec::with_capacity(stack.len());
+        let _span = debug_span!("parse").entered();
+        let find_copies = config::find_copies(repo);
+        let mut raw_diffs = Vec::with_capacity(stack.len());
         for commit in &stack {
-            let diff = owned::Diff::new(
-                &repo.diff_tree_to_tree(
-                    if commit.parents().len() == 0 {
-                        None
-                    } else {
-                        Some(commit.parent(0)?.tree()?)
+            let parent_tree = if commit.parents().len() == 0 {
+                None
+            } else {
+                Some(commit.parent(0)?.tree()?)
+            };
+            let tree = commit.tree()?;
+            let mut diff =
+                repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), diff_options.as_mut())?;
+            // a file that the commit added may have been moved there (eg
+            // along with its directory), in which case the commits below
+            // have it at its old path, so those are diffed too, and the
+            // hunks keep commuting under the old path. a copied file is
+            // reported as such, but its hunks stop at the copy
+            if let Some(parent_tree) = &parent_tree {
+                let sources = rename_sources(repo, parent_tree, &tree, &diff, find_copies)?;
+                if !sources.is_empty() {
+                    if let Some(diff_options) = diff_options.as_mut() {
+                        for source in &sources {
+                            debug!(
+                                commit = %commit.id(),
+                                path = %String::from_utf8_lossy(source),
+                                "following rename",
+                            );
+                            diff_options.pathspec(source.as_slice());
+                        }
+                        // copies are only found from files that are in
+                        // the diff, even unmodified
+                        diff_options.include_unmodified(find_copies);
                     }
-                    .as_ref(),
-                    Some(&commit.tree()?),
-                    diff_options.as_mut(),
-                )?,
-            )?;
-            trace!(config.logger, "parsed commit diff";
-                   "commit" => commit.id().to_string(),
-                   "diff" => format!("{:?}", diff),
-            );
+                    diff = repo.diff_tree_to_tree(
+                        Some(parent_tree),
+                        Some(&tree),
+                        diff_options.as_mut(),
+                    )?;
+                    if let Some(diff_options) = diff_options.as_mut() {
+                        diff_options.include_unmodified(false);
+                    }
+                    diff.find_similar(Some(&mut similarity_options(find_copies)))?;
+                }
+            }
+            raw_diffs.push(diff);
+        }
+        // the blobs are only read once the diffs are parsed, so any that
+        // a partial clone left out can all be fetched before that
+        if let Some(remote) = promisor::remote(repo) {
+            let mut missing = BTreeSet::new();
+            for diff in &raw_diffs {
+                promisor::missing_blobs(repo, diff, &mut missing)?;
+            }
+            if !missing.is_empty() {
+                info!(count = missing.len(), %remote, "fetching missing blobs");
+                promisor::fetch(repo, &remote, &missing).with_context(|| {
+                    format!(
+                        "could not fetch the {} blobs this partial clone is missing from {}, \
+                         fetch them or use --base to absorb into fewer commits",
+                        missing.len(),
+                        remote,
+                    )
+                })?;
+            }
+        }
+
+        let mut diffs = Vec::with_capacity(stack.len());
+        for (commit, raw_diff) in stack.iter().zip(&raw_diffs) {
+            let diff = owned::Diff::new(raw_diff)?;
+            trace!(commit = %commit.id(), ?diff, "parsed commit diff");
+            reporter.on_commit_scanned(commit.id());
             diffs.push(diff);
+            check_interrupt(&config)?;
         }
 
         let summary_counts = stack::summary_counts(&stack);
         (stack.into_iter().zip(diffs).collect(), summary_counts)
     };
 
-    let mut head_tree = repo.head()?.peel_to_tree()?;
-    let index = owned::Diff::new(&repo.diff_tree_to_index(
-        Some(&head_tree),
-        None,
-        diff_options.as_mut(),
-    )?)?;
-    trace!(config.logger, "parsed index";
-           "index" => format!("{:?}", index),
-    );
+    let target_commit = match config.target {
+        Some(target) => {
+            let target_id = repo.revparse_single(target)?.peel_to_commit()?.id();
+            let target_commit = stack
+                .iter()
+                .map(|(commit, _)| commit)
+                .find(|commit| commit.id() == target_id)
+                .ok_or_else(|| Error::TargetNotInStack(target.to_owned()))?;
+            if target_commit.parent_count() > 1 {
+                return Err(Error::TargetIsMergeCommit(target.to_owned()));
+            }
+            Some(target_commit)
+        }
+        None => None,
+    };
+
+    let mut path_targets = Vec::new();
+    for (pathspec, target) in config::path_targets(repo) {
+        match path_target(repo, &stack, &target)? {
+            Some(commit) => path_targets.push((git2::Pathspec::new([&pathspec])?, commit)),
+            None => warn!(
+                %pathspec,
+                %target,
+                "No commit in the stack matches {}",
+                config::PATH_TARGET_CONFIG_NAME,
+            ),
+        }
+    }
+
+    let strategy = config.strategy.unwrap_or(Strategy::Commute);
+    let mut blamer = match strategy {
+        Strategy::Commute => None,
+        Strategy::Blame | Strategy::CommuteThenBlame => Some(blame::Blamer::new(repo)?),
+    };
+
+    let replace_binary_files = config::replace_binary_files(repo);
+    let whole_file_paths = config::whole_file_paths(repo);
+    let whole_file_paths = if whole_file_paths.is_empty() {
+        None
+    } else {
+        Some(git2::Pathspec::new(&whole_file_paths)?)
+    };
+
+    let exclude_commits = regex::RegexSet::new(config::exclude_commits(repo))
+        .map_err(|e| anyhow!("invalid {}: {}", config::EXCLUDE_COMMITS_CONFIG_NAME, e))?;
+    let commit_grep = config
+        .commit_grep
+        .map(regex::Regex::new)
+        .transpose()
+        .map_err(|e| anyhow!("invalid --commit-grep: {}", e))?;
+    // explicitly given targets are never rejected
+    let rejection = |commit: &git2::Commit| {
+        let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+        let message = String::from_utf8_lossy(commit.message_bytes());
+        if commit.parent_count() > 1 {
+            Some(Reason::MergeCommit)
+        } else if exclude_commits.is_match(&summary) {
+            Some(Reason::ExcludedCommit)
+        } else if !commit_grep
+            .as_ref()
+            .map_or(true, |re| re.is_match(&message))
+        {
+            Some(Reason::CommitGrep)
+        } else {
+            None
+        }
+    };
+
+    // when the stack stopped at its size limit, absorb.autoExtendStack
+    // can widen it if nothing finds a commit
+    let max_stack = config.max_stack.unwrap_or(config::MAX_STACK);
+    let can_extend = config::auto_extend_stack(repo)
+        && base.is_none()
+        && !config.fork_point
+        && stack.len() == max_stack
+        && max_stack < config::AUTO_EXTEND_STACK_LIMIT;
 
-    let signature = repo
-        .signature()
-        .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))?;
-    let mut head_commit = repo.head()?.peel_to_commit()?;
+    let commute_options = commute::Options {
+        interleave_hunks: config.interleave_hunks,
+    };
+    let fuzz = config.fuzz.unwrap_or(0);
+    let split_hunks = config::split_hunks(repo);
+    let context_lines = config::context_lines(repo);
 
+    let span = debug_span!("plan").entered();
     let mut hunks_with_commit = vec![];
+    let mut unabsorbed = vec![];
 
     let mut patches_considered = 0usize;
+    let mut reported = (0, 0);
+    // every hunk of a file is commuted past the same patches, so their
+    // positions are only worked out once
+    let mut shifts: HashMap<(git2::Oid, &[u8]), commute::Shifts> = HashMap::new();
+    // whether the rest of the hunks were declined with --patch
+    let mut quit_selecting = false;
     'patch: for index_patch in index.iter() {
+        check_interrupt(&config)?;
+        report_planned(reporter, &hunks_with_commit, &unabsorbed, &mut reported);
         let old_path = index_patch.new_path.as_slice();
-        if index_patch.status != git2::Delta::Modified {
-            debug!(config.logger, "skipped non-modified hunk";
-                    "path" => String::from_utf8_lossy(old_path).into_owned(),
-                    "status" => format!("{:?}", index_patch.status),
+        if !matches!(
+            index_patch.status,
+            git2::Delta::Modified | git2::Delta::Typechange
+        ) {
+            debug!(
+                path = %String::from_utf8_lossy(old_path),
+                status = ?index_patch.status,
+                "skipped non-modified hunk",
             );
+            unabsorbed.push(Unabsorbed {
+                path: old_path.to_vec(),
+                change: format!("{:?}", index_patch.status).to_lowercase(),
+                reason: Reason::NotModified(index_patch.status),
+            });
+            continue 'patch;
+        }
+
+        if max_file_size.is_some_and(|max| index_patch.size > max) {
+            if !config.explain {
+                warn!(
+                    path = %String::from_utf8_lossy(old_path),
+                    size = index_patch.size,
+                    "Not absorbing file larger than {}",
+                    config::MAX_FILE_SIZE_CONFIG_NAME,
+                );
+            }
+            unabsorbed.push(Unabsorbed {
+                path: old_path.to_vec(),
+                change: "modified".to_owned(),
+                reason: Reason::TooLarge,
+            });
             continue 'patch;
         }
 
         patches_considered += 1;
 
+        // --target wins over absorb.pathTarget
+        let (target_commit, target_reason) = match target_commit {
+            Some(commit) => (Some(commit), Reason::Target),
+            None => {
+                let path_target = path_targets.iter().find(|(pathspec, _)| {
+                    repo_path::to_path(old_path).is_some_and(|path| {
+                        pathspec.matches_path(path, git2::PathspecFlags::DEFAULT)
+                    })
+                });
+                (path_target.map(|(_, commit)| *commit), Reason::PathTarget)
+            }
+        };
+
+        let whole_file = config.whole_file
+            || whole_file_paths.as_ref().is_some_and(|pathspec| {
+                repo_path::to_path(old_path)
+                    .is_some_and(|path| pathspec.matches_path(path, git2::PathspecFlags::DEFAULT))
+            });
+
+        // a new type replaces the whole file, so it belongs in the
+        // newest commit that touched the file, along with the new
+        // contents
+        if index_patch.status == git2::Delta::Typechange {
+            let newest = stack
+                .iter()
+                .find_map(|(commit, diff)| diff.by_new(old_path).map(|patch| (commit, patch)));
+            let (dest_commit, reason) = match (target_commit, newest) {
+                (Some(commit), _) => (Some(commit), target_reason),
+                (None, Some((commit, patch))) => (
+                    Some(commit),
+                    match patch.status {
+                        git2::Delta::Added => Reason::AddedFile,
+                        git2::Delta::Copied => Reason::CopiedFile,
+                        git2::Delta::Typechange => Reason::SetType,
+                        _ => Reason::Conflict,
+                    },
+                ),
+                (None, None) => (None, Reason::StackExhausted),
+            };
+            let change = Change::Type(index_patch.new_id, index_patch.new_mode);
+            let rejected = dest_commit
+                .filter(|_| target_commit.is_none())
+                .and_then(rejection);
+            match dest_commit {
+                Some(dest_commit) if rejected.is_none() => hunks_with_commit.push(HunkWithCommit {
+                    change,
+                    dest_commit,
+                    index_patch,
+                    reason,
+                }),
+                _ => unabsorbed.push(Unabsorbed {
+                    path: old_path.to_vec(),
+                    change: change.describe(),
+                    reason: rejected.unwrap_or(reason),
+                }),
+            }
+            continue 'patch;
+        }
+
+        // binary files have no hunks, so they can only be absorbed
+        // whole, and only into the commit that added them, if nothing
+        // else changed them since. a mode change is absorbed on its own
+        if index_patch.binary && index_patch.old_id != index_patch.new_id {
+            let newest = stack
+                .iter()
+                .find(|(_, diff)| diff.by_new(old_path).is_some());
+            let (dest_commit, reason) = match newest {
+                Some((commit, diff)) => match diff.by_new(old_path) {
+                    Some(patch) if replace_binary_files && patch.status == git2::Delta::Added => {
+                        (Some(commit), Reason::AddedFile)
+                    }
+                    Some(patch) if replace_binary_files && patch.status == git2::Delta::Copied => {
+                        (Some(commit), Reason::CopiedFile)
+                    }
+                    _ => (None, Reason::Binary),
+                },
+                None => (None, Reason::StackExhausted),
+            };
+            match dest_commit {
+                Some(dest_commit) if rejection(dest_commit).is_none() => {
+                    hunks_with_commit.push(HunkWithCommit {
+                        change: Change::Blob(index_patch.new_id),
+                        dest_commit,
+                        index_patch,
+                        reason,
+                    });
+                }
+                _ => {
+                    let reason = dest_commit.and_then(rejection).unwrap_or(reason);
+                    if !config.explain {
+                        warn!(
+                            path = %String::from_utf8_lossy(old_path),
+                            reason = %reason,
+                            "Not absorbing binary file",
+                        );
+                    }
+                    unabsorbed.push(Unabsorbed {
+                        path: old_path.to_vec(),
+                        change: Change::Blob(index_patch.new_id).describe(),
+                        reason,
+                    });
+                }
+            }
+        }
+
+        // finds the newest commit that a hunk of this file cannot
+        // commute with, and why, along with the path and position of
+        // the hunk in that commit
+        let mut commute_past_stack = |hunk: &owned::Hunk| {
+            let mut dest_commit = None;
+            let mut reason = Reason::StackExhausted;
+            let mut commuted_old_path = old_path;
+            let mut commuted_index_hunk = hunk.clone();
+            let mut dest_path = old_path;
+
+            'commit: for (commit, diff) in stack.iter().filter(|_| strategy != Strategy::Blame) {
+                let _span = debug_span!("commit", commit = %commit.id()).entered();
+                let next_patch = match diff.by_new(commuted_old_path) {
+                    Some(patch) => patch,
+                    // this commit doesn't touch the hunk's file, so
+                    // they trivially commute, and the next commit
+                    // should be considered
+                    None => {
+                        debug!("skipped commit with no path");
+                        continue 'commit;
+                    }
+                };
+                dest_path = next_patch.new_path.as_slice();
+
+                // sometimes we just forget some change (eg: intializing some object) that
+                // happens in a completely unrelated place with the current hunks. In those
+                // cases, might be helpful to just match the first commit touching the same
+                // file as the current hunk. Use this option with care!
+                if whole_file {
+                    debug!("Commit touches the hunk file and match whole file is enabled");
+                    dest_commit = Some(commit);
+                    reason = Reason::WholeFile;
+                    break 'commit;
+                }
+
+                if next_patch.status == git2::Delta::Added {
+                    debug!("found noncommutative commit by add");
+                    dest_commit = Some(commit);
+                    reason = Reason::AddedFile;
+                    break 'commit;
+                }
+                // the copy's hunks are relative to its source, but the
+                // commits below never had the copy
+                if next_patch.status == git2::Delta::Copied {
+                    debug!("found noncommutative commit by copy");
+                    dest_commit = Some(commit);
+                    reason = Reason::CopiedFile;
+                    break 'commit;
+                }
+                if next_patch.status == git2::Delta::Typechange {
+                    debug!("found noncommutative commit by type change");
+                    dest_commit = Some(commit);
+                    reason = Reason::SetType;
+                    break 'commit;
+                }
+                if commuted_old_path != next_patch.old_path.as_slice() {
+                    debug!(
+                        path = %String::from_utf8_lossy(&next_patch.old_path),
+                        "changed commute path",
+                    );
+                    commuted_old_path = next_patch.old_path.as_slice();
+                }
+                let commuted = shifts
+                    .entry((commit.id(), commuted_old_path))
+                    .or_insert_with(|| commute::Shifts::new(&next_patch.hunks))
+                    .commute_before(&commuted_index_hunk)
+                    .or_else(|| {
+                        commute::commute_diff_before(
+                            &commuted_index_hunk,
+                            &next_patch.hunks,
+                            commute_options,
+                        )
+                    });
+                commuted_index_hunk = match commuted {
+                    Some(hunk) => {
+                        debug!(
+                            offset = (hunk.added.start as i64)
+                                - (commuted_index_hunk.added.start as i64),
+                            "commuted hunk with commit",
+                        );
+                        hunk
+                    }
+                    None if fuzz > 0 => {
+                        match reanchor(repo, commit, next_patch, &commuted_index_hunk, fuzz)? {
+                            Some(hunk) => {
+                                debug!(
+                                    offset = (hunk.added.start as i64)
+                                        - (commuted_index_hunk.added.start as i64),
+                                    "re-anchored hunk past commit",
+                                );
+                                hunk
+                            }
+                            None => {
+                                debug!("found noncommutative commit by conflict");
+                                dest_commit = Some(commit);
+                                reason = Reason::Conflict;
+                                break 'commit;
+                            }
+                        }
+                    }
+                    // this commit contains a hunk that cannot
+                    // commute with the hunk being absorbed
+                    None => {
+                        debug!("found noncommutative commit by conflict");
+                        dest_commit = Some(commit);
+                        reason = Reason::Conflict;
+                        break 'commit;
+                    }
+                };
+            }
+            let anchor = dest_commit.map(|_| (dest_path, commuted_index_hunk));
+            Ok::<_, anyhow::Error>((dest_commit, reason, anchor))
+        };
+
         let mut preceding_hunks_offset = 0isize;
         let mut applied_hunks_offset = 0isize;
         'hunk: for index_hunk in &index_patch.hunks {
-            debug!(config.logger, "next hunk";
-                   "header" => index_hunk.header(),
-                   "path" => String::from_utf8_lossy(old_path).into_owned(),
+            debug!(
+                header = %index_hunk.header(),
+                path = %String::from_utf8_lossy(old_path),
+                "next hunk",
             );
 
             // To properly handle files ("patches" in libgit2 lingo) with multiple hunks, we
@@ -172,338 +989,2209 @@ fn run_with_repo(config: &Config, repo: &git2::Repository) -> Result<()> {
             // |   -5,1 +3,0    | -5,1 +4,0 |    -4,1 +3,0     |
             // |----------------|-----------|------------------|
 
-            debug!(config.logger, "";
-                "to apply" => hunk_to_apply.header(),
-                "to commute" => isolated_hunk.header(),
-                "preceding hunks" => format!("{}/{}", applied_hunks_offset, preceding_hunks_offset),
+            debug!(
+                to_apply = %hunk_to_apply.header(),
+                to_commute = %isolated_hunk.header(),
+                preceding_hunks = %format!("{}/{}", applied_hunks_offset, preceding_hunks_offset),
             );
 
             preceding_hunks_offset += hunk_offset;
 
-            // find the newest commit that the hunk cannot commute with
-            let mut dest_commit = None;
-            let mut commuted_old_path = old_path;
-            let mut commuted_index_hunk = isolated_hunk;
-
-            'commit: for (commit, diff) in &stack {
-                let c_logger = config.logger.new(o!(
-                    "commit" => commit.id().to_string(),
-                ));
-                let next_patch = match diff.by_new(commuted_old_path) {
-                    Some(patch) => patch,
-                    // this commit doesn't touch the hunk's file, so
-                    // they trivially commute, and the next commit
-                    // should be considered
+            if let Some(target_commit) = target_commit {
+                debug!(reason = %target_reason, "using the given target commit");
+                let hunk_to_apply = match select_hunk(
+                    repo,
+                    &config,
+                    &mut quit_selecting,
+                    old_path,
+                    index_hunk,
+                    hunk_to_apply,
+                    target_commit,
+                    target_reason,
+                )? {
+                    Some(hunk) => hunk,
                     None => {
-                        debug!(c_logger, "skipped commit with no path");
-                        continue 'commit;
+                        unabsorbed.push(Unabsorbed {
+                            path: old_path.to_vec(),
+                            change: index_hunk.header(),
+                            reason: Reason::Declined,
+                        });
+                        continue 'hunk;
                     }
                 };
+                let hunk_offset = hunk_to_apply.changed_offset();
+                hunks_with_commit.push(HunkWithCommit {
+                    change: Change::Hunk(hunk_to_apply),
+                    dest_commit: target_commit,
+                    index_patch,
+                    reason: target_reason,
+                });
+                applied_hunks_offset += hunk_offset;
+                continue 'hunk;
+            }
 
-                // sometimes we just forget some change (eg: intializing some object) that
-                // happens in a completely unrelated place with the current hunks. In those
-                // cases, might be helpful to just match the first commit touching the same
-                // file as the current hunk. Use this option with care!
-                if config.whole_file {
-                    debug!(
-                        c_logger,
-                        "Commit touches the hunk file and match whole file is enabled"
-                    );
-                    dest_commit = Some(commit);
-                    break 'commit;
+            // find the newest commit that the hunk cannot commute with
+            let (mut dest_commit, mut reason, anchor) = commute_past_stack(&isolated_hunk)?;
+            if dest_commit.is_none() {
+                if let Some(blamer) = blamer.as_mut() {
+                    let blamed = blamer.newest_commit(
+                        old_path,
+                        &isolated_hunk,
+                        stack.iter().map(|(commit, _)| commit),
+                    )?;
+                    if let Some(i) = blamed {
+                        debug!(commit = %stack[i].0.id(), "found commit by blame");
+                        dest_commit = Some(&stack[i].0);
+                        reason = Reason::Blame;
+                    }
                 }
-
-                if next_patch.status == git2::Delta::Added {
-                    debug!(c_logger, "found noncommutative commit by add");
-                    dest_commit = Some(commit);
-                    break 'commit;
+            }
+            // the hunk may conflict with the newest commit only in part,
+            // in which case it is split, and the rest of it is absorbed
+            // into the commits below
+            let mut parts = vec![(isolated_hunk.clone(), dest_commit, reason, anchor)];
+            if split_hunks && matches!(reason, Reason::Conflict) {
+                let mut pieces = Vec::new();
+                for piece in isolated_hunk.split_lines() {
+                    let (dest, reason, anchor) = commute_past_stack(&piece)?;
+                    pieces.push((piece, dest, reason, anchor));
                 }
-                if commuted_old_path != next_patch.old_path.as_slice() {
-                    debug!(c_logger, "changed commute path";
-                           "path" => String::from_utf8_lossy(&next_patch.old_path).into_owned(),
-                    );
-                    commuted_old_path = next_patch.old_path.as_slice();
+                // runs of pieces that go into the same commit
+                let mut runs: Vec<std::ops::Range<usize>> = Vec::new();
+                for (i, (_, dest, _, _)) in pieces.iter().enumerate() {
+                    match runs.last_mut() {
+                        Some(run)
+                            if pieces[run.start].1.map(|commit| commit.id())
+                                == dest.map(|commit| commit.id()) =>
+                        {
+                            run.end = i + 1
+                        }
+                        _ => runs.push(i..i + 1),
+                    }
                 }
-                commuted_index_hunk = match commute::commute_diff_before(
-                    &commuted_index_hunk,
-                    &next_patch.hunks,
-                ) {
-                    Some(hunk) => {
-                        debug!(c_logger, "commuted hunk with commit";
-                               "offset" => (hunk.added.start as i64) - (commuted_index_hunk.added.start as i64),
+                if runs.len() > 1 {
+                    debug!(parts = runs.len(), "split hunk across commits");
+                    parts = runs
+                        .into_iter()
+                        .map(|run| {
+                            let run = &pieces[run];
+                            let (_, dest, reason, _) = &run[0];
+                            let join = |hunks: Vec<owned::Hunk>| owned::Hunk::join(&hunks);
+                            let part = join(run.iter().map(|(piece, ..)| piece.clone()).collect());
+                            // the pieces of a run commute past the same
+                            // commits, so they stay together in its commit
+                            let anchor = run[0].3.as_ref().map(|(path, _)| {
+                                let commuted = run
+                                    .iter()
+                                    .filter_map(|(.., anchor)| anchor.as_ref())
+                                    .map(|(_, commuted)| commuted.clone())
+                                    .collect();
+                                (*path, join(commuted))
+                            });
+                            (part, *dest, *reason, anchor)
+                        })
+                        .collect();
+                }
+            }
+
+            for (part, dest_commit, reason, anchor) in parts {
+                // the part as it is in the index, for reporting
+                let index_part = part
+                    .clone()
+                    .shift_added_block(preceding_hunks_offset - hunk_offset);
+                if let Some(rejection) = dest_commit.and_then(rejection) {
+                    if !config.explain {
+                        warn!(
+                            commit = ?dest_commit.map(|commit| commit.id()),
+                            reason = %rejection,
+                            "Not fixing up commit",
                         );
-                        hunk
                     }
-                    // this commit contains a hunk that cannot
-                    // commute with the hunk being absorbed
+                    unabsorbed.push(Unabsorbed {
+                        path: old_path.to_vec(),
+                        change: index_part.header(),
+                        reason: rejection,
+                    });
+                    continue;
+                }
+                // the rebase is more likely to apply the fixup cleanly if
+                // the lines around the hunk are the same in the commit
+                if let (Some(commit), Some((path, commuted))) = (dest_commit, &anchor) {
+                    if context_lines > 0
+                        && !context_matches(
+                            repo,
+                            index_patch.old_id,
+                            &part,
+                            commit,
+                            path,
+                            commuted,
+                            context_lines,
+                        )?
+                    {
+                        debug!(commit = %commit.id(), "context differs in commit");
+                        unabsorbed.push(Unabsorbed {
+                            path: old_path.to_vec(),
+                            change: index_part.header(),
+                            reason: Reason::ContextMismatch,
+                        });
+                        continue;
+                    }
+                }
+                let dest_commit = match dest_commit {
+                    Some(commit) => commit,
+                    // the hunk commutes with every commit in the stack,
+                    // so there is no commit to absorb it into
                     None => {
-                        debug!(c_logger, "found noncommutative commit by conflict");
-                        dest_commit = Some(commit);
-                        break 'commit;
+                        if !config.explain && !can_extend {
+                            warn!(
+                                "Could not find a commit to fix up, use \
+                                 --base to increase the search range.",
+                            );
+                        }
+                        unabsorbed.push(Unabsorbed {
+                            path: old_path.to_vec(),
+                            change: index_part.header(),
+                            reason,
+                        });
+                        continue;
+                    }
+                };
+
+                let hunk_to_apply = match select_hunk(
+                    repo,
+                    &config,
+                    &mut quit_selecting,
+                    old_path,
+                    &index_part,
+                    part.shift_both_blocks(applied_hunks_offset),
+                    dest_commit,
+                    reason,
+                )? {
+                    Some(hunk) => hunk,
+                    None => {
+                        unabsorbed.push(Unabsorbed {
+                            path: old_path.to_vec(),
+                            change: index_part.header(),
+                            reason: Reason::Declined,
+                        });
+                        continue;
                     }
                 };
+                // an edited hunk moves the lines below it differently
+                let hunk_offset = hunk_to_apply.changed_offset();
+                hunks_with_commit.push(HunkWithCommit {
+                    change: Change::Hunk(hunk_to_apply),
+                    dest_commit,
+                    index_patch,
+                    reason,
+                });
+
+                applied_hunks_offset += hunk_offset;
+            }
+        }
+
+        if index_patch.changes_mode() {
+            debug!(
+                path = %String::from_utf8_lossy(old_path),
+                mode = %format!("{:?} -> {:?}", index_patch.old_mode, index_patch.new_mode),
+                "next mode change",
+            );
+
+            // a mode change belongs to the newest commit that set the
+            // mode of the file, either by adding it or by changing it
+            let mut dest_commit = target_commit;
+            let mut reason = match target_commit {
+                Some(_) => target_reason,
+                None => Reason::StackExhausted,
+            };
+            let mut commuted_old_path = old_path;
+            'mode_commit: for (commit, diff) in stack.iter().filter(|_| target_commit.is_none()) {
+                let next_patch = match diff.by_new(commuted_old_path) {
+                    Some(patch) => patch,
+                    None => continue 'mode_commit,
+                };
+                if whole_file
+                    || next_patch.status == git2::Delta::Added
+                    || next_patch.status == git2::Delta::Copied
+                    || next_patch.status == git2::Delta::Typechange
+                    || next_patch.changes_mode()
+                {
+                    debug!(commit = %commit.id(), "found commit that set mode");
+                    dest_commit = Some(commit);
+                    reason = if whole_file {
+                        Reason::WholeFile
+                    } else if next_patch.status == git2::Delta::Added {
+                        Reason::AddedFile
+                    } else if next_patch.status == git2::Delta::Copied {
+                        Reason::CopiedFile
+                    } else if next_patch.status == git2::Delta::Typechange {
+                        Reason::SetType
+                    } else {
+                        Reason::SetMode
+                    };
+                    break 'mode_commit;
+                }
+                commuted_old_path = next_patch.old_path.as_slice();
+            }
+            if let Some(rejection) = dest_commit
+                .filter(|_| target_commit.is_none())
+                .and_then(rejection)
+            {
+                unabsorbed.push(Unabsorbed {
+                    path: old_path.to_vec(),
+                    change: Change::Mode(index_patch.new_mode).describe(),
+                    reason: rejection,
+                });
+                continue 'patch;
             }
-            let dest_commit = match dest_commit {
-                Some(commit) => commit,
-                // the hunk commutes with every commit in the stack,
-                // so there is no commit to absorb it into
+            match dest_commit {
+                Some(dest_commit) => hunks_with_commit.push(HunkWithCommit {
+                    change: Change::Mode(index_patch.new_mode),
+                    dest_commit,
+                    index_patch,
+                    reason,
+                }),
                 None => {
-                    warn!(
-                        config.logger,
-                        "Could not find a commit to fix up, use \
-                         --base to increase the search range."
-                    );
-                    continue 'hunk;
+                    if !config.explain && !can_extend {
+                        warn!(
+                            path = %String::from_utf8_lossy(old_path),
+                            "Could not find a commit to fix up the mode change, use \
+                             --base to increase the search range.",
+                        );
+                    }
+                    unabsorbed.push(Unabsorbed {
+                        path: old_path.to_vec(),
+                        change: Change::Mode(index_patch.new_mode).describe(),
+                        reason,
+                    });
                 }
-            };
+            }
+        }
+    }
+    report_planned(reporter, &hunks_with_commit, &unabsorbed, &mut reported);
+    span.exit();
 
-            let hunk_with_commit = HunkWithCommit {
-                hunk_to_apply,
-                dest_commit,
-                index_patch,
-            };
-            hunks_with_commit.push(hunk_with_commit);
+    let mut hunks: Vec<PlannedHunk> = hunks_with_commit
+        .into_iter()
+        .map(|hunk| PlannedHunk {
+            target: stack
+                .iter()
+                .position(|(commit, _)| commit.id() == hunk.dest_commit.id())
+                .expect("destination commits are in the stack"),
+            patch: index
+                .iter()
+                .position(|patch| patch.new_path == hunk.index_patch.new_path)
+                .expect("hunks are from the index"),
+            change: hunk.change,
+            reason: hunk.reason,
+        })
+        .collect();
+    if let Some(command) = config::matcher_command(repo) {
+        hunks = run_matcher(repo, &command, &stack, &index, hunks, &mut unabsorbed)?;
+    }
 
-            applied_hunks_offset += hunk_offset;
+    // report which remote branches will need a force-push once the
+    // fixups are squashed
+    let mut targets: Vec<git2::Oid> = hunks.iter().map(|hunk| stack[hunk.target].0.id()).collect();
+    targets.sort();
+    targets.dedup();
+    let pushed_targets = remote::branches_containing(repo, &targets)?;
+    if config::forbid_pushed_targets(repo) {
+        if let Some(commit) = pushed_targets.keys().next() {
+            return Err(Error::PushedTarget(*commit));
         }
     }
+    for (commit, branches) in &pushed_targets {
+        warn!(
+            %commit,
+            branches = %branches.join(", "),
+            "Fixing up a commit that has already been pushed, \
+             which will require a force-push",
+        );
+        reporter.on_warning(&format!(
+            "Fixing up commit {}, which has already been pushed to {}",
+            commit,
+            branches.join(", ")
+        ));
+    }
 
-    let target_always_sha: bool = config::fixup_target_always_sha(repo);
+    Ok(Plan {
+        repo,
+        config,
+        stack,
+        summary_counts,
+        index,
+        head_tree,
+        head_commit,
+        hunks,
+        unabsorbed,
+        patches_considered,
+        index_was_empty,
+        we_added_everything_to_index,
+        autostaged,
+        split,
+        stack_was_empty: false,
+        can_extend,
+        lock,
+    })
+}
 
-    hunks_with_commit.sort_by_key(|h| h.dest_commit.id());
-    // * apply all hunks that are going to be fixed up into `dest_commit`
-    // * commit the fixup
-    // * repeat for all `dest_commit`s
-    //
-    // the `.zip` here will gives us something similar to `.windows`, but with
-    // an extra iteration for the last element (otherwise we would have to
-    // special case the last element and commit it separately)
-    for (current, next) in hunks_with_commit
-        .iter()
-        .zip(hunks_with_commit.iter().skip(1).map(Some).chain([None]))
-    {
-        let new_head_tree = apply_hunk_to_tree(
-            repo,
-            &head_tree,
-            &current.hunk_to_apply,
-            &current.index_patch.old_path,
-        )?;
+/// What absorbing the index would do: which commit each staged change
+/// will be absorbed into, and which changes will be left in the index.
+/// Created by `plan`.
+pub struct Plan<'a> {
+    repo: &'a git2::Repository,
+    config: Config<'a>,
+    stack: Vec<(git2::Commit<'a>, owned::Diff)>,
+    summary_counts: HashMap<String, u64>,
+    index: owned::Diff,
+    head_tree: git2::Tree<'a>,
+    head_commit: git2::Commit<'a>,
+    hunks: Vec<PlannedHunk>,
+    unabsorbed: Vec<Unabsorbed>,
+    patches_considered: usize,
+    index_was_empty: bool,
+    we_added_everything_to_index: bool,
+    autostaged: Option<index_state::Autostaged<'a>>,
+    /// The commit given with --from.
+    split: Option<git2::Commit<'a>>,
+    stack_was_empty: bool,
+    /// Whether the stack stopped at its size limit, so that
+    /// absorb.autoExtendStack can widen it.
+    can_extend: bool,
+    // last, so that it is released after everything else is cleaned up
+    lock: lock::Lock,
+}
 
-        // whether there are no more hunks to apply to `dest_commit`
-        let commit_fixup = next.map_or(true, |next| {
-            // if the next hunk is for a different commit -- commit what we have so far
-            !config.one_fixup_per_commit || next.dest_commit.id() != current.dest_commit.id()
-        });
-        if commit_fixup {
-            // TODO: the git2 api only supports utf8 commit messages,
-            // so it's okay to use strings instead of bytes here
-            // https://docs.rs/git2/0.7.5/src/git2/repo.rs.html#998
+/// A staged change, and the commit it will be absorbed into.
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub path: Vec<u8>,
+    /// The hunk header or mode change, eg `-3,0 +4,1`.
+    pub change: String,
+    pub target: git2::Oid,
+    /// Why the change belongs in the target.
+    pub reason: String,
+}
+
+/// A staged change that will be left in the index.
+#[derive(Debug, Clone)]
+pub struct Leftover {
+    pub path: Vec<u8>,
+    pub change: String,
+    /// Why the change can't be absorbed.
+    pub reason: String,
+}
+
+/// A planned hunk, as indices into the plan's stack and index.
+struct PlannedHunk {
+    change: Change,
+    target: usize,
+    patch: usize,
+    reason: Reason,
+}
+
+impl<'a> Plan<'a> {
+    pub fn assignments(&self) -> Vec<Assignment> {
+        self.hunks
+            .iter()
+            .map(|hunk| Assignment {
+                path: self.index[hunk.patch].new_path.clone(),
+                change: hunk.change.describe(),
+                target: self.stack[hunk.target].0.id(),
+                reason: hunk.reason.to_string(),
+            })
+            .collect()
+    }
+
+    pub fn leftovers(&self) -> Vec<Leftover> {
+        self.unabsorbed
+            .iter()
+            .map(|hunk| Leftover {
+                path: hunk.path.clone(),
+                change: hunk.change.clone(),
+                reason: hunk.reason.to_string(),
+            })
+            .collect()
+    }
+
+    /// The parent of the oldest commit in the stack, which is where an
+    /// autosquash rebase has to start. None if the stack reaches the
+    /// root commit, or is empty.
+    pub fn base(&self) -> Option<git2::Oid> {
+        let (oldest, _) = self.stack.last()?;
+        oldest.parent_ids().next()
+    }
+
+    /// Creates the fixup commits (unless this is a dry run), and runs
+    /// the rebase if requested.
+    pub fn apply(self) -> Result<Outcome, Error> {
+        let Plan {
+            repo,
+            config,
+            stack,
+            summary_counts,
+            index,
+            mut head_tree,
+            mut head_commit,
+            mut hunks,
+            mut unabsorbed,
+            patches_considered,
+            index_was_empty,
+            we_added_everything_to_index,
+            autostaged,
+            split,
+            stack_was_empty,
+            can_extend: _,
+            lock: _lock,
+        } = self;
+        let config = &config;
+        let reporter = config.reporter.unwrap_or(&report::NoReporter);
+        if stack_was_empty {
+            return Ok(Outcome {
+                absorbed: if index_was_empty {
+                    Absorbed::NothingStaged
+                } else {
+                    Absorbed::None
+                },
+                summary: None,
+                exit_code: config.exit_code,
+            });
+        }
+
+        if config.edit_plan && !hunks.is_empty() {
+            hunks = edit_plan(repo, &stack, &index, hunks, &mut unabsorbed)?;
+        }
+
+        let hunks_with_commit: Vec<HunkWithCommit> = hunks
+            .into_iter()
+            .map(|hunk| HunkWithCommit {
+                change: hunk.change,
+                dest_commit: &stack[hunk.target].0,
+                index_patch: &index[hunk.patch],
+                reason: hunk.reason,
+            })
+            .collect();
+
+        let signature = repo
+            .signature()
+            .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))?;
+        let signer = if config.dry_run || config.no_gpg_sign {
+            None
+        } else {
+            sign::Signer::from_config(repo, &signature)?
+        };
+
+        if config.explain {
+            for hunk in &hunks_with_commit {
+                info!(
+                    path = %String::from_utf8_lossy(&hunk.index_patch.new_path),
+                    change = %hunk.change.describe(),
+                    commit = %hunk.dest_commit.id(),
+                    reason = %hunk.reason,
+                    "absorbing",
+                );
+            }
+            for hunk in &unabsorbed {
+                warn!(
+                    path = %String::from_utf8_lossy(&hunk.path),
+                    change = %hunk.change,
+                    reason = %hunk.reason,
+                    "not absorbing",
+                );
+            }
+        }
+
+        if config.confirm && !config.dry_run && !hunks_with_commit.is_empty() {
+            let plan = hunks_with_commit
+                .iter()
+                .map(|hunk| {
+                    format!(
+                        "{} {}: {}",
+                        String::from_utf8_lossy(&hunk.index_patch.new_path),
+                        hunk.change.describe(),
+                        select::describe(hunk.dest_commit),
+                    )
+                })
+                .chain(unabsorbed.iter().map(|hunk| {
+                    format!(
+                        "{} {}: left in the index, {}",
+                        String::from_utf8_lossy(&hunk.path),
+                        hunk.change,
+                        hunk.reason,
+                    )
+                }))
+                .collect::<Vec<_>>();
+            let fixups = if config.one_fixup_per_commit {
+                hunks_with_commit
+                    .iter()
+                    .map(|hunk| hunk.dest_commit.id())
+                    .collect::<HashSet<_>>()
+                    .len()
+            } else {
+                hunks_with_commit.len()
+            };
+            let question = format!(
+                "Create {} fixup commit{}?",
+                fixups,
+                if fixups == 1 { "" } else { "s" }
+            );
+            // unify() made sure that there is someone to ask
+            let confirmed = match config.ask {
+                Some(ask) => ask.ask_with_plan(&plan, &question)?,
+                None => false,
+            };
+            if !confirmed {
+                return Err(Error::NotConfirmed);
+            }
+        }
+
+        // the plan is given to the pre-absorb and post-absorb hooks in the
+        // porcelain format
+        let run_absorb_hooks = !config.dry_run && !hunks_with_commit.is_empty();
+        let mut hook_report = Vec::new();
+        porcelain::version(&mut hook_report)?;
+        for hunk in &hunks_with_commit {
+            porcelain::absorbed(
+                &mut hook_report,
+                hunk.dest_commit.id(),
+                &hunk.change.describe(),
+                &hunk.index_patch.new_path,
+            )?;
+        }
+        for hunk in &unabsorbed {
+            porcelain::leftover(&mut hook_report, hunk.reason, &hunk.change, &hunk.path)?;
+        }
+        if run_absorb_hooks {
+            hooks::run(repo, "pre-absorb", None::<&str>, &[], Some(&hook_report))?;
+        }
+
+        let mut porcelain_out = std::io::stdout();
+        if config.porcelain {
+            porcelain::version(&mut porcelain_out)?;
+            for hunk in &hunks_with_commit {
+                porcelain::absorbed(
+                    &mut porcelain_out,
+                    hunk.dest_commit.id(),
+                    &hunk.change.describe(),
+                    &hunk.index_patch.new_path,
+                )?;
+            }
+        }
+
+        let span = debug_span!("apply").entered();
+        let target_always_sha: bool = config::fixup_target_always_sha(repo);
+        let conventional_commits = config::conventional_commits(repo);
+        // eg `feat(parser)!: `, which linters want at the start of the
+        // subject, right after `fixup! `
+        let conventional_header = regex::Regex::new(r"^[a-zA-Z]+(\([^)]*\))?!?: ")
+            .expect("the conventional commit regex is valid");
+        let identity_trailers = config::identity_trailers(repo);
+        let copy_identity_trailers = config::copy_identity_trailers(repo);
+        let prepare_message = config::prepare_message(repo);
+        let cleanup = message::Cleanup::from_config(repo, config.edit)?;
+        let mut extra_trailers = config::trailers(repo);
+        if config.signoff {
+            extra_trailers.push(format!("Signed-off-by: {}", signature));
+        }
+
+        let mut summary = summary::Summary::new(config.dry_run);
+        for (commit, _) in &stack {
+            for hunk in &hunks_with_commit {
+                if hunk.dest_commit.id() == commit.id() {
+                    summary.absorbed(commit);
+                }
+            }
+        }
+        for hunk in &unabsorbed {
+            summary.leftover(hunk.reason);
+        }
+        let stack_commits: Vec<&git2::Commit> = stack.iter().map(|(commit, _)| commit).collect();
+        // the tree of the last fixup commit, which the next one will be
+        // diffed against
+        let mut fixup_parent_tree = head_tree.clone();
+
+        // fixups are committed for the oldest commits first, so that the
+        // parts of a split hunk are squashed in order
+        let hunks_with_commit = fixup_order(hunks_with_commit, &stack_commits);
+
+        let fixup_count = if config.one_fixup_per_commit {
+            hunks_with_commit
+                .iter()
+                .map(|hunk| hunk.dest_commit.id())
+                .collect::<HashSet<_>>()
+                .len()
+        } else {
+            hunks_with_commit.len()
+        };
+        let range = match stack.last().and_then(|(commit, _)| commit.parent(0).ok()) {
+            Some(base) => format!(
+                "{}..HEAD",
+                base.as_object().short_id()?.as_str().unwrap_or("")
+            ),
+            None => "HEAD".to_owned(),
+        };
+        let reflog_message = config::reflog_message(repo)
+            .replace("{count}", &fixup_count.to_string())
+            .replace("{range}", &range);
+        // refs are only updated once every fixup has been committed, so
+        // a failed run leaves them alone
+        let pre_absorb_head = split.as_ref().map_or(head_commit.id(), |split| split.id());
+        let mut reflog_entries = Vec::new();
+        // * apply all hunks that are going to be fixed up into `dest_commit`
+        // * commit the fixup
+        // * repeat for all `dest_commit`s
+        //
+        // the `.zip` here will gives us something similar to `.windows`, but with
+        // an extra iteration for the last element (otherwise we would have to
+        // special case the last element and commit it separately)
+        let reader = objects::reopen(repo)?;
+        let memory_odb = reader.odb()?;
+        let objects = objects::Buffered::new(repo, &memory_odb)?;
+        if config.verify_plan && !hunks_with_commit.is_empty() {
+            let fixups = fixup_trees(
+                repo,
+                &head_tree,
+                &hunks_with_commit,
+                config.one_fixup_per_commit,
+            )?;
+            verify_plan(repo, &stack_commits, &head_tree, &fixups)?;
+        }
+        // the fixups' trees, to check whether squashing them conflicts
+        let mut fixups = Vec::new();
+        let mut pending_changes = Vec::new();
+        let mut fixups_created = 0usize;
+        let mut patches_written = 0usize;
+        for (current, next) in hunks_with_commit
+            .iter()
+            .zip(hunks_with_commit.iter().skip(1).map(Some).chain([None]))
+        {
+            // nothing that was committed so far is on the branch yet
+            check_interrupt(config)?;
+            // whether there are no more hunks to apply to `dest_commit`
+            let commit_fixup = next.map_or(true, |next| {
+                // if the next hunk is for a different commit -- commit what we have so far
+                !config.one_fixup_per_commit || next.dest_commit.id() != current.dest_commit.id()
+            });
+
+            // all of the changes that go into the same fixup are applied
+            // together, so its trees are only written once
+            pending_changes.push((current.index_patch.old_path.as_slice(), &current.change));
+            if !commit_fixup {
+                continue;
+            }
+            let new_head_tree = apply_changes_to_tree(repo, &head_tree, &pending_changes)?;
+            pending_changes.clear();
+            // TODO: the git2 api only supports utf8 commit messages,
+            // so it's okay to use strings instead of bytes here
+            // https://docs.rs/git2/0.7.5/src/git2/repo.rs.html#998
             // https://libgit2.org/libgit2/#HEAD/group/commit/git_commit_create
-            let dest_commit_id = current.dest_commit.id().to_string();
-            let dest_commit_locator = match target_always_sha {
-                true => &dest_commit_id,
-                false => current
-                    .dest_commit
-                    .summary()
-                    .filter(|&msg| summary_counts[msg] == 1)
-                    .unwrap_or(&dest_commit_id),
+            // a fixup of a fixup would not be squashed properly, so
+            // fixups of fixups name the commit at the end of the chain
+            let squash_target = autosquash::target(current.dest_commit, &stack_commits);
+            let target_commit = match squash_target {
+                autosquash::Target::Commit(commit) => commit,
+                autosquash::Target::Subject(_) => current.dest_commit,
+            };
+            let dest_commit_id = target_commit.id().to_string();
+            let unique_summary = target_commit
+                .summary()
+                .filter(|&msg| summary_counts[msg] == 1);
+            // conventional headers are kept even if SHAs are preferred
+            let keep_header = conventional_commits
+                && unique_summary.is_some_and(|msg| conventional_header.is_match(msg));
+            let dest_commit_locator = match (&squash_target, target_always_sha && !keep_header) {
+                (autosquash::Target::Subject(subject), _) => subject,
+                (_, true) => &dest_commit_id,
+                (_, false) => unique_summary.unwrap_or(&dest_commit_id),
+            };
+            let fixup_diff =
+                repo.diff_tree_to_tree(Some(&fixup_parent_tree), Some(&new_head_tree), None)?;
+            let diff = fixup_diff.stats()?;
+            let mut message = if config.amend {
+                // the body of an amend! commit replaces the
+                // target's message when autosquashing
+                format!(
+                    "amend! {}\n\n{}",
+                    dest_commit_locator,
+                    autosquash::final_message(target_commit, &stack_commits)
+                )
+            } else {
+                format!(
+                    "{}! {}\n",
+                    if config.squash { "squash" } else { "fixup" },
+                    dest_commit_locator
+                )
             };
-            let diff = repo
-                .diff_tree_to_tree(Some(&head_commit.tree()?), Some(&new_head_tree), None)?
-                .stats()?;
+            let author = if config.fixup_author_is_target_author == Some(true) {
+                let target_author = target_commit.author();
+                match (target_author.name(), target_author.email()) {
+                    (Some(name), Some(email)) => {
+                        git2::Signature::new(name, email, &signature.when())?
+                    }
+                    _ => target_author.to_owned(),
+                }
+            } else {
+                signature.clone()
+            };
+            fixups.push(squash::Fixup {
+                target: current.dest_commit.id(),
+                parent_tree: fixup_parent_tree.clone(),
+                tree: new_head_tree.clone(),
+            });
+            // even in dry runs, later fixups are built on top of the
+            // earlier ones
+            head_tree = new_head_tree;
+            fixup_parent_tree = head_tree.clone();
             if !config.dry_run {
-                head_tree = new_head_tree;
-                head_commit = repo.find_commit(repo.commit(
-                    Some("HEAD"),
-                    &signature,
-                    &signature,
-                    &format!("fixup! {}\n", dest_commit_locator),
-                    &head_tree,
-                    &[&head_commit],
-                )?)?;
-                info!(config.logger, "committed";
-                      "commit" => head_commit.id().to_string(),
-                      "header" => format!("+{},-{}", diff.insertions(), diff.deletions()),
+                // like git commit, the pre-commit hook runs before the
+                // message is edited, and the commit-msg hook after
+                if config.run_hooks == Some(true) {
+                    // the hook needs to see the fixup's tree
+                    objects.flush(head_tree.id())?;
+                    hooks::pre_commit(repo, &head_tree)?;
+                }
+                if prepare_message {
+                    // an amend! body replaces the target's message,
+                    // which already went through the template
+                    message = message::prepare(repo, &message, !config.amend)?;
+                }
+                if config.edit {
+                    message = message::edit(repo, &message, cleanup)?;
+                }
+                // amend! commits replace the target's message, so they
+                // must keep its identity; squash! messages are appended
+                // to it, so they must not repeat it
+                if config.amend || (copy_identity_trailers && !config.squash) {
+                    let trailers = message::trailers(
+                        &autosquash::final_message(target_commit, &stack_commits),
+                        &identity_trailers,
+                    );
+                    message = message::add_trailers(&message, &trailers);
+                }
+                // like git commit -s --trailer
+                message = message::add_trailers(&message, &extra_trailers);
+                if config.run_hooks == Some(true) {
+                    message = hooks::commit_msg(repo, &message)?;
+                }
+                // like git commit, the message is cleaned up after the
+                // hooks had their say
+                message = message::cleanup_message(&message, cleanup);
+                let new_commit_id = match &signer {
+                    Some(signer) => signer.commit(
+                        repo,
+                        &author,
+                        &signature,
+                        &message,
+                        &head_tree,
+                        &[&head_commit],
+                    )?,
+                    None => repo.commit(
+                        None,
+                        &author,
+                        &signature,
+                        &message,
+                        &head_tree,
+                        &[&head_commit],
+                    )?,
+                };
+                // HEAD can only point at the fixup once it is on disk
+                objects.flush(new_commit_id)?;
+                if !config.one_reflog_entry {
+                    let subject = message.lines().next().unwrap_or("");
+                    reflog_entries.push((new_commit_id, format!("commit: {}", subject)));
+                }
+                head_commit = repo.find_commit(new_commit_id)?;
+                fixups_created += 1;
+                reporter.on_fixup_committed(Some(head_commit.id()), current.dest_commit.id());
+                summary.fixup(current.dest_commit);
+                porcelain::fixup(
+                    &mut hook_report,
+                    Some(head_commit.id()),
+                    current.dest_commit.id(),
+                )?;
+                if config.porcelain {
+                    porcelain::fixup(
+                        &mut porcelain_out,
+                        Some(head_commit.id()),
+                        current.dest_commit.id(),
+                    )?;
+                }
+                info!(
+                    commit = %head_commit.id(),
+                    header = %format!("+{},-{}", diff.insertions(), diff.deletions()),
+                    "committed",
+                );
+            } else {
+                summary.fixup(current.dest_commit);
+                reporter.on_fixup_committed(None, current.dest_commit.id());
+                if config.porcelain {
+                    porcelain::fixup(&mut porcelain_out, None, current.dest_commit.id())?;
+                }
+                if config.show_diff {
+                    preview::print_fixup(
+                        &mut std::io::stdout(),
+                        &message,
+                        current.dest_commit,
+                        &fixup_diff,
+                        std::io::stdout().is_terminal(),
+                    )?;
+                }
+                if let Some(dir) = config.format_patch {
+                    patches_written += 1;
+                    let path = mbox::write(
+                        dir,
+                        patches_written,
+                        fixup_count,
+                        &message,
+                        &fixup_diff,
+                        &author,
+                    )?;
+                    info!(
+                        fixup = %dest_commit_locator,
+                        path = %path.display(),
+                        "wrote patch",
+                    );
+                } else {
+                    info!(
+                        fixup = %dest_commit_locator,
+                        header = %format!("+{},-{}", diff.insertions(), diff.deletions()),
+                        "would have committed",
+                    );
+                }
+            }
+        }
+
+        if config.porcelain {
+            for hunk in &unabsorbed {
+                porcelain::leftover(&mut porcelain_out, hunk.reason, &hunk.change, &hunk.path)?;
+            }
+            porcelain_out.flush()?;
+        }
+        if let Some(split) = split.as_ref().filter(|_| fixups_created > 0) {
+            // whatever wasn't absorbed stays in the split commit, which
+            // is dropped if nothing is left
+            if head_tree.id() != split.tree_id() {
+                let message = split.message().unwrap_or("");
+                let tree = split.tree()?;
+                let new_commit_id = match &signer {
+                    Some(signer) => signer.commit(
+                        repo,
+                        &split.author(),
+                        &signature,
+                        message,
+                        &tree,
+                        &[&head_commit],
+                    )?,
+                    None => repo.commit(
+                        None,
+                        &split.author(),
+                        &signature,
+                        message,
+                        &tree,
+                        &[&head_commit],
+                    )?,
+                };
+                objects.flush(new_commit_id)?;
+                head_commit = repo.find_commit(new_commit_id)?;
+                if !config.one_reflog_entry {
+                    let subject = split.summary().unwrap_or("");
+                    reflog_entries.push((new_commit_id, format!("commit: {}", subject)));
+                }
+            }
+        }
+        // so that the rebase squashing the fixups doesn't stop on a
+        // conflict out of the blue
+        for (target, paths) in squash::conflicts(repo, &fixups)? {
+            let paths = paths
+                .iter()
+                .map(|path| String::from_utf8_lossy(path))
+                .collect::<Vec<_>>()
+                .join(", ");
+            warn!(
+                commit = %target,
+                paths = %paths,
+                "The fixup for this commit will likely conflict when squashed",
+            );
+            reporter.on_warning(&format!(
+                "The fixup for {} will likely conflict in {}",
+                target, paths
+            ));
+            if let Some(commit) = stack_commits.iter().find(|commit| commit.id() == target) {
+                summary.conflict(commit);
+            }
+        }
+        // anything that wasn't flushed was only needed for a dry run
+        drop(objects);
+        check_interrupt(config)?;
+        if fixups_created > 0 {
+            if config.one_reflog_entry {
+                reflog_entries.push((head_commit.id(), reflog_message.clone()));
+            }
+            update_refs(
+                repo,
+                pre_absorb_head,
+                head_commit.id(),
+                &signature,
+                &reflog_entries,
+                &reflog_message,
+            )?;
+            // the index didn't have the patch's (or the working tree's)
+            // changes, so they shouldn't seem to be staged
+            if config.patch.is_some() || config.unstaged {
+                index_state::follow_head(
+                    repo,
+                    &repo.find_commit(pre_absorb_head)?.tree()?,
+                    &head_commit.tree()?,
+                )?;
+            }
+        }
+
+        if let Some(autostaged) = autostaged {
+            if !config.dry_run && config::keep_auto_staged_leftovers(repo) {
+                // for committing what's left right away
+                autostaged.keep();
+            } else {
+                // now that the fixup commits have been created,
+                // we should unstage the remaining changes from the index
+                autostaged.unstage()?;
+            }
+        }
+        reporter.on_finished();
+
+        if patches_considered == 0 {
+            if index_was_empty && !we_added_everything_to_index {
+                warn!(
+                    "No changes staged, try adding something \
+                     to the index or set {} = true",
+                    config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME,
                 );
+                reporter.on_warning("No changes staged");
             } else {
-                info!(config.logger, "would have committed";
-                      "fixup" => dest_commit_locator,
-                      "header" => format!("+{},-{}", diff.insertions(), diff.deletions()),
+                warn!(
+                    "Could not find a commit to fix up, use \
+                     --base to increase the search range.",
                 );
+                reporter.on_warning("Could not find a commit to fix up");
+            }
+        } else if config.and_rebase {
+            use std::process::Command;
+            // unwrap() is safe here, as we exit early if the stack is empty
+            let last_commit_in_stack = &stack.last().unwrap().0;
+            // The stack isn't supposed to have any merge commits, per the check in working_stack(),
+            // unless it crosses them
+            let number_of_parents = last_commit_in_stack.parents().len();
+            assert!(number_of_parents <= 1 || config.cross_merges);
+
+            let mut command = Command::new("git");
+            // rebase the worktree that was absorbed into, wherever we
+            // were started from
+            if let Some(workdir) = repo.workdir() {
+                command.arg("-C").arg(workdir);
+            }
+            command.args(["rebase", "--interactive"]);
+            command.arg(match config.autosquash {
+                Some(false) => "--no-autosquash",
+                _ => "--autosquash",
+            });
+            command.arg(match config.autostash {
+                Some(false) => "--no-autostash",
+                _ => "--autostash",
+            });
+            if config.quiet {
+                command.arg("--quiet");
+            }
+            if config.rebase_non_interactive {
+                // git skips the editor when it is ":"
+                command.env("GIT_SEQUENCE_EDITOR", ":");
             }
+            if config.cross_merges {
+                command.arg("--rebase-merges");
+            }
+            if config.update_refs {
+                command.arg("--update-refs");
+            }
+            if config.committer_date_is_author_date {
+                command.arg("--committer-date-is-author-date");
+            }
+            if config.reset_author_date {
+                command.arg("--reset-author-date");
+            }
+
+            if number_of_parents == 0 {
+                command.arg("--root");
+            } else {
+                // Use a range that is guaranteed to include all the commits we might have
+                // committed "fixup!" commits for.
+                let base_commit_sha = last_commit_in_stack.parent(0)?.id().to_string();
+                command.arg(&base_commit_sha);
+            }
+
+            // Don't check that we have successfully absorbed everything, nor git's
+            // exit code -- as git will print helpful messages on its own.
+            let status = command.status().expect("could not run git rebase");
+
+            if config.and_push {
+                if !status.success() || repo.state() != git2::RepositoryState::Clean {
+                    warn!("Not pushing, since the rebase did not finish");
+                    reporter.on_warning("Not pushing, since the rebase did not finish");
+                } else if let Some(upstream) = remote::upstream(repo)? {
+                    remote::force_push(repo, &upstream, config.quiet)?;
+                } else {
+                    warn!("Not pushing, since the branch has no upstream");
+                    reporter.on_warning("Not pushing, since the branch has no upstream");
+                }
+            }
+        }
+
+        if !config.dry_run && !config.and_rebase && !hunks_with_commit.is_empty() {
+            // unwrap() is safe here, as the fixups' targets are in the stack
+            let last_commit_in_stack = &stack.last().unwrap().0;
+            summary.rebase_required(match last_commit_in_stack.parents().len() {
+                0 => "git rebase -i --autosquash --root".to_owned(),
+                _ => format!(
+                    "git rebase -i --autosquash {}",
+                    last_commit_in_stack.parent_id(0)?
+                ),
+            });
+        }
+        let summary = if !config.quiet && !summary.is_empty() {
+            Some(summary.to_string())
         } else {
-            // we didn't commit anything, but we applied a hunk
-            head_tree = new_head_tree;
+            None
+        };
+
+        if run_absorb_hooks {
+            // the fixups exist by now, so a failing hook can't stop anything
+            if let Err(err) = hooks::run(repo, "post-absorb", None::<&str>, &[], Some(&hook_report))
+            {
+                warn!("{}", err);
+                reporter.on_warning(&err.to_string());
+            }
         }
+        span.exit();
+
+        let absorbed = match (hunks_with_commit.is_empty(), unabsorbed.is_empty()) {
+            // changes that were ignored (eg whitespace) are still staged
+            (true, true) if index_was_empty => Absorbed::NothingStaged,
+            (true, _) => Absorbed::None,
+            (false, true) => Absorbed::All,
+            (false, false) => Absorbed::Some,
+        };
+        Ok(Outcome {
+            absorbed,
+            summary,
+            exit_code: config.exit_code,
+        })
     }
+}
 
-    if autostage_enabled && we_added_everything_to_index {
-        // now that the fixup commits have been created,
-        // we should unstage the remaining changes from the index.
+/// Fails if the run was interrupted. Everything done so far is undone
+/// as the error is passed up.
+fn check_interrupt(config: &Config) -> Result<(), Error> {
+    match config.interrupt {
+        Some(interrupt) if interrupt.load(Ordering::SeqCst) => Err(Error::Interrupted),
+        _ => Ok(()),
+    }
+}
 
-        let mut index = repo.index()?;
-        index.read_tree(&head_tree)?;
-        index.write()?;
+/// Refuses to add fixups in the middle of a rebase, merge or other
+/// operation, unless `force` is set.
+fn check_repo_state(
+    repo: &git2::Repository,
+    force: bool,
+    reporter: &dyn Reporter,
+) -> Result<(), Error> {
+    use git2::RepositoryState::*;
+    let operation = match repo.state() {
+        // bisecting doesn't care about new commits
+        Clean | Bisect => return Ok(()),
+        Merge => "merge",
+        Revert | RevertSequence => "revert",
+        CherryPick | CherryPickSequence => "cherry-pick",
+        Rebase | RebaseInteractive | RebaseMerge => "rebase",
+        ApplyMailbox | ApplyMailboxOrRebase => "git am",
+    };
+    if !force {
+        return Err(Error::OperationInProgress(operation));
     }
+    warn!(
+        operation,
+        "operation in progress, but --force-in-progress used to continue"
+    );
+    reporter.on_warning(&format!(
+        "A {} is in progress, but --force-in-progress used to continue",
+        operation
+    ));
+    Ok(())
+}
 
-    if patches_considered == 0 {
-        if index_was_empty && !we_added_everything_to_index {
-            warn!(
-                config.logger,
-                "No changes staged, try adding something \
-                 to the index or set {} = true",
-                config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME
-            );
-        } else {
-            warn!(
-                config.logger,
-                "Could not find a commit to fix up, use \
-                 --base to increase the search range."
-            )
+/// Moves HEAD from `old` to `new`, and points the pre-absorb ref at
+/// `old`, all at once. Each of `reflog_entries` is written to the
+/// reflogs of HEAD and its branch.
+fn update_refs(
+    repo: &git2::Repository,
+    old: git2::Oid,
+    new: git2::Oid,
+    signature: &git2::Signature,
+    reflog_entries: &[(git2::Oid, String)],
+    reflog_message: &str,
+) -> Result<()> {
+    let branch = repo.find_reference("HEAD")?.resolve()?;
+    let branch = branch
+        .name()
+        .ok_or_else(|| anyhow!("HEAD is not valid utf8"))?;
+    // so that the run can be undone with git reset, and earlier runs
+    // with PRE_ABSORB_HEAD@{n}
+    let pre_absorb_ref = config::pre_absorb_ref(repo);
+    repo.reference_ensure_log(&pre_absorb_ref)?;
+
+    let mut transaction = repo.transaction()?;
+    transaction.lock_ref(branch)?;
+    if branch != "HEAD" {
+        transaction.lock_ref("HEAD")?;
+    }
+    transaction.lock_ref(&pre_absorb_ref)?;
+    if repo.refname_to_id(branch)? != old {
+        return Err(anyhow!("{} was moved while absorbing", branch));
+    }
+
+    // the branch's own reflog isn't updated when its reflog is set
+    let mut reflogs = vec![(branch, repo.reflog(branch)?)];
+    if branch != "HEAD" {
+        reflogs.push(("HEAD", repo.reflog("HEAD")?));
+    }
+    for (name, mut reflog) in reflogs {
+        for (id, message) in reflog_entries {
+            reflog.append(*id, signature, Some(message))?;
         }
-    } else if config.and_rebase {
-        use std::process::Command;
-        // unwrap() is safe here, as we exit early if the stack is empty
-        let last_commit_in_stack = &stack.last().unwrap().0;
-        // The stack isn't supposed to have any merge commits, per the check in working_stack()
-        let number_of_parents = last_commit_in_stack.parents().len();
-        assert!(number_of_parents <= 1);
+        transaction.set_reflog(name, reflog)?;
+    }
+    transaction.set_target(branch, new, None, reflog_message)?;
+    transaction.set_target(&pre_absorb_ref, old, Some(signature), reflog_message)?;
+    transaction.commit()?;
+    Ok(())
+}
 
-        let mut command = Command::new("git");
-        command.args(["rebase", "--interactive", "--autosquash", "--autostash"]);
+/// A piece of the index that can be absorbed on its own.
+enum Change {
+    Hunk(owned::Hunk),
+    /// A new file mode, eg from toggling the executable bit.
+    Mode(git2::FileMode),
+    /// The new contents of a binary file, which has no hunks.
+    Blob(git2::Oid),
+    /// The new contents and mode of a file that changed type, eg from
+    /// a regular file to a symlink.
+    Type(git2::Oid, git2::FileMode),
+}
 
-        if number_of_parents == 0 {
-            command.arg("--root");
-        } else {
-            // Use a range that is guaranteed to include all the commits we might have
-            // committed "fixup!" commits for.
-            let base_commit_sha = last_commit_in_stack.parent(0)?.id().to_string();
-            command.arg(&base_commit_sha);
+impl Change {
+    fn describe(&self) -> String {
+        match self {
+            Change::Hunk(hunk) => hunk.header(),
+            Change::Mode(mode) => format!("mode {:o}", u32::from(*mode)),
+            Change::Blob(_) => "binary".to_owned(),
+            Change::Type(_, mode) => format!("type {:o}", u32::from(*mode)),
         }
+    }
+}
+
+/// Why a change was absorbed into a commit, or why it was not.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Reason {
+    /// The commit was given with --target.
+    Target,
+    /// The commit touches the file and --whole-file (or
+    /// absorb.wholeFilePaths) applies to it.
+    WholeFile,
+    /// The commit added the file.
+    AddedFile,
+    /// The commit copied the file from another one (with
+    /// absorb.findCopies).
+    CopiedFile,
+    /// The commit contains a hunk that does not commute with the change.
+    Conflict,
+    /// The commit last touched the lines of the change, according to
+    /// blame.
+    Blame,
+    /// The commit set the mode of the file.
+    SetMode,
+    /// The commit changed the type of the file, eg to a symlink.
+    SetType,
+    /// The change belongs in a commit that matches absorb.excludeCommits.
+    ExcludedCommit,
+    /// The change belongs in a merge commit.
+    MergeCommit,
+    /// The change belongs in a commit that does not match --commit-grep.
+    CommitGrep,
+    /// The change commutes with every commit in the stack.
+    StackExhausted,
+    /// The file was not modified, but eg added or deleted.
+    NotModified(git2::Delta),
+    /// The change was declined with --patch or dropped from the plan
+    /// with --edit-plan.
+    Declined,
+    /// The commit was chosen with --edit-plan.
+    Edited,
+    /// The commit was chosen by absorb.matcherCommand.
+    Matcher,
+    /// The commit was given for the file's path with absorb.pathTarget.
+    PathTarget,
+    /// The file is binary, so it can't be split into hunks.
+    Binary,
+    /// The file is larger than absorb.maxFileSize.
+    TooLarge,
+    /// The lines around the change are different in the commit, see
+    /// absorb.contextLines.
+    ContextMismatch,
+}
 
-        // Don't check that we have successfully absorbed everything, nor git's
-        // exit code -- as git will print helpful messages on its own.
-        command.status().expect("could not run git rebase");
+impl Reason {
+    /// A stable identifier for the reason, for machine-readable output.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Reason::Target => "target",
+            Reason::WholeFile => "whole-file",
+            Reason::AddedFile => "added-file",
+            Reason::CopiedFile => "copied-file",
+            Reason::Conflict => "conflict",
+            Reason::Blame => "blame",
+            Reason::SetMode => "set-mode",
+            Reason::SetType => "set-type",
+            Reason::ExcludedCommit => "excluded-commit",
+            Reason::MergeCommit => "merge-commit",
+            Reason::CommitGrep => "commit-grep",
+            Reason::StackExhausted => "stack-exhausted",
+            Reason::NotModified(_) => "not-modified",
+            Reason::Declined => "declined",
+            Reason::Edited => "edited",
+            Reason::Matcher => "matcher",
+            Reason::PathTarget => "path-target",
+            Reason::Binary => "binary",
+            Reason::TooLarge => "too-large",
+            Reason::ContextMismatch => "context-mismatch",
+        }
     }
+}
 
-    Ok(())
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reason::Target => write!(f, "the commit was given with --target"),
+            Reason::WholeFile => write!(
+                f,
+                "the commit touches the file and whole-file matching is enabled for it"
+            ),
+            Reason::AddedFile => write!(f, "the commit added the file"),
+            Reason::CopiedFile => write!(f, "the commit copied the file"),
+            Reason::Conflict => write!(f, "the change does not commute with the commit"),
+            Reason::Blame => write!(f, "the commit last touched the changed lines"),
+            Reason::SetMode => write!(f, "the commit set the file's mode"),
+            Reason::SetType => write!(f, "the commit changed the file's type"),
+            Reason::ExcludedCommit => write!(
+                f,
+                "the change belongs in a commit that is excluded by {}",
+                config::EXCLUDE_COMMITS_CONFIG_NAME
+            ),
+            Reason::MergeCommit => write!(f, "the change belongs in a merge commit"),
+            Reason::CommitGrep => write!(
+                f,
+                "the change belongs in a commit that does not match --commit-grep"
+            ),
+            Reason::StackExhausted => write!(
+                f,
+                "the change commutes with every commit in the stack, \
+                 use --base to increase the search range"
+            ),
+            Reason::NotModified(status) => write!(
+                f,
+                "only modified files can be absorbed, but the file was {:?}",
+                status
+            ),
+            Reason::Declined => write!(f, "the change was declined"),
+            Reason::Edited => write!(f, "the commit was chosen by editing the plan"),
+            Reason::Matcher => write!(
+                f,
+                "the commit was chosen by {}",
+                config::MATCHER_COMMAND_CONFIG_NAME
+            ),
+            Reason::PathTarget => write!(
+                f,
+                "the commit was given for the file by {}",
+                config::PATH_TARGET_CONFIG_NAME
+            ),
+            Reason::Binary => write!(f, "the file is binary, so it is not absorbable"),
+            Reason::TooLarge => write!(
+                f,
+                "the file is larger than {}",
+                config::MAX_FILE_SIZE_CONFIG_NAME
+            ),
+            Reason::ContextMismatch => write!(
+                f,
+                "the lines around the change are different in the commit, see {}",
+                config::CONTEXT_LINES_CONFIG_NAME
+            ),
+        }
+    }
 }
 
 struct HunkWithCommit<'c, 'r, 'p> {
-    hunk_to_apply: owned::Hunk,
+    change: Change,
     dest_commit: &'c git2::Commit<'r>,
     index_patch: &'p owned::Patch,
+    reason: Reason,
 }
 
-fn apply_hunk_to_tree<'repo>(
-    repo: &'repo git2::Repository,
-    base: &git2::Tree,
+/// A change that could not be absorbed.
+struct Unabsorbed {
+    /// The path of the file in the index, ie its new path if renamed,
+    /// like the `index_patch.new_path` of absorbed hunks.
+    path: Vec<u8>,
+    change: String,
+    reason: Reason,
+}
+
+/// Passes the changes that were planned since the last call on to
+/// `reporter`. `reported` counts the hunks and leftovers reported so far.
+fn report_planned(
+    reporter: &dyn Reporter,
+    hunks_with_commit: &[HunkWithCommit],
+    unabsorbed: &[Unabsorbed],
+    reported: &mut (usize, usize),
+) {
+    for hunk in &hunks_with_commit[reported.0..] {
+        reporter.on_hunk_planned(
+            &hunk.index_patch.new_path,
+            &hunk.change.describe(),
+            Some(hunk.dest_commit.id()),
+            &hunk.reason.to_string(),
+        );
+    }
+    for hunk in &unabsorbed[reported.1..] {
+        reporter.on_hunk_planned(&hunk.path, &hunk.change, None, &hunk.reason.to_string());
+    }
+    *reported = (hunks_with_commit.len(), unabsorbed.len());
+}
+
+/// Tries to commute `hunk` past `commit` by re-anchoring it in the
+/// commit's version of the file, see `commute::reanchor`.
+fn reanchor(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    patch: &owned::Patch,
+    hunk: &owned::Hunk,
+    fuzz: usize,
+) -> Result<Option<owned::Hunk>> {
+    let blob = |tree: git2::Tree, path: &[u8]| -> Result<Option<git2::Blob>> {
+        let path = match repo_path::to_path(path) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        // eg a file that a later commit of the stack added
+        let entry = match tree.get_path(path) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(repo.find_blob(entry.id())?))
+    };
+    if commit.parent_count() != 1 {
+        return Ok(None);
+    }
+    let (pre, post) = match (
+        blob(commit.parent(0)?.tree()?, &patch.old_path)?,
+        blob(commit.tree()?, &patch.new_path)?,
+    ) {
+        (Some(pre), Some(post)) => (pre, post),
+        _ => return Ok(None),
+    };
+    Ok(commute::reanchor(
+        hunk,
+        &patch.hunks,
+        pre.content(),
+        post.content(),
+        fuzz,
+    ))
+}
+
+/// Whether the lines that `hunk` removes from `HEAD`'s version of the
+/// file (`head_blob`), and the `context` lines around them, are the same
+/// in `commit`'s version of the file at `path`, where the hunk commuted
+/// to `commuted`.
+fn context_matches(
+    repo: &git2::Repository,
+    head_blob: git2::Oid,
     hunk: &owned::Hunk,
+    commit: &git2::Commit,
     path: &[u8],
+    commuted: &owned::Hunk,
+    context: usize,
+) -> Result<bool> {
+    let commit_blob =
+        match repo_path::to_path(path).and_then(|path| commit.tree().ok()?.get_path(path).ok()) {
+            Some(entry) => entry.id(),
+            None => return Ok(false),
+        };
+    let lines_around = |blob: git2::Oid, hunk: &owned::Hunk| -> Result<Vec<Vec<u8>>> {
+        let blob = repo.find_blob(blob)?;
+        let lines: Vec<&[u8]> = blob.content().split_inclusive(|&b| b == b'\n').collect();
+        // the index of the first removed line, or of the line that the
+        // added lines go before
+        let at = match hunk.removed.lines.len() {
+            0 => hunk.removed.start,
+            _ => hunk.removed.start - 1,
+        };
+        let start = at.saturating_sub(context).min(lines.len());
+        let end = (at + hunk.removed.lines.len() + context).min(lines.len());
+        Ok(lines[start..end].iter().map(|line| line.to_vec()).collect())
+    };
+    Ok(lines_around(head_blob, hunk)? == lines_around(commit_blob, commuted)?)
+}
+
+/// Whether `path` is managed by a clean/smudge filter driver, like Git
+/// LFS. libgit2 does not run filter drivers, so these files have to be
+/// staged by git itself.
+fn uses_filter_driver(repo: &git2::Repository, path: &Path) -> bool {
+    let driver = match repo.get_attr(path, "filter", git2::AttrCheckFlags::FILE_THEN_INDEX) {
+        Ok(value) => match git2::AttrValue::from_string(value) {
+            git2::AttrValue::String(driver) => driver.to_owned(),
+            _ => return false,
+        },
+        Err(_) => return false,
+    };
+    repo.config().is_ok_and(|config| {
+        ["clean", "process"].iter().any(|key| {
+            config
+                .get_string(&format!("filter.{}.{}", driver, key))
+                .is_ok()
+        })
+    })
+}
+
+/// Applies `changes` to the files at their paths in `base`, and returns
+/// the new tree. The changes to each file must be in file order, each
+/// hunk positioned as if the ones before it were already applied.
+///
+/// Every changed blob is written first, and then the trees that contain
+/// them are rebuilt once each, from the deepest up to `base`.
+fn apply_changes_to_tree<'repo>(
+    repo: &'repo git2::Repository,
+    base: &git2::Tree,
+    changes: &[(&[u8], &Change)],
 ) -> Result<git2::Tree<'repo>> {
-    let mut treebuilder = repo.treebuilder(Some(base))?;
+    let mut by_path: BTreeMap<&[u8], Vec<&Change>> = BTreeMap::new();
+    for &(path, change) in changes {
+        by_path.entry(path).or_default().push(change);
+    }
 
-    // recurse into nested tree if applicable
-    if let Some(slash) = path.iter().position(|&x| x == b'/') {
-        let (first, rest) = path.split_at(slash);
-        let rest = &rest[1..];
+    // the new entries of each tree that changes, keyed by the tree's
+    // depth and path ("" for `base`) so that the deepest trees are built
+    // first, before the trees that contain them
+    type Entries = Vec<(Vec<u8>, git2::Oid, i32)>;
+    let mut trees: BTreeMap<(usize, Vec<u8>), Entries> = BTreeMap::new();
+    trees.insert((0, Vec::new()), Vec::new());
+    for (path, changes) in by_path {
+        let entry = tree_entry(repo, base, path)?
+            .ok_or_else(|| anyhow!("couldn't find blob entry in tree for path"))?;
+        let (blob, mode) = apply_changes_to_blob(
+            repo,
+            entry.id(),
+            entry.filemode(),
+            &changes,
+            // the full path, to select gitattributes filters (eg eol
+            // conversion) by
+            repo_path::to_path(path),
+        )?;
+        let (dir, name) = split_path(path);
+        let depth = path.iter().filter(|&&byte| byte == b'/').count();
+        trees
+            .entry((depth, dir.to_vec()))
+            .or_default()
+            .push((name.to_vec(), blob, mode));
+    }
 
-        let (subtree, submode) = {
-            let entry = treebuilder
-                .get(first)?
+    while let Some(((depth, dir), entries)) = trees.pop_last() {
+        let (tree, mode) = if depth == 0 {
+            (base.clone(), 0)
+        } else {
+            let entry = tree_entry(repo, base, &dir)?
                 .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?;
             (repo.find_tree(entry.id())?, entry.filemode())
         };
-        // TODO: loop instead of recursing to avoid potential stack overflow
-        let result_subtree = apply_hunk_to_tree(repo, &subtree, hunk, rest)?;
-
-        treebuilder.insert(first, result_subtree.id(), submode)?;
-        return Ok(repo.find_tree(treebuilder.write()?)?);
+        let mut treebuilder = repo.treebuilder(Some(&tree))?;
+        for (name, id, mode) in entries {
+            treebuilder.insert(name, id, mode)?;
+        }
+        let id = treebuilder.write()?;
+        if depth == 0 {
+            return Ok(repo.find_tree(id)?);
+        }
+        let (parent, name) = split_path(&dir);
+        trees
+            .entry((depth - 1, parent.to_vec()))
+            .or_default()
+            .push((name.to_vec(), id, mode));
     }
+    unreachable!("the base tree is always rebuilt")
+}
 
-    let (blob, mode) = {
-        let entry = treebuilder
-            .get(path)?
-            .ok_or_else(|| anyhow!("couldn't find blob entry in tree for path"))?;
-        (repo.find_blob(entry.id())?, entry.filemode())
-    };
-
-    // TODO: convert path to OsStr and pass it during blob_writer
-    // creation, to get gitattributes handling (note that converting
-    // &[u8] to &std::path::Path is only possible on unixy platforms)
-    let mut blobwriter = repo.blob_writer(None)?;
-    let old_content = blob.content();
-    let (old_start, _, _, _) = hunk.anchors();
-
-    // first, write the lines from the old content that are above the
-    // hunk
-    let old_content = {
-        let (pre, post) = split_lines_after(old_content, old_start);
-        blobwriter.write_all(pre)?;
-        post
-    };
-    // next, write the added side of the hunk
-    for line in &*hunk.added.lines {
-        blobwriter.write_all(line)?;
+/// Finds where the files that `diff` shows as added were moved from, if
+/// they were moved (or copied, with `copies`), by detecting renames in
+/// the whole of the commit from `parent_tree` to `tree`.
+fn rename_sources(
+    repo: &git2::Repository,
+    parent_tree: &git2::Tree,
+    tree: &git2::Tree,
+    diff: &git2::Diff,
+    copies: bool,
+) -> Result<Vec<Vec<u8>>> {
+    let added: HashSet<&[u8]> = diff
+        .deltas()
+        .filter(|delta| delta.status() == git2::Delta::Added)
+        .filter_map(|delta| delta.new_file().path_bytes())
+        .collect();
+    if added.is_empty() {
+        return Ok(Vec::new());
     }
-    // if this hunk removed lines from the old content, those must be
-    // skipped
-    let (_, old_content) = split_lines_after(old_content, hunk.removed.lines.len());
-    // finally, write the remaining lines of the old content
-    blobwriter.write_all(old_content)?;
 
-    treebuilder.insert(path, blobwriter.commit()?, mode)?;
-    Ok(repo.find_tree(treebuilder.write()?)?)
+    let mut whole = repo.diff_tree_to_tree(
+        Some(parent_tree),
+        Some(tree),
+        Some(git2::DiffOptions::new().include_unmodified(copies)),
+    )?;
+    whole.find_similar(Some(&mut similarity_options(copies)))?;
+    Ok(whole
+        .deltas()
+        .filter(|delta| matches!(delta.status(), git2::Delta::Renamed | git2::Delta::Copied))
+        .filter(|delta| {
+            delta
+                .new_file()
+                .path_bytes()
+                .is_some_and(|path| added.contains(path))
+        })
+        .filter_map(|delta| delta.old_file().path_bytes().map(Vec::from))
+        .collect())
 }
 
-/// Return slices for lines [1..n] and [n+1; ...]
-fn split_lines_after(content: &[u8], n: usize) -> (&[u8], &[u8]) {
-    let split_index = if n > 0 {
-        memchr::Memchr::new(b'\n', content)
-            .fuse() // TODO: is fuse necessary here?
-            .nth(n - 1) // the position of '\n' ending the `n`-th line
-            .map(|x| x + 1)
-            .unwrap_or_else(|| content.len())
-    } else {
-        0
-    };
-    content.split_at(split_index)
+/// The options to detect renames, and copies from any file with
+/// `copies`, in a diff.
+fn similarity_options(copies: bool) -> git2::DiffFindOptions {
+    let mut options = git2::DiffFindOptions::new();
+    options
+        .renames(true)
+        .copies(copies)
+        .copies_from_unmodified(copies)
+        .remove_unmodified(copies);
+    options
 }
 
-fn nothing_left_in_index(repo: &git2::Repository) -> Result<bool> {
-    let stats = index_stats(repo)?;
-    let nothing = stats.files_changed() == 0 && stats.insertions() == 0 && stats.deletions() == 0;
-    Ok(nothing)
+/// Looks up the entry at the byte `path` in `tree`, like
+/// `Tree::get_path`, but also for paths that the platform can't
+/// represent as a `Path`.
+fn tree_entry(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    path: &[u8],
+) -> Result<Option<git2::TreeEntry<'static>>> {
+    let (dir, name) = split_path(path);
+    if dir.is_empty() {
+        return Ok(tree.get_name_bytes(name).map(|entry| entry.to_owned()));
+    }
+    let subtree = match tree_entry(repo, tree, dir)? {
+        Some(entry) if entry.kind() == Some(git2::ObjectType::Tree) => {
+            repo.find_tree(entry.id())?
+        }
+        _ => return Ok(None),
+    };
+    Ok(subtree.get_name_bytes(name).map(|entry| entry.to_owned()))
 }
 
-fn index_stats(repo: &git2::Repository) -> Result<git2::DiffStats> {
-    let head = repo.head()?.peel_to_tree()?;
-    let diff = repo.diff_tree_to_index(Some(&head), Some(&repo.index()?), None)?;
-    let stats = diff.stats()?;
-    Ok(stats)
+/// Splits a path into its parent directory ("" at the top level) and
+/// its last component.
+fn split_path(path: &[u8]) -> (&[u8], &[u8]) {
+    match path.iter().rposition(|&byte| byte == b'/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => (&[], path),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::path::{Path, PathBuf};
+/// Applies `changes` to the blob `blob_id`, and returns the new blob and
+/// file mode.
+fn apply_changes_to_blob(
+    repo: &git2::Repository,
+    blob_id: git2::Oid,
+    mut mode: i32,
+    changes: &[&Change],
+    filter_path: Option<&Path>,
+) -> Result<(git2::Oid, i32)> {
+    let mut hunks = Vec::with_capacity(changes.len());
+    let mut blob_id = blob_id;
+    for change in changes {
+        match change {
+            Change::Hunk(hunk) => hunks.push(hunk),
+            Change::Mode(new_mode) => mode = (*new_mode).into(),
+            // binary files have no hunks to go with this
+            Change::Blob(new_blob_id) => blob_id = *new_blob_id,
+            Change::Type(new_blob_id, new_mode) => {
+                blob_id = *new_blob_id;
+                mode = (*new_mode).into();
+            }
+        }
+    }
+    if hunks.is_empty() {
+        return Ok((blob_id, mode));
+    }
 
-    use super::*;
+    // loose objects can be streamed instead of loaded into memory all
+    // at once, but packed objects can only be read whole
+    let odb = repo.odb()?;
+    let blob;
+    let mut old_content: Box<dyn std::io::BufRead> = match odb.reader(blob_id) {
+        Ok((reader, _, _)) => Box::new(std::io::BufReader::new(reader)),
+        Err(_) => {
+            blob = repo.find_blob(blob_id)?;
+            Box::new(blob.content())
+        }
+    };
 
-    struct Context {
-        repo: git2::Repository,
-        dir: tempfile::TempDir,
+    // the hunks and the old content are both already normalized, but
+    // writing the blob through the filters keeps it consistent with
+    // what git add would write (eg with core.safecrlf)
+    let mut blobwriter = repo.blob_writer(filter_path)?;
+    // the number of lines of the old content already written or skipped
+    let mut old_line = 0;
+    // how far the hunks applied so far moved the lines below them
+    let mut offset = 0isize;
+    for hunk in hunks {
+        let (old_start, _, _, _) = hunk.anchors();
+        let old_start = (old_start as isize - offset) as usize;
+        // first, write the lines from the old content that are above
+        // the hunk
+        copy_lines(
+            &mut old_content,
+            Some(&mut blobwriter),
+            old_start.saturating_sub(old_line),
+        )?;
+        // next, write the added side of the hunk
+        for line in &*hunk.added.lines {
+            blobwriter.write_all(line)?;
+        }
+        // if this hunk removed lines from the old content, those must
+        // be skipped
+        copy_lines(&mut old_content, None, hunk.removed.lines.len())?;
+        // an added line without a newline would run into the next one
+        if !hunk.added.trailing_newline && !old_content.fill_buf()?.is_empty() {
+            return Err(anyhow!(
+                "hunk {} is missing a newline at the end but is not at the end of the file",
+                hunk.header()
+            ));
+        }
+        old_line = old_start + hunk.removed.lines.len();
+        offset += hunk.changed_offset();
     }
+    // finally, write the remaining lines of the old content
+    std::io::copy(&mut old_content, &mut blobwriter)?;
 
-    impl Context {
-        fn join(&self, p: &Path) -> PathBuf {
-            self.dir.path().join(p)
+    Ok((blobwriter.commit()?, mode))
+}
+
+/// Copies the next `n` lines of `from` to `to`, or skips them if `to` is
+/// None. Stops early at the end of `from`.
+fn copy_lines(
+    from: &mut dyn std::io::BufRead,
+    mut to: Option<&mut dyn Write>,
+    n: usize,
+) -> Result<()> {
+    let mut line = Vec::new();
+    for _ in 0..n {
+        line.clear();
+        if from.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if let Some(to) = to.as_mut() {
+            to.write_all(&line)?;
         }
     }
+    Ok(())
+}
 
-    /// Prepare a fresh git repository with an initial commit and a file.
-    fn prepare_repo() -> (Context, PathBuf) {
-        let dir = tempfile::tempdir().unwrap();
-        let repo = git2::Repository::init(dir.path()).unwrap();
+/// Works out the trees of the fixup commits that `hunks` make on top of
+/// `head_tree`, without committing them.
+fn fixup_trees<'r>(
+    repo: &'r git2::Repository,
+    head_tree: &git2::Tree<'r>,
+    hunks: &[HunkWithCommit],
+    one_fixup_per_commit: bool,
+) -> Result<Vec<squash::Fixup<'r>>> {
+    let mut fixups: Vec<squash::Fixup> = Vec::new();
+    let mut pending = Vec::new();
+    for (i, hunk) in hunks.iter().enumerate() {
+        pending.push((hunk.index_patch.old_path.as_slice(), &hunk.change));
+        let commit_fixup = hunks.get(i + 1).map_or(true, |next| {
+            !one_fixup_per_commit || next.dest_commit.id() != hunk.dest_commit.id()
+        });
+        if !commit_fixup {
+            continue;
+        }
+        let parent_tree = fixups
+            .last()
+            .map_or_else(|| head_tree.clone(), |fixup| fixup.tree.clone());
+        let tree = apply_changes_to_tree(repo, &parent_tree, &pending)?;
+        pending.clear();
+        fixups.push(squash::Fixup {
+            target: hunk.dest_commit.id(),
+            parent_tree,
+            tree,
+        });
+    }
+    Ok(fixups)
+}
+
+/// Checks that squashing `fixups` into the stack gives the same tree as
+/// the fixups on top of `head_tree` do, before any of them is committed.
+fn verify_plan<'r>(
+    repo: &'r git2::Repository,
+    stack: &[&git2::Commit<'r>],
+    head_tree: &git2::Tree<'r>,
+    fixups: &[squash::Fixup<'r>],
+) -> Result<(), Error> {
+    let expected = fixups
+        .last()
+        .map_or(head_tree.id(), |fixup| fixup.tree.id());
+    match squash::simulate(repo, stack, fixups)? {
+        None => {
+            warn!("Could not verify the plan, since the stack has merge commits");
+            Ok(())
+        }
+        Some(squash::Squashed::Tree(tree)) if tree == expected => {
+            debug!(%tree, "verified plan");
+            Ok(())
+        }
+        Some(squash::Squashed::Tree(tree)) => Err(Error::PlanNotVerified(format!(
+            "squashing the fixups would give tree {} instead of {}",
+            tree, expected
+        ))),
+        Some(squash::Squashed::Conflict {
+            commit,
+            fixup,
+            paths,
+        }) => Err(Error::PlanNotVerified(format!(
+            "{} {} would conflict in {}",
+            if fixup { "the fixup for" } else { "commit" },
+            commit,
+            paths
+                .iter()
+                .map(|path| String::from_utf8_lossy(path))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Orders the hunks by their destination commits, oldest first, and
+/// keeps each hunk positioned as if the hunks above it in the same file
+/// were applied before it, which the plan assumed in its own order.
+fn fixup_order<'c, 'r, 'p>(
+    hunks: Vec<HunkWithCommit<'c, 'r, 'p>>,
+    stack: &[&git2::Commit],
+) -> Vec<HunkWithCommit<'c, 'r, 'p>> {
+    let position = |hunk: &HunkWithCommit| {
+        stack
+            .iter()
+            .position(|commit| commit.id() == hunk.dest_commit.id())
+    };
+    let mut order: Vec<usize> = (0..hunks.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(position(&hunks[i])));
+
+    let offset = |hunk: &HunkWithCommit| match &hunk.change {
+        Change::Hunk(hunk) => hunk.changed_offset(),
+        _ => 0,
+    };
+    let mut slots: Vec<Option<HunkWithCommit>> = hunks.into_iter().map(Some).collect();
+    let mut ordered = Vec::with_capacity(slots.len());
+    for i in order {
+        let mut hunk = slots[i].take().expect("each hunk is ordered once");
+        let path = hunk.index_patch.old_path.as_slice();
+        // the hunks above this one that are not applied yet
+        let pending: isize = slots[..i]
+            .iter()
+            .flatten()
+            .filter(|other| other.index_patch.old_path == path)
+            .map(offset)
+            .sum();
+        if let Change::Hunk(change) = hunk.change {
+            hunk.change = Change::Hunk(change.shift_both_blocks(-pending));
+        }
+        ordered.push(hunk);
+    }
+    ordered
+}
+
+/// Finds the commit given with --from, which can only be split if it is
+/// HEAD and not a merge.
+fn split_commit<'r>(repo: &'r git2::Repository, from: &str) -> Result<git2::Commit<'r>> {
+    let commit = repo.revparse_single(from)?.peel_to_commit()?;
+    if repo.head()?.target() != Some(commit.id()) {
+        return Err(anyhow!(
+            "--from {} is not HEAD, only the commit at the tip of the branch can be split",
+            from
+        ));
+    }
+    if commit.parent_count() > 1 {
+        return Err(Error::MergeCommitBoundary(commit.id()).into());
+    }
+    if commit.parent_count() == 0 {
+        return Err(anyhow!("--from {} must have a parent", from));
+    }
+    Ok(commit)
+}
+
+/// Finds the commit of the stack that an absorb.pathTarget rule names,
+/// either as a commit-ish or as a regex that its summary matches (the
+/// newest such commit). Merge commits are never targets.
+fn path_target<'a, 'r>(
+    repo: &git2::Repository,
+    stack: &'a [(git2::Commit<'r>, owned::Diff)],
+    target: &str,
+) -> Result<Option<&'a git2::Commit<'r>>> {
+    let commits = || {
+        stack
+            .iter()
+            .map(|(commit, _)| commit)
+            .filter(|commit| commit.parent_count() == 1)
+    };
+    if let Ok(id) = repo
+        .revparse_single(target)
+        .and_then(|object| object.peel_to_commit())
+        .map(|commit| commit.id())
+    {
+        if let Some(commit) = commits().find(|commit| commit.id() == id) {
+            return Ok(Some(commit));
+        }
+    }
+    let regex = regex::Regex::new(target)
+        .map_err(|e| anyhow!("invalid {}: {}", config::PATH_TARGET_CONFIG_NAME, e))?;
+    Ok(commits().find(|commit| {
+        regex.is_match(&String::from_utf8_lossy(
+            commit.summary_bytes().unwrap_or_default(),
+        ))
+    }))
+}
+
+/// Lets the user reassign or drop the planned `hunks` in an editor, for
+/// --edit-plan, and returns the hunks that are still to be absorbed.
+/// Dropped hunks are added to `unabsorbed`.
+fn edit_plan(
+    repo: &git2::Repository,
+    stack: &[(git2::Commit, owned::Diff)],
+    index: &owned::Diff,
+    hunks: Vec<PlannedHunk>,
+    unabsorbed: &mut Vec<Unabsorbed>,
+) -> Result<Vec<PlannedHunk>> {
+    let entries: Vec<_> = hunks
+        .iter()
+        .map(|hunk| plan_edit::Entry {
+            target: &stack[hunk.target].0,
+            path: &index[hunk.patch].new_path,
+            change: hunk.change.describe(),
+        })
+        .collect();
+    let leftovers: Vec<_> = unabsorbed
+        .iter()
+        .map(|hunk| {
+            (
+                hunk.path.as_slice(),
+                hunk.change.as_str(),
+                hunk.reason.to_string(),
+            )
+        })
+        .collect();
+    let targets = plan_edit::edit(repo, &entries, &leftovers)?;
+    retarget(stack, index, hunks, targets, Reason::Edited, unabsorbed)
+}
+
+/// Lets absorb.matcherCommand reassign or drop the planned `hunks`,
+/// and returns the hunks that are still to be absorbed. Dropped hunks
+/// are added to `unabsorbed`.
+fn run_matcher(
+    repo: &git2::Repository,
+    command: &str,
+    stack: &[(git2::Commit, owned::Diff)],
+    index: &owned::Diff,
+    hunks: Vec<PlannedHunk>,
+    unabsorbed: &mut Vec<Unabsorbed>,
+) -> Result<Vec<PlannedHunk>> {
+    let commits: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+    let matched: Vec<_> = hunks
+        .iter()
+        .map(|hunk| {
+            let (removed, added): (&[Vec<u8>], &[Vec<u8>]) = match &hunk.change {
+                Change::Hunk(planned) => (&planned.removed.lines, &planned.added.lines),
+                Change::Mode(_) | Change::Blob(_) | Change::Type(..) => (&[], &[]),
+            };
+            matcher::Hunk {
+                path: &index[hunk.patch].new_path,
+                header: hunk.change.describe(),
+                removed,
+                added,
+                target: stack[hunk.target].0.id(),
+            }
+        })
+        .collect();
+    let targets = matcher::run(repo, command, &commits, &matched)?;
+    retarget(stack, index, hunks, targets, Reason::Matcher, unabsorbed)
+}
+
+/// Absorbs each of the `hunks` into the commit given for it in
+/// `targets`, or leaves it in the index if None.
+fn retarget(
+    stack: &[(git2::Commit, owned::Diff)],
+    index: &owned::Diff,
+    hunks: Vec<PlannedHunk>,
+    targets: Vec<Option<git2::Oid>>,
+    reason: Reason,
+    unabsorbed: &mut Vec<Unabsorbed>,
+) -> Result<Vec<PlannedHunk>> {
+    let mut kept = Vec::with_capacity(hunks.len());
+    // the hunks of a file were planned as if every earlier one was
+    // absorbed, so the dropped ones no longer move the lines below them
+    let mut dropped_offsets: HashMap<usize, isize> = HashMap::new();
+    for (mut hunk, target) in hunks.into_iter().zip(targets) {
+        let dropped_offset = dropped_offsets.entry(hunk.patch).or_default();
+        let target = match target {
+            Some(target) => target,
+            None => {
+                if let Change::Hunk(dropped) = &hunk.change {
+                    *dropped_offset += dropped.changed_offset();
+                }
+                unabsorbed.push(Unabsorbed {
+                    path: index[hunk.patch].new_path.clone(),
+                    change: hunk.change.describe(),
+                    reason: Reason::Declined,
+                });
+                continue;
+            }
+        };
+        if let Change::Hunk(planned) = hunk.change {
+            hunk.change = Change::Hunk(planned.shift_both_blocks(-*dropped_offset));
+        }
+        if target != stack[hunk.target].0.id() {
+            hunk.target = stack
+                .iter()
+                .position(|(commit, _)| commit.id() == target)
+                .ok_or_else(|| anyhow!("{} is not in the absorb stack", target))?;
+            if stack[hunk.target].0.parent_count() > 1 {
+                return Err(Error::MergeCommitBoundary(target).into());
+            }
+            hunk.reason = reason;
+        }
+        kept.push(hunk);
+    }
+    Ok(kept)
+}
+
+/// Asks the --patch selector, if any, whether `hunk` (the `index_hunk`
+/// as it will be applied) should be absorbed into `target`, and returns
+/// the hunk to absorb, which may have been edited.
+#[allow(clippy::too_many_arguments)]
+fn select_hunk(
+    repo: &git2::Repository,
+    config: &Config,
+    quit: &mut bool,
+    path: &[u8],
+    index_hunk: &owned::Hunk,
+    hunk: owned::Hunk,
+    target: &git2::Commit,
+    reason: Reason,
+) -> Result<Option<owned::Hunk>> {
+    let selector = match config.selector {
+        Some(selector) => selector,
+        None => return Ok(Some(hunk)),
+    };
+    if *quit {
+        return Ok(None);
+    }
+    let proposal = Proposal {
+        path,
+        header: index_hunk.header(),
+        removed: &hunk.removed.lines,
+        added: &hunk.added.lines,
+        target,
+        reason: reason.to_string(),
+    };
+    match selector.select(repo, &proposal)? {
+        Selection::Absorb => Ok(Some(hunk)),
+        Selection::Skip => Ok(None),
+        Selection::Quit => {
+            *quit = true;
+            Ok(None)
+        }
+        Selection::Edit(added) => Ok(Some(owned::Hunk {
+            added: owned::Block {
+                trailing_newline: added.last().map_or(true, |line| line.ends_with(b"\n")),
+                lines: std::rc::Rc::new(added),
+                ..hunk.added
+            },
+            removed: hunk.removed,
+        })),
+    }
+}
+
+/// Diffs HEAD against the working tree, for the files that have
+/// unstaged changes but nothing staged, whose index entries can follow
+/// HEAD once the changes are absorbed.
+fn unstaged_diff<'r>(
+    repo: &'r git2::Repository,
+    head_tree: &git2::Tree,
+    diff_options: Option<&mut git2::DiffOptions>,
+) -> Result<git2::Diff<'r>> {
+    let staged = repo.diff_tree_to_index(Some(head_tree), None, None)?;
+    let staged: HashSet<&[u8]> = staged
+        .deltas()
+        .filter_map(|delta| delta.new_file().path_bytes())
+        .collect();
+    let unstaged = repo.diff_index_to_workdir(None, None)?;
+    let mut paths = Vec::new();
+    for delta in unstaged.deltas() {
+        let path = match delta.new_file().path_bytes() {
+            Some(path) => path,
+            None => continue,
+        };
+        if staged.contains(path) {
+            warn!(
+                path = %String::from_utf8_lossy(path),
+                "Not absorbing the unstaged changes of a file with staged changes",
+            );
+        } else {
+            paths.push(path.to_vec());
+        }
+    }
+
+    let mut own_options = git2::DiffOptions::new();
+    let diff_options = diff_options.unwrap_or(&mut own_options);
+    // with no pathspec at all, everything would be diffed
+    if paths.is_empty() {
+        return Ok(repo.diff_tree_to_tree(Some(head_tree), Some(head_tree), None)?);
+    }
+    diff_options.disable_pathspec_match(true);
+    for path in &paths {
+        diff_options.pathspec(path.as_slice());
+    }
+    Ok(repo.diff_tree_to_workdir(Some(head_tree), Some(diff_options))?)
+}
+
+/// Whether there is nothing to absorb in the index, or in the patch,
+/// commit or working tree if one of those is absorbed instead.
+fn nothing_to_absorb(repo: &git2::Repository, config: &Config) -> Result<bool> {
+    if config.unstaged {
+        let head_tree = repo.head()?.peel_to_tree()?;
+        return Ok(unstaged_diff(repo, &head_tree, None)?.deltas().len() == 0);
+    }
+    if let Some(from) = config.from {
+        let commit = split_commit(repo, from)?;
+        let diff = repo.diff_tree_to_tree(
+            Some(&commit.parent(0)?.tree()?),
+            Some(&commit.tree()?),
+            None,
+        )?;
+        return Ok(diff.deltas().len() == 0);
+    }
+    match config.patch {
+        Some(patch) => Ok(git2::Diff::from_buffer(patch)?.deltas().len() == 0),
+        None => nothing_left_in_index(repo),
+    }
+}
+
+fn nothing_left_in_index(repo: &git2::Repository) -> Result<bool> {
+    let stats = index_stats(repo)?;
+    let nothing = stats.files_changed() == 0 && stats.insertions() == 0 && stats.deletions() == 0;
+    Ok(nothing)
+}
+
+fn index_stats(repo: &git2::Repository) -> Result<git2::DiffStats> {
+    let head = repo.head()?.peel_to_tree()?;
+    let diff = repo.diff_tree_to_index(Some(&head), Some(&repo.index()?), None)?;
+    let stats = diff.stats()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    struct Context {
+        repo: git2::Repository,
+        dir: tempfile::TempDir,
+    }
+
+    impl Context {
+        fn join(&self, p: &Path) -> PathBuf {
+            self.dir.path().join(p)
+        }
+    }
+
+    fn default_config() -> Config<'static> {
+        Config {
+            dry_run: false,
+            force: false,
+            force_in_progress: false,
+            base: None,
+            fork_point: false,
+            not: &[],
+            and_rebase: false,
+            whole_file: false,
+            one_fixup_per_commit: false,
+            no_gpg_sign: false,
+            max_stack: None,
+            target: None,
+            squash: false,
+            amend: false,
+            edit: false,
+            explain: false,
+            porcelain: false,
+            exit_code: false,
+            show_diff: false,
+            format_patch: None,
+            patch: None,
+            from: None,
+            unstaged: false,
+            include_untracked: false,
+            strategy: None,
+            commit_grep: None,
+            interleave_hunks: false,
+            fuzz: None,
+            ignore_space_change: false,
+            ignore_all_space: false,
+            skip_whitespace_only: false,
+            fixup_author_is_target_author: None,
+            committer_date_is_author_date: false,
+            reset_author_date: false,
+            cross_merges: false,
+            update_refs: false,
+            quiet: false,
+            run_hooks: None,
+            rebase_non_interactive: false,
+            and_push: false,
+            autosquash: None,
+            autostash: None,
+            one_reflog_entry: false,
+            verify_plan: false,
+            confirm: false,
+            signoff: false,
+            edit_plan: false,
+            selector: None,
+            ask: None,
+            reporter: None,
+            interrupt: None,
+        }
+    }
+
+    /// Prepare a fresh git repository with an initial commit and a file.
+    fn prepare_repo() -> (Context, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
 
         let path = PathBuf::from("test-file.txt");
         std::fs::write(
@@ -536,237 +3224,2814 @@ lines
             .unwrap();
         }
 
-        (Context { repo, dir }, path)
+        (Context { repo, dir }, path)
+    }
+
+    /// Stage the changes made to `path`.
+    fn add<'r>(repo: &'r git2::Repository, path: &Path) -> git2::Tree<'r> {
+        let mut index = repo.index().unwrap();
+        index.add_path(path).unwrap();
+        index.write().unwrap();
+
+        let tree_id = index.write_tree_to(repo).unwrap();
+        repo.find_tree(tree_id).unwrap()
+    }
+
+    /// Commit everything in the index on top of HEAD.
+    fn commit_staged(repo: &git2::Repository, message: &str) -> git2::Oid {
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = repo
+            .signature()
+            .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))
+            .unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &[&head],
+        )
+        .unwrap()
+    }
+
+    /// Prepare a repo like `prepare_repo`, and a linked worktree of it
+    /// on a new branch with a commit of its own, and stage a change next
+    /// to each of that commit's two hunks.
+    fn prepare_worktree() -> (Context, git2::Repository) {
+        let (ctx, file_path) = prepare_repo();
+        let worktree = ctx
+            .repo
+            .worktree("wt", &ctx.join(Path::new("wt")), None)
+            .unwrap();
+        let worktree = git2::Repository::open_from_worktree(&worktree).unwrap();
+
+        let path = worktree.workdir().unwrap().join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("wt_top\n{contents}wt_bottom\n")).unwrap();
+        add(&worktree, &file_path);
+        commit_staged(&worktree, "Worktree commit");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}new_line2\n")).unwrap();
+        add(&worktree, &file_path);
+
+        (ctx, worktree)
+    }
+
+    /// Prepare an empty repo, and stage some changes.
+    fn prepare_and_stage() -> Context {
+        let (ctx, file_path) = prepare_repo();
+
+        // add some lines to our file
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let modifications = format!("new_line1\n{contents}\nnew_line2");
+        std::fs::write(&path, &modifications).unwrap();
+
+        // stage it
+        add(&ctx.repo, &file_path);
+
+        ctx
+    }
+
+    #[test]
+    fn default_base_config() {
+        let ctx = prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        // a base of HEAD leaves nothing to fix up
+        let mut config = ctx.repo.config().unwrap();
+        config
+            .set_str(config::DEFAULT_BASE_CONFIG_NAME, "HEAD")
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head.id()
+        );
+
+        // one that doesn't resolve is ignored
+        config
+            .set_str(config::DEFAULT_BASE_CONFIG_NAME, "origin/main")
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn auto_extend_stack_config() {
+        let (ctx, file_path) = prepare_repo();
+        for message in ["other", "another"] {
+            std::fs::write(ctx.join(Path::new("other.txt")), message).unwrap();
+            add(&ctx.repo, Path::new("other.txt"));
+            commit_staged(&ctx.repo, message);
+        }
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        let config = Config {
+            max_stack: Some(1),
+            ..default_config()
+        };
+
+        let planned = plan(&ctx.repo, &config).unwrap();
+        assert!(planned.hunks.is_empty());
+        drop(planned);
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_EXTEND_STACK_CONFIG_NAME, true)
+            .unwrap();
+        let plan = plan(&ctx.repo, &config).unwrap();
+        assert_eq!(plan.stack.len(), 3);
+        plan.apply().unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn multiple_fixups_per_commit() {
+        let ctx = prepare_and_stage();
+
+        // run 'git-absorb'
+        let config = default_config();
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 3);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    /// Answers --patch prompts from a script.
+    struct Scripted(std::cell::RefCell<Vec<Selection>>);
+
+    impl Selector for Scripted {
+        fn select(&self, _: &git2::Repository, _: &Proposal) -> std::io::Result<Selection> {
+            Ok(self.0.borrow_mut().remove(0))
+        }
+    }
+
+    #[test]
+    fn returns_the_summary_for_the_caller_to_print() {
+        let ctx = prepare_and_stage();
+        let outcome = run_with_repo(&default_config(), &ctx.repo).unwrap();
+        let summary = outcome.summary().unwrap();
+        assert!(summary.starts_with("Absorbed 2 hunks into 1 commit:\n"));
+        assert!(summary.contains(" Initial commit. (2 hunks, 2 fixups)\n"));
+        assert!(
+            summary.ends_with("Run `git rebase -i --autosquash --root` to squash the fixups.\n")
+        );
+
+        let ctx = prepare_and_stage();
+        let config = Config {
+            quiet: true,
+            ..default_config()
+        };
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(outcome.summary(), None);
+    }
+
+    #[test]
+    fn patch_absorbs_edited_hunks() {
+        let ctx = prepare_and_stage();
+        let selector = Scripted(std::cell::RefCell::new(vec![
+            Selection::Edit(vec![b"edited1\n".to_vec(), b"edited2\n".to_vec()]),
+            Selection::Absorb,
+        ]));
+        let config = Config {
+            one_fixup_per_commit: true,
+            selector: Some(&selector),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+        let blob = head
+            .tree()
+            .unwrap()
+            .get_name("test-file.txt")
+            .unwrap()
+            .to_object(&ctx.repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(
+            blob.content(),
+            b"edited1\nedited2\n\nline\nline\n\nmore\nlines\n\nnew_line2"
+        );
+        // the index still has what was staged
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn edited_plan_drops_hunks() {
+        let ctx = prepare_and_stage();
+        let path = ctx.join(Path::new("test-file.txt"));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, Path::new("test-file.txt"));
+        // planned as: new_line1, more edited, new_line2
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..default_config()
+        };
+        let mut planned = plan(&ctx.repo, &config).unwrap();
+        assert_eq!(planned.hunks.len(), 3);
+
+        let hunks = std::mem::take(&mut planned.hunks);
+        planned.hunks = retarget(
+            &planned.stack,
+            &planned.index,
+            hunks,
+            vec![None, Some(initial), Some(initial)],
+            Reason::Edited,
+            &mut planned.unabsorbed,
+        )
+        .unwrap();
+        assert_eq!(planned.leftovers().len(), 1);
+        planned.apply().unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let blob = head
+            .tree()
+            .unwrap()
+            .get_name("test-file.txt")
+            .unwrap()
+            .to_object(&ctx.repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(
+            blob.content(),
+            b"\nline\nline\n\nmore edited\nlines\n\nnew_line2"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn matcher_command_drops_hunks() {
+        let ctx = prepare_and_stage();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str(
+                config::MATCHER_COMMAND_CONFIG_NAME,
+                "cat > matcher-input.json; \
+                 echo '{\"assignments\": [{\"hunk\": 0, \"commit\": null}]}'",
+            )
+            .unwrap();
+        let planned = plan(&ctx.repo, &default_config()).unwrap();
+        let leftovers = planned.leftovers();
+        assert_eq!(leftovers.len(), 1);
+        assert_eq!(leftovers[0].change, "-0,0 +1,1");
+        assert_eq!(planned.assignments().len(), 1);
+        drop(planned);
+
+        let input: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(ctx.join(Path::new("matcher-input.json"))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(input["hunks"].as_array().unwrap().len(), 2);
+        assert_eq!(input["hunks"][0]["added"][0], "new_line1\n");
+        assert_eq!(input["commits"][0]["summary"], "Initial commit.");
+    }
+
+    #[test]
+    fn patch_leaves_declined_hunks() {
+        let ctx = prepare_and_stage();
+        let selector = Scripted(std::cell::RefCell::new(vec![Selection::Quit]));
+        let config = Config {
+            selector: Some(&selector),
+            ..default_config()
+        };
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+
+        assert!(matches!(outcome.absorbed, Absorbed::None));
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Initial commit."));
+        assert!(selector.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn one_fixup_per_commit() {
+        let ctx = prepare_and_stage();
+
+        // run 'git-absorb'
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    fn autostage_common(ctx: &Context, file_path: &Path) -> (PathBuf, PathBuf) {
+        // 1 modification w/o staging
+        let path = ctx.join(file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let modifications = format!("{contents}\nnew_line2");
+        std::fs::write(&path, &modifications).unwrap();
+
+        // 1 extra file
+        let fp2 = PathBuf::from("unrel.txt");
+        std::fs::write(ctx.join(&fp2), "foo").unwrap();
+
+        (path, fp2)
+    }
+
+    #[test]
+    fn autostage_if_index_was_empty() {
+        let (ctx, file_path) = prepare_repo();
+
+        // requires enabled config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+
+        autostage_common(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let config = default_config();
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn autostage_only_matches_pathspec() {
+        let (ctx, file_path) = prepare_repo();
+        let mut config = ctx.repo.config().unwrap();
+        config
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+        config
+            .set_str(config::AUTO_STAGE_PATHSPEC_CONFIG_NAME, "*.md")
+            .unwrap();
+
+        autostage_common(&ctx, &file_path);
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        // the change to test-file.txt was never staged
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn autostage_leaves_out_untracked_files() {
+        let (ctx, file_path) = prepare_repo();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+        autostage_common(&ctx, &file_path);
+
+        let leftovers = plan(&ctx.repo, &default_config()).unwrap().leftovers();
+        assert!(leftovers.is_empty());
+
+        // as intent-to-add, they are left over like any other new file
+        let config = Config {
+            include_untracked: true,
+            ..default_config()
+        };
+        let planned = plan(&ctx.repo, &config).unwrap();
+        let leftovers = planned.leftovers();
+        assert_eq!(leftovers.len(), 1);
+        assert_eq!(leftovers[0].path, b"unrel.txt");
+        planned.apply().unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+        let index = ctx.repo.index().unwrap();
+        assert!(index.get_path(Path::new("unrel.txt"), 0).is_none());
+    }
+
+    #[test]
+    fn autostage_keeps_leftovers_staged() {
+        let (ctx, file_path) = prepare_repo();
+        let mut config = ctx.repo.config().unwrap();
+        config
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+        config
+            .set_bool(config::KEEP_AUTO_STAGED_LEFTOVERS_CONFIG_NAME, true)
+            .unwrap();
+        config
+            .set_str(config::EXCLUDE_COMMITS_CONFIG_NAME, "^Add unrel")
+            .unwrap();
+        let (_, unrelated) = autostage_common(&ctx, &file_path);
+        add(&ctx.repo, &unrelated);
+        commit_staged(&ctx.repo, "Add unrel.txt.");
+        // a leftover, since its commit is excluded
+        std::fs::write(ctx.join(&unrelated), "bar").unwrap();
+
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+        assert_eq!(index_stats(&ctx.repo).unwrap().files_changed(), 1);
+    }
+
+    #[test]
+    fn do_not_autostage_if_index_was_not_empty() {
+        let (ctx, file_path) = prepare_repo();
+
+        // enable config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+
+        let (_, fp2) = autostage_common(&ctx, &file_path);
+        // we stage the extra file - should stay in index
+        add(&ctx.repo, &fp2);
+
+        // run 'git-absorb'
+        let config = default_config();
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        assert_eq!(index_stats(&ctx.repo).unwrap().files_changed(), 1);
+    }
+
+    #[test]
+    fn do_not_autostage_if_not_enabled_by_config_var() {
+        let (ctx, file_path) = prepare_repo();
+
+        // disable config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, false)
+            .unwrap();
+
+        autostage_common(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let config = default_config();
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    /// Answers yes/no questions from a script.
+    struct Answers(std::cell::RefCell<Vec<bool>>);
+
+    impl Ask for Answers {
+        fn ask(&self, _: &str) -> std::io::Result<bool> {
+            Ok(self.0.borrow_mut().remove(0))
+        }
+
+        fn ask_with_plan(&self, plan: &[String], question: &str) -> std::io::Result<bool> {
+            assert!(plan.iter().all(|line| line.starts_with("test-file.txt ")));
+            self.ask(question)
+        }
+    }
+
+    #[test]
+    fn autostage_asks_without_config_var() {
+        let (ctx, file_path) = prepare_repo();
+        autostage_common(&ctx, &file_path);
+
+        // never asked in a dry run or for porcelain output
+        let answers = Answers(std::cell::RefCell::new(Vec::new()));
+        for config in [
+            Config {
+                dry_run: true,
+                ask: Some(&answers),
+                ..default_config()
+            },
+            Config {
+                porcelain: true,
+                ask: Some(&answers),
+                ..default_config()
+            },
+        ] {
+            run_with_repo(&config, &ctx.repo).unwrap();
+        }
+
+        let answers = Answers(std::cell::RefCell::new(vec![false]));
+        let config = Config {
+            ask: Some(&answers),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(answers.0.borrow().is_empty());
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        let answers = Answers(std::cell::RefCell::new(vec![true]));
+        let config = Config {
+            ask: Some(&answers),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(answers.0.borrow().is_empty());
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+    }
+
+    #[test]
+    fn confirm_asks_before_committing() {
+        let ctx = prepare_and_stage();
+
+        // nobody to ask is an error, rather than a silent no
+        let config = Config {
+            confirm: true,
+            ..default_config()
+        };
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::NobodyToConfirm)
+        ));
+
+        let answers = Answers(std::cell::RefCell::new(vec![false]));
+        let config = Config {
+            confirm: true,
+            ask: Some(&answers),
+            ..default_config()
+        };
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::NotConfirmed)
+        ));
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        let answers = Answers(std::cell::RefCell::new(vec![true]));
+        let config = Config {
+            confirm: true,
+            ask: Some(&answers),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(answers.0.borrow().is_empty());
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn fixup_message_keeps_conventional_header() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "feat(parser): edit more");
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more edited", "more edited again")).unwrap();
+        add(&ctx.repo, &file_path);
+
+        let mut config = ctx.repo.config().unwrap();
+        config
+            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, true)
+            .unwrap();
+        config
+            .set_bool(config::CONVENTIONAL_COMMITS_CONFIG_NAME, true)
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! feat(parser): edit more"));
+    }
+
+    #[test]
+    fn fixup_message_gets_signoff_and_trailers() {
+        let ctx = prepare_and_stage();
+
+        let mut config = ctx.repo.config().unwrap();
+        config
+            .set_multivar(config::TRAILERS_CONFIG_NAME, "^$", "Co-authored-by:A <a@b>")
+            .unwrap();
+        let config = Config {
+            signoff: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let signature = ctx
+            .repo
+            .signature()
+            .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))
+            .unwrap();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some(
+                format!(
+                    "fixup! Initial commit.\n\nCo-authored-by: A <a@b>\nSigned-off-by: {}\n",
+                    signature
+                )
+                .as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn fixup_message_always_commit_sha_if_configured() {
+        let ctx = prepare_and_stage();
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, true)
+            .unwrap();
+
+        // run 'git-absorb'
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(oids.len(), 2);
+
+        let commit = ctx.repo.find_commit(oids[0]).unwrap();
+        let actual_msg = commit.summary().unwrap();
+        let expected_msg = format!("fixup! {}", oids[1]);
+        assert_eq!(actual_msg, expected_msg);
+    }
+
+    #[test]
+    fn absorbs_mode_change() {
+        let (ctx, file_path) = prepare_repo();
+
+        // stage `chmod +x` without touching the file contents
+        {
+            let mut index = ctx.repo.index().unwrap();
+            let mut entry = index.get_path(&file_path, 0).unwrap();
+            entry.mode = u32::from(git2::FileMode::BlobExecutable);
+            index.add(&entry).unwrap();
+            index.write().unwrap();
+        }
+
+        // run 'git-absorb'
+        let config = default_config();
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        let entry = head_tree.get_path(&file_path).unwrap();
+        assert_eq!(entry.filemode(), i32::from(git2::FileMode::BlobExecutable));
+    }
+
+    #[test]
+    fn follows_moved_directories() {
+        let (ctx, _) = prepare_repo();
+        let (old, new) = (Path::new("old/a.txt"), Path::new("new/a.txt"));
+        std::fs::create_dir(ctx.join(Path::new("old"))).unwrap();
+        std::fs::write(ctx.join(old), "one\ntwo\nthree\nfour\n").unwrap();
+        add(&ctx.repo, old);
+        commit_staged(&ctx.repo, "add a");
+        std::fs::create_dir(ctx.join(Path::new("new"))).unwrap();
+        std::fs::rename(ctx.join(old), ctx.join(new)).unwrap();
+        {
+            let mut index = ctx.repo.index().unwrap();
+            index.remove_path(old).unwrap();
+            index.write().unwrap();
+        }
+        add(&ctx.repo, new);
+        commit_staged(&ctx.repo, "move to new");
+
+        std::fs::write(ctx.join(new), "one\ntwo\nthree edited\nfour\n").unwrap();
+        add(&ctx.repo, new);
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! add a"));
+    }
+
+    #[test]
+    fn context_lines_config() {
+        let (ctx, _) = prepare_repo();
+        let path = Path::new("context.txt");
+        let lines = |changed: &[(usize, &str)]| {
+            let mut lines: Vec<String> = (1..=10).map(|i| i.to_string()).collect();
+            for (i, line) in changed {
+                lines[i - 1] = line.to_string();
+            }
+            lines.join("\n") + "\n"
+        };
+        std::fs::write(ctx.join(path), lines(&[])).unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "add context");
+        std::fs::write(ctx.join(path), lines(&[(2, "two")])).unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "change two");
+
+        // commutes past "change two", which changed a line three lines up
+        std::fs::write(ctx.join(path), lines(&[(2, "two"), (5, "five")])).unwrap();
+        add(&ctx.repo, path);
+        let set_context = |lines| {
+            ctx.repo
+                .config()
+                .unwrap()
+                .set_i64(config::CONTEXT_LINES_CONFIG_NAME, lines)
+                .unwrap();
+        };
+
+        set_context(2);
+        {
+            let planned = plan(&ctx.repo, &default_config()).unwrap();
+            assert_eq!(planned.hunks.len(), 1);
+            assert!(matches!(planned.hunks[0].reason, Reason::AddedFile));
+        }
+        set_context(3);
+        let planned = plan(&ctx.repo, &default_config()).unwrap();
+        assert!(planned.hunks.is_empty());
+        assert_eq!(planned.unabsorbed.len(), 1);
+        assert!(matches!(
+            planned.unabsorbed[0].reason,
+            Reason::ContextMismatch
+        ));
+    }
+
+    #[test]
+    fn verify_plan_accepts_fixups() {
+        let ctx = prepare_and_stage();
+        let config = Config {
+            verify_plan: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn verify_plan_detects_conflicts() {
+        let (ctx, path) = prepare_repo();
+        std::fs::write(ctx.join(&path), "changed\n").unwrap();
+        add(&ctx.repo, &path);
+        commit_staged(&ctx.repo, "change");
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let stack = [&head];
+
+        // a fixup that changes lines the commit didn't have
+        let tree = |content: &str| {
+            std::fs::write(ctx.join(&path), content).unwrap();
+            add(&ctx.repo, &path)
+        };
+        let fixups = [squash::Fixup {
+            target: head.id(),
+            parent_tree: tree("other\n"),
+            tree: tree("fixed\n"),
+        }];
+        match squash::simulate(&ctx.repo, &stack, &fixups).unwrap() {
+            Some(squash::Squashed::Conflict { commit, fixup, .. }) => {
+                assert_eq!(commit, head.id());
+                assert!(fixup);
+            }
+            _ => panic!("expected a conflict"),
+        }
+
+        // the fixup that absorbing would make
+        let fixups = [squash::Fixup {
+            target: head.id(),
+            parent_tree: head.tree().unwrap(),
+            tree: tree("fixed\n"),
+        }];
+        match squash::simulate(&ctx.repo, &stack, &fixups).unwrap() {
+            Some(squash::Squashed::Tree(tree)) => assert_eq!(tree, fixups[0].tree.id()),
+            _ => panic!("expected a tree"),
+        }
+    }
+
+    #[test]
+    fn predicts_fixup_conflicts() {
+        let (ctx, path) = prepare_repo();
+        std::fs::write(ctx.join(&path), "changed\n").unwrap();
+        add(&ctx.repo, &path);
+        commit_staged(&ctx.repo, "change");
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let tree = |content: &str| {
+            std::fs::write(ctx.join(&path), content).unwrap();
+            add(&ctx.repo, &path)
+        };
+        let fixups = [
+            squash::Fixup {
+                target: head.id(),
+                parent_tree: head.tree().unwrap(),
+                tree: tree("fixed\n"),
+            },
+            // changes lines that the target didn't have
+            squash::Fixup {
+                target: head.id(),
+                parent_tree: tree("other\n"),
+                tree: tree("fixed again\n"),
+            },
+        ];
+        let conflicts = squash::conflicts(&ctx.repo, &fixups).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, head.id());
+        assert_eq!(conflicts[0].1, [path.to_str().unwrap().as_bytes()]);
+
+        // a fixup that changes what an earlier fixup for the same target
+        // did applies on top of that one
+        let fixups = [
+            squash::Fixup {
+                target: head.id(),
+                parent_tree: head.tree().unwrap(),
+                tree: tree("fixed\n"),
+            },
+            squash::Fixup {
+                target: head.id(),
+                parent_tree: tree("fixed\n"),
+                tree: tree("fixed again\n"),
+            },
+        ];
+        assert!(squash::conflicts(&ctx.repo, &fixups).unwrap().is_empty());
+    }
+
+    #[test]
+    fn splits_hunk_across_commits() {
+        let (ctx, _) = prepare_repo();
+        let path = Path::new("split.txt");
+        let lines = |changed: &[(usize, &str)]| {
+            let mut lines: Vec<String> = (1..=8).map(|i| i.to_string()).collect();
+            for (i, line) in changed {
+                lines[i - 1] = line.to_string();
+            }
+            lines.join("\n") + "\n"
+        };
+        std::fs::write(ctx.join(path), lines(&[])).unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "add split");
+        std::fs::write(ctx.join(path), lines(&[(3, "three")])).unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "change three");
+        std::fs::write(ctx.join(path), lines(&[(3, "three"), (5, "five")])).unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "change five");
+
+        // one hunk, whose first line only conflicts with "change three"
+        std::fs::write(
+            ctx.join(path),
+            lines(&[(3, "THREE"), (4, "FOUR"), (5, "FIVE"), (6, "SIX")]),
+        )
+        .unwrap();
+        add(&ctx.repo, path);
+        {
+            let planned = plan(&ctx.repo, &default_config()).unwrap();
+            assert_eq!(planned.hunks.len(), 1);
+        }
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::SPLIT_HUNKS_CONFIG_NAME, true)
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let summaries: Vec<String> = revwalk
+            .take(2)
+            .map(|oid| {
+                let commit = ctx.repo.find_commit(oid.unwrap()).unwrap();
+                commit.summary().unwrap().to_owned()
+            })
+            .collect();
+        // the fixup of the older commit comes first
+        assert_eq!(summaries, ["fixup! change five", "fixup! change three"]);
+        let older_fixup = ctx
+            .repo
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .parent(0)
+            .unwrap();
+        let blob = older_fixup.tree().unwrap().get_path(path).unwrap().id();
+        assert_eq!(
+            ctx.repo.find_blob(blob).unwrap().content(),
+            lines(&[(3, "THREE"), (5, "five")]).as_bytes()
+        );
+    }
+
+    #[test]
+    fn absorbs_into_copying_commit_with_find_copies() {
+        let (ctx, _) = prepare_repo();
+        let (original, copy) = (Path::new("a.txt"), Path::new("b.txt"));
+        std::fs::write(ctx.join(original), "one\ntwo\nthree\nfour\nfive\n").unwrap();
+        add(&ctx.repo, original);
+        commit_staged(&ctx.repo, "add a");
+        // the copy's source is changed as well, so it is the old path
+        // of two patches
+        std::fs::copy(ctx.join(original), ctx.join(copy)).unwrap();
+        std::fs::write(ctx.join(original), "one\ntwo\nthree\nfour\nfive\nsix\n").unwrap();
+        add(&ctx.repo, original);
+        add(&ctx.repo, copy);
+        commit_staged(&ctx.repo, "copy a to b");
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::FIND_COPIES_CONFIG_NAME, true)
+            .unwrap();
+
+        std::fs::write(ctx.join(copy), "one\ntwo edited\nthree\nfour\nfive\n").unwrap();
+        add(&ctx.repo, copy);
+        {
+            let planned = plan(&ctx.repo, &default_config()).unwrap();
+            assert_eq!(planned.hunks.len(), 1);
+            assert!(matches!(planned.hunks[0].reason, Reason::CopiedFile));
+        }
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! copy a to b"));
+    }
+
+    #[test]
+    fn absorbs_type_change() {
+        let (ctx, _) = prepare_repo();
+        let path = Path::new("link");
+        std::fs::write(ctx.join(path), "test-file.txt\n").unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "add link");
+
+        // stage the file as a symlink instead
+        {
+            let mut index = ctx.repo.index().unwrap();
+            let mut entry = index.get_path(path, 0).unwrap();
+            entry.mode = u32::from(git2::FileMode::Link);
+            index.add_frombuffer(&entry, b"test-file.txt").unwrap();
+            index.write().unwrap();
+        }
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! add link"));
+        let entry = head.tree().unwrap().get_path(path).unwrap();
+        assert_eq!(entry.filemode(), i32::from(git2::FileMode::Link));
+        let blob = ctx.repo.find_blob(entry.id()).unwrap();
+        assert_eq!(blob.content(), b"test-file.txt");
+    }
+
+    #[test]
+    fn max_file_size_config() {
+        let ctx = prepare_and_stage();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_i64(config::MAX_FILE_SIZE_CONFIG_NAME, 10)
+            .unwrap();
+
+        let planned = plan(&ctx.repo, &default_config()).unwrap();
+        assert!(planned.hunks.is_empty());
+        assert_eq!(planned.unabsorbed.len(), 1);
+        assert!(matches!(planned.unabsorbed[0].reason, Reason::TooLarge));
+    }
+
+    #[test]
+    fn binary_files_are_replaced_only_if_configured() {
+        let (ctx, _) = prepare_repo();
+        let path = Path::new("image.bin");
+        std::fs::write(ctx.join(path), b"\0binary\n").unwrap();
+        add(&ctx.repo, path);
+        commit_staged(&ctx.repo, "add image");
+        std::fs::write(ctx.join(path), b"\0changed\n").unwrap();
+        add(&ctx.repo, path);
+
+        let planned = plan(&ctx.repo, &default_config()).unwrap();
+        assert!(planned.hunks.is_empty());
+        assert!(matches!(planned.unabsorbed[0].reason, Reason::Binary));
+        drop(planned);
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::REPLACE_BINARY_FILES_CONFIG_NAME, true)
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! add image"));
+        let entry = head.tree().unwrap().get_path(path).unwrap();
+        let blob = ctx.repo.find_blob(entry.id()).unwrap();
+        assert_eq!(blob.content(), b"\0changed\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signs_fixups_if_configured() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ctx = prepare_and_stage();
+
+        // a stand-in for gpg that always produces the same signature
+        let fake_gpg = ctx.join(Path::new("fake-gpg.sh"));
+        std::fs::write(
+            &fake_gpg,
+            "#!/bin/sh\ncat >/dev/null\necho '-----BEGIN PGP SIGNATURE-----'\n\
+             echo 'fake'\necho '-----END PGP SIGNATURE-----'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&fake_gpg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_bool("commit.gpgSign", true).unwrap();
+        repo_config
+            .set_str("gpg.program", fake_gpg.to_str().unwrap())
+            .unwrap();
+
+        // run 'git-absorb'
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert!(head.summary().unwrap().starts_with("fixup! "));
+        let (signature, _) = ctx.repo.extract_signature(&head.id(), None).unwrap();
+        assert!(signature.as_str().unwrap().contains("fake"));
+    }
+
+    #[test]
+    fn autostage_preserves_skip_worktree() {
+        let (ctx, file_path) = prepare_repo();
+
+        // commit a second file and mark it skip-worktree
+        let sparse_path = PathBuf::from("sparse.txt");
+        std::fs::write(ctx.join(&sparse_path), "sparse\n").unwrap();
+        add(&ctx.repo, &sparse_path);
+        commit_staged(&ctx.repo, "Add sparse file.");
+        {
+            let mut index = ctx.repo.index().unwrap();
+            let mut entry = index.get_path(&sparse_path, 0).unwrap();
+            entry.flags_extended |= git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+            index.add(&entry).unwrap();
+            index.write().unwrap();
+        }
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+        autostage_common(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let config = default_config();
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let index = ctx.repo.index().unwrap();
+        let entry = index.get_path(&sparse_path, 0).unwrap();
+        assert!(entry.flags_extended & git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits() != 0);
+    }
+
+    #[test]
+    fn max_stack_flag_overrides_config() {
+        let (ctx, _) = prepare_repo();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 2)
+            .unwrap();
+        let config = default_config();
+        assert_eq!(
+            config::unify(&config, &ctx.repo).unwrap().max_stack,
+            Some(2)
+        );
+
+        let config = Config {
+            max_stack: Some(5),
+            ..default_config()
+        };
+        assert_eq!(
+            config::unify(&config, &ctx.repo).unwrap().max_stack,
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn target_forces_destination() {
+        let (ctx, file_path) = prepare_repo();
+        let second_path = PathBuf::from("second.txt");
+        std::fs::write(ctx.join(&second_path), "second\n").unwrap();
+        add(&ctx.repo, &second_path);
+        commit_staged(&ctx.repo, "Add second file.");
+
+        // this change would normally be absorbed into the initial commit
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+        let config = Config {
+            target: Some("HEAD"),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Add second file."));
+    }
+
+    #[test]
+    fn blame_strategy_finds_last_commit_to_touch_lines() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Edit more.");
+        std::fs::write(ctx.join(Path::new("second.txt")), "second\n").unwrap();
+        add(&ctx.repo, Path::new("second.txt"));
+        commit_staged(&ctx.repo, "Add second file.");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more edited", "more edited again")).unwrap();
+        add(&ctx.repo, &file_path);
+        let config = Config {
+            strategy: Some(Strategy::Blame),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Edit more."));
+    }
+
+    #[test]
+    fn whole_file_paths_config() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Edit more.");
+
+        // this change would normally be absorbed into the initial commit
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str(config::WHOLE_FILE_PATHS_CONFIG_NAME, "*.txt")
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Edit more."));
+    }
+
+    #[test]
+    fn fixups_of_fixups_name_the_original_commit() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "fixup! Initial commit.");
+
+        // this change belongs in the existing fixup
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more edited", "more edited again")).unwrap();
+        add(&ctx.repo, &file_path);
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+        let parent = head.parent(0).unwrap();
+        assert_eq!(parent.summary(), Some("fixup! Initial commit."));
+    }
+
+    #[test]
+    fn amends_of_amends_keep_the_amended_message() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "amend! Initial commit.\n\nBetter message.\n");
+
+        // this change belongs in the existing amend! commit
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more edited", "more edited again")).unwrap();
+        add(&ctx.repo, &file_path);
+        let config = Config {
+            amend: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some("amend! Initial commit.\n\nBetter message.\n")
+        );
+    }
+
+    #[test]
+    fn path_target_config() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Edit more.");
+
+        // this change would normally be absorbed into the initial commit
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str(config::PATH_TARGET_CONFIG_NAME, "*.txt ^Edit")
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Edit more."));
+    }
+
+    #[test]
+    fn excluded_commits_are_not_targets() {
+        let ctx = prepare_and_stage();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str(config::EXCLUDE_COMMITS_CONFIG_NAME, "^Initial")
+            .unwrap();
+        let outcome = run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::None);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Initial commit."));
+    }
+
+    #[test]
+    fn commit_grep_restricts_targets() {
+        let ctx = prepare_and_stage();
+        let config = Config {
+            commit_grep: Some("^Second"),
+            ..default_config()
+        };
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::None);
+
+        let config = Config {
+            commit_grep: Some("^Initial"),
+            ..default_config()
+        };
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::All);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn diff_algorithm_prefers_absorb_config() {
+        let ctx = prepare_and_stage();
+        let mut repo_config = ctx.repo.config().unwrap();
+        assert_eq!(config::diff_algorithm(&ctx.repo), None);
+
+        repo_config
+            .set_str(config::GIT_DIFF_ALGORITHM_CONFIG_NAME, "histogram")
+            .unwrap();
+        assert_eq!(
+            config::diff_algorithm(&ctx.repo).as_deref(),
+            Some("histogram")
+        );
+
+        repo_config
+            .set_str(config::DIFF_ALGORITHM_CONFIG_NAME, "patience")
+            .unwrap();
+        assert_eq!(
+            config::diff_algorithm(&ctx.repo).as_deref(),
+            Some("patience")
+        );
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn reanchor_skips_missing_paths() {
+        let (ctx, _) = prepare_repo();
+        let path = Path::new("new.txt");
+        std::fs::write(ctx.join(path), "one\ntwo\n").unwrap();
+        add(&ctx.repo, path);
+        let commit = commit_staged(&ctx.repo, "Add new.txt");
+        let commit = ctx.repo.find_commit(commit).unwrap();
+        let diff = owned::Diff::new(
+            &ctx.repo
+                .diff_tree_to_tree(
+                    Some(&commit.parent(0).unwrap().tree().unwrap()),
+                    Some(&commit.tree().unwrap()),
+                    None,
+                )
+                .unwrap(),
+        )
+        .unwrap();
+        let patch = diff.by_new(b"new.txt").unwrap();
+
+        // the file isn't in the commit's parent, so the hunk can't be
+        // re-anchored there
+        let hunk = patch.hunks[0].clone();
+        assert!(reanchor(&ctx.repo, &commit, patch, &hunk, 1)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn ignore_all_space_keeps_whitespace_changes_in_the_stack() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "    more")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Reindent.");
+        let config = Config {
+            ignore_all_space: true,
+            skip_whitespace_only: true,
+            ..default_config()
+        };
+
+        // a whitespace-only change is left in the index
+        std::fs::write(&path, contents.replace("more", "  more")).unwrap();
+        add(&ctx.repo, &file_path);
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::None);
+
+        // a change to a reindented line stays with the reindent, which
+        // the fixup would conflict with otherwise
+        let changed = contents.replace("more", "    more changed");
+        std::fs::write(&path, &changed).unwrap();
+        add(&ctx.repo, &file_path);
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("user.name", "nobody").unwrap();
+        repo_config
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        let config = Config {
+            and_rebase: true,
+            rebase_non_interactive: true,
+            ..config
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        assert_eq!(ctx.repo.state(), git2::RepositoryState::Clean);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Reindent."));
+        assert_eq!(head.parent(0).unwrap().summary(), Some("Initial commit."));
+        let blob = head.tree().unwrap().get_path(&file_path).unwrap();
+        let blob = ctx.repo.find_blob(blob.id()).unwrap();
+        assert_eq!(blob.content(), changed.as_bytes());
+    }
+
+    #[test]
+    fn autostage_skips_filter_driver_files() {
+        let (ctx, file_path) = prepare_repo();
+        std::fs::write(
+            ctx.join(Path::new(".gitattributes")),
+            "test-file.txt filter=fake\n",
+        )
+        .unwrap();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("filter.fake.clean", "cat").unwrap();
+        repo_config
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Initial commit."));
+    }
+
+    #[test]
+    fn finds_tree_entries_by_bytes() {
+        let (ctx, _) = prepare_repo();
+        let blob = ctx.repo.blob(b"contents\n").unwrap();
+        let mut builder = ctx.repo.treebuilder(None).unwrap();
+        builder.insert(&b"caf\xe9.txt"[..], blob, 0o100644).unwrap();
+        let dir = builder.write().unwrap();
+        let mut builder = ctx.repo.treebuilder(None).unwrap();
+        builder.insert(&b"d\xefr"[..], dir, 0o040000).unwrap();
+        let tree = ctx.repo.find_tree(builder.write().unwrap()).unwrap();
+
+        let entry = tree_entry(&ctx.repo, &tree, b"d\xefr/caf\xe9.txt").unwrap();
+        assert_eq!(entry.map(|entry| entry.id()), Some(blob));
+        let entry = tree_entry(&ctx.repo, &tree, b"d\xefr").unwrap();
+        assert_eq!(entry.map(|entry| entry.id()), Some(dir));
+        assert!(tree_entry(&ctx.repo, &tree, b"d\xefr/missing")
+            .unwrap()
+            .is_none());
+        assert!(tree_entry(&ctx.repo, &tree, b"d\xefr/caf\xe9.txt/below")
+            .unwrap()
+            .is_none());
+    }
+
+    // macOS refuses file names that aren't UTF-8
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn absorbs_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (ctx, _) = prepare_repo();
+        let file_path = Path::new(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(ctx.join(file_path), "line\n").unwrap();
+        add(&ctx.repo, file_path);
+        commit_staged(&ctx.repo, "Add non-utf8 file.");
+        std::fs::write(ctx.join(file_path), "line changed\n").unwrap();
+        add(&ctx.repo, file_path);
+        let config = Config {
+            strategy: Some(Strategy::Blame),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Add non-utf8 file."));
+    }
+
+    #[test]
+    fn fixup_author_is_target_author() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("more", "more edited")).unwrap();
+        let tree = add(&ctx.repo, &file_path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let teammate = git2::Signature::now("Teammate", "teammate@example.com").unwrap();
+        ctx.repo
+            .commit(
+                Some("HEAD"),
+                &teammate,
+                &teammate,
+                "Edit more.",
+                &tree,
+                &[&head],
+            )
+            .unwrap();
+
+        std::fs::write(&path, contents.replace("more", "more edited again")).unwrap();
+        add(&ctx.repo, &file_path);
+        let config = Config {
+            fixup_author_is_target_author: Some(true),
+            force: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Edit more."));
+        assert_eq!(head.author().email(), Some("teammate@example.com"));
+    }
+
+    #[test]
+    fn and_rebase_preserves_committer_dates() {
+        let ctx = prepare_and_stage();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("user.name", "nobody").unwrap();
+        repo_config
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        // an author date and a committer date that are neither now, nor
+        // the same
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let authored = git2::Time::new(1_112_911_993, 0);
+        let author = git2::Signature::new("nobody", "nobody@example.com", &authored).unwrap();
+        let committer = git2::Signature::new(
+            "nobody",
+            "nobody@example.com",
+            &git2::Time::new(1_136_073_600, 0),
+        )
+        .unwrap();
+        let initial = initial
+            .amend(
+                Some("HEAD"),
+                Some(&author),
+                Some(&committer),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let config = Config {
+            and_rebase: true,
+            rebase_non_interactive: true,
+            committer_date_is_author_date: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_ne!(head.id(), initial);
+        assert_eq!(head.summary(), Some("Initial commit."));
+        assert_eq!(head.author().when(), authored);
+        assert_eq!(head.committer().when(), authored);
+    }
+
+    #[test]
+    fn and_rebase_update_refs_keeps_base_branch() {
+        let (ctx, path) = prepare_repo();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("user.name", "nobody").unwrap();
+        repo_config
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo.branch("feature", &initial, false).unwrap();
+        ctx.repo.set_head("refs/heads/feature").unwrap();
+        ctx.repo.branch("main", &initial, true).unwrap();
+        let main = ctx.repo.refname_to_id("refs/heads/main").unwrap();
+
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        std::fs::write(ctx.join(&path), format!("{contents}stacked\n")).unwrap();
+        add(&ctx.repo, &path);
+        let stacked = commit_staged(&ctx.repo, "Stacked commit");
+        ctx.repo
+            .branch("stacked", &ctx.repo.find_commit(stacked).unwrap(), false)
+            .unwrap();
+        std::fs::write(ctx.join(&path), format!("top\n{contents}stacked\n")).unwrap();
+        add(&ctx.repo, &path);
+        commit_staged(&ctx.repo, "Top commit");
+
+        // one change for the stacked branch, and one for main's commit
+        let fixed = contents.replace("more", "MORE");
+        std::fs::write(ctx.join(&path), format!("top\n{fixed}STACKED\n")).unwrap();
+        add(&ctx.repo, &path);
+        let config = Config {
+            and_rebase: true,
+            rebase_non_interactive: true,
+            update_refs: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        assert_eq!(ctx.repo.refname_to_id("refs/heads/main").unwrap(), main);
+        let stacked = ctx
+            .repo
+            .find_reference("refs/heads/stacked")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(stacked.summary(), Some("Stacked commit"));
+        assert_eq!(stacked.parent_id(0).unwrap(), main);
+        let blob = stacked.tree().unwrap().get_path(&path).unwrap();
+        let blob = ctx.repo.find_blob(blob.id()).unwrap();
+        assert_eq!(blob.content(), format!("{contents}STACKED\n").as_bytes());
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Top commit"));
+        assert_eq!(head.parent_id(0).unwrap(), stacked.id());
+    }
+
+    #[test]
+    fn and_rebase_honors_rebase_autosquash() {
+        let ctx = prepare_and_stage();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("user.name", "nobody").unwrap();
+        repo_config
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        repo_config.set_bool("rebase.autoSquash", false).unwrap();
+        let config = Config {
+            and_rebase: true,
+            rebase_non_interactive: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        // the fixups were rebased, but not squashed
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+    }
+
+    #[test]
+    fn and_push_force_pushes_to_upstream() {
+        let ctx = prepare_and_stage();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("user.name", "nobody").unwrap();
+        repo_config
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let remote = git2::Repository::init_bare(remote_dir.path()).unwrap();
+        ctx.repo
+            .remote("origin", remote_dir.path().to_str().unwrap())
+            .unwrap();
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(ctx.dir.path())
+            .args(["push", "--quiet", "-u", "origin", "HEAD"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let config = Config {
+            and_rebase: true,
+            rebase_non_interactive: true,
+            and_push: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap();
+        let branch = head.name().unwrap();
+        assert_eq!(
+            remote.refname_to_id(branch).unwrap(),
+            head.target().unwrap()
+        );
+    }
+
+    #[test]
+    fn format_patch_writes_fixups_as_patches() {
+        let ctx = prepare_and_stage();
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            format_patch: Some(dir.path()),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "0001-fixup-Initial-commit.patch",
+                "0002-fixup-Initial-commit.patch"
+            ]
+        );
+        let patch = std::fs::read_to_string(dir.path().join(&names[0])).unwrap();
+        assert!(patch.contains("Subject: [PATCH 1/2] fixup! Initial commit."));
+        assert!(patch.contains("+new_line1"));
+    }
+
+    #[test]
+    fn and_rebase_copies_notes() {
+        let ctx = prepare_and_stage();
+        let mut repo_config = ctx.repo.config().unwrap();
+        repo_config.set_str("user.name", "nobody").unwrap();
+        repo_config
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        repo_config
+            .set_str("notes.rewriteRef", "refs/notes/commits")
+            .unwrap();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = ctx.repo.signature().unwrap();
+        ctx.repo
+            .note(
+                &signature,
+                &signature,
+                None,
+                initial.id(),
+                "reviewed",
+                false,
+            )
+            .unwrap();
+        let config = Config {
+            and_rebase: true,
+            rebase_non_interactive: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        // the rebase carries the note over to the rewritten commit
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_ne!(head.id(), initial.id());
+        let note = ctx.repo.find_note(None, head.id()).unwrap();
+        assert_eq!(note.message(), Some("reviewed"));
+    }
+
+    #[test]
+    fn forbid_pushed_targets() {
+        let ctx = prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo
+            .reference("refs/remotes/origin/main", head.id(), false, "")
+            .unwrap();
+        let config = Config {
+            dry_run: true,
+            ..default_config()
+        };
+        // pushed targets only cause a warning by default
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::FORBID_PUSHED_TARGETS_CONFIG_NAME, true)
+            .unwrap();
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::PushedTarget(commit)) if commit == head.id()
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_commit_hooks_if_configured() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ctx = prepare_and_stage();
+        let hooks = ctx.join(Path::new("hooks"));
+        std::fs::create_dir(&hooks).unwrap();
+        for (name, script) in [
+            (
+                "pre-commit",
+                "git diff --cached --name-only > pre-commit.out\n",
+            ),
+            ("commit-msg", "echo 'Hooked: yes' >> \"$1\"\n"),
+        ] {
+            let path = hooks.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str("core.hooksPath", "hooks")
+            .unwrap();
+        let config = Config {
+            run_hooks: Some(true),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some("fixup! Initial commit.\nHooked: yes\n")
+        );
+        assert_eq!(
+            std::fs::read_to_string(ctx.join(Path::new("pre-commit.out"))).unwrap(),
+            "test-file.txt\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prepares_message_with_template_and_hook() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ctx = prepare_and_stage();
+        let hooks = ctx.repo.path().join("hooks");
+        std::fs::create_dir_all(&hooks).unwrap();
+        let hook = hooks.join("prepare-commit-msg");
+        std::fs::write(
+            &hook,
+            "#!/bin/sh\n\
+             { echo \"[ABC-1] $(head -n 1 \"$1\")\"; tail -n +2 \"$1\"; echo \"Source: $2\"; } > \"$1.new\"\n\
+             mv \"$1.new\" \"$1\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let template = ctx.repo.path().join("template.txt");
+        std::fs::write(&template, "# a comment\nRefs: #123\n").unwrap();
+        let mut config = ctx.repo.config().unwrap();
+        config
+            .set_str("commit.template", template.to_str().unwrap())
+            .unwrap();
+        config
+            .set_bool(config::PREPARE_MESSAGE_CONFIG_NAME, true)
+            .unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some("fixup! Initial commit.\n\nRefs: #123\nSource: template\n")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_absorb_hooks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ctx = prepare_and_stage();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let hooks = ctx.repo.path().join("hooks");
+        std::fs::create_dir_all(&hooks).unwrap();
+        for name in ["pre-absorb", "post-absorb"] {
+            let path = hooks.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\ncat > {}.out\n", name)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        let fixup = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let pre = std::fs::read_to_string(ctx.join(Path::new("pre-absorb.out"))).unwrap();
+        let post = std::fs::read_to_string(ctx.join(Path::new("post-absorb.out"))).unwrap();
+        assert!(pre.starts_with("version 1\nabsorbed "));
+        assert!(!pre.contains("fixup "));
+        assert!(post.starts_with(&pre));
+        assert!(post.ends_with(&format!("fixup {} {}\n", fixup.id(), initial.id())));
+
+        // a failing pre-absorb hook stops the absorb
+        std::fs::write(hooks.join("pre-absorb"), "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::write(ctx.join(Path::new("test-file.txt")), "changed\n").unwrap();
+        add(&ctx.repo, Path::new("test-file.txt"));
+        assert!(matches!(
+            run_with_repo(&default_config(), &ctx.repo),
+            Err(Error::HookFailed { hook, .. }) if hook == "pre-absorb"
+        ));
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            fixup.id()
+        );
+    }
+
+    #[test]
+    fn plan_then_apply() {
+        let ctx = prepare_and_stage();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        let config = default_config();
+        let plan = plan(&ctx.repo, &config).unwrap();
+        let assignments = plan.assignments();
+        assert_eq!(assignments.len(), 2);
+        assert!(assignments
+            .iter()
+            .all(|assignment| assignment.target == initial.id()));
+        assert!(plan.leftovers().is_empty());
+        assert_eq!(plan.base(), None);
+        // planning doesn't create any commits
+        assert_eq!(ctx.repo.head().unwrap().target(), Some(initial.id()));
+
+        let outcome = plan.apply().unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::All);
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 3);
+    }
+
+    #[test]
+    fn reports_progress() {
+        #[derive(Default)]
+        struct Recorder {
+            scanned: std::cell::RefCell<Vec<git2::Oid>>,
+            planned: std::cell::RefCell<Vec<Option<git2::Oid>>>,
+            committed: std::cell::RefCell<Vec<git2::Oid>>,
+        }
+        impl Reporter for Recorder {
+            fn on_commit_scanned(&self, commit: git2::Oid) {
+                self.scanned.borrow_mut().push(commit);
+            }
+            fn on_hunk_planned(&self, _: &[u8], _: &str, target: Option<git2::Oid>, _: &str) {
+                self.planned.borrow_mut().push(target);
+            }
+            fn on_fixup_committed(&self, _: Option<git2::Oid>, target: git2::Oid) {
+                self.committed.borrow_mut().push(target);
+            }
+        }
+
+        let ctx = prepare_and_stage();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let recorder = Recorder::default();
+        let config = Config {
+            reporter: Some(&recorder),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        assert_eq!(*recorder.scanned.borrow(), vec![initial.id()]);
+        assert_eq!(
+            *recorder.planned.borrow(),
+            vec![Some(initial.id()), Some(initial.id())]
+        );
+        assert_eq!(
+            *recorder.committed.borrow(),
+            vec![initial.id(), initial.id()]
+        );
+    }
+
+    #[test]
+    fn explains_each_hunk() {
+        let (ctx, path) = prepare_repo();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        let edited = contents.replacen("line\nline", "line\nedited line", 1);
+        std::fs::write(ctx.join(&path), &edited).unwrap();
+        add(&ctx.repo, &path);
+        let edit = commit_staged(&ctx.repo, "Edit a line.");
+
+        // the change to the edited line can't commute with the commit
+        // that edited it, but the other one commutes past the whole stack
+        let staged = edited
+            .replace("edited line", "edited line again")
+            .replace("more", "MORE");
+        std::fs::write(ctx.join(&path), staged).unwrap();
+        add(&ctx.repo, &path);
+
+        /// Collects the log output.
+        #[derive(Clone, Default)]
+        struct Capture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Capture::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        let base = initial.id().to_string();
+        let config = Config {
+            base: Some(&base),
+            explain: true,
+            dry_run: true,
+            ..default_config()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            run_with_repo(&config, &ctx.repo).unwrap();
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output
+            .lines()
+            .filter(|line| line.contains("absorbing"))
+            .collect();
+        assert_eq!(lines.len(), 2, "{}", output);
+        assert!(!lines[0].contains("not absorbing"));
+        assert!(lines[0].contains(&format!("commit={}", edit)));
+        assert!(lines[0].contains(&format!("reason={}", Reason::Conflict)));
+        assert!(lines[1].contains("not absorbing"));
+        assert!(lines[1].contains(&format!("reason={}", Reason::StackExhausted)));
+    }
+
+    #[test]
+    fn detached_head_fails() {
+        let ctx = prepare_and_stage();
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        ctx.repo.set_head_detached(head).unwrap();
+        assert!(matches!(
+            run_with_repo(&default_config(), &ctx.repo),
+            Err(Error::DetachedHead)
+        ));
+    }
+
+    #[test]
+    fn target_outside_stack_fails() {
+        let ctx = prepare_and_stage();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo.branch("other", &initial, false).unwrap();
+        commit_staged(&ctx.repo, "Second commit.");
+        std::fs::write(ctx.join(Path::new("test-file.txt")), "changed\n").unwrap();
+        add(&ctx.repo, Path::new("test-file.txt"));
+        let config = Config {
+            target: Some("other"),
+            ..default_config()
+        };
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::TargetNotInStack(_))
+        ));
+    }
+
+    #[test]
+    fn squash_commits() {
+        let ctx = prepare_and_stage();
+        let config = Config {
+            one_fixup_per_commit: true,
+            squash: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("squash! Initial commit.\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn squash_commits_with_edited_body() {
+        // GIT_EDITOR takes precedence over core.editor, like in git
+        if std::env::var_os("GIT_EDITOR").is_some() {
+            return;
+        }
+        let ctx = prepare_and_stage();
+        // an "editor" that appends a body to the message
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str("core.editor", "printf '\\nsquash body\\n' >>")
+            .unwrap();
+        let config = Config {
+            one_fixup_per_commit: true,
+            squash: true,
+            edit: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some("squash! Initial commit.\n\nsquash body\n")
+        );
+    }
+
+    #[test]
+    fn amend_commits() {
+        let ctx = prepare_and_stage();
+        let config = Config {
+            one_fixup_per_commit: true,
+            amend: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some("amend! Initial commit.\n\nInitial commit.\n")
+        );
+    }
+
+    #[test]
+    fn outcome_reports_partial_absorb() {
+        let (ctx, file_path) = prepare_repo();
+        // one change to the committed file, and one new file that
+        // cannot be absorbed
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+        std::fs::write(ctx.join(Path::new("new.txt")), "new\n").unwrap();
+        add(&ctx.repo, Path::new("new.txt"));
+        let config = Config {
+            exit_code: true,
+            ..default_config()
+        };
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::Some);
+        assert_eq!(outcome.exit_code(), 4);
+
+        let outcome = run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::None);
+        assert_eq!(outcome.exit_code(), 3);
+    }
+
+    #[test]
+    fn outcome_reports_nothing_staged() {
+        let (ctx, _) = prepare_repo();
+        let outcome = run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert_eq!(outcome.absorbed, Absorbed::NothingStaged);
+        // without --exit-code, the process still succeeds
+        assert_eq!(outcome.exit_code(), 0);
+    }
+
+    #[test]
+    fn applies_changes_to_nested_paths() {
+        let (ctx, _) = prepare_repo();
+        let repo = &ctx.repo;
+        std::fs::create_dir_all(ctx.join(Path::new("a/b"))).unwrap();
+        for path in ["a/b/c.txt", "a/d.txt"] {
+            std::fs::write(ctx.join(Path::new(path)), "one\ntwo\n").unwrap();
+            add(repo, Path::new(path));
+        }
+        let base = add(repo, Path::new("a/d.txt"));
+
+        let hunk = |start, line: &[u8]| owned::Hunk {
+            added: owned::Block {
+                start,
+                lines: std::rc::Rc::new(vec![line.to_vec()]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start,
+                lines: std::rc::Rc::new(vec![]),
+                trailing_newline: true,
+            },
+        };
+        let first = Change::Hunk(hunk(1, b"zero\n"));
+        // positioned after the first hunk
+        let second = Change::Hunk(hunk(4, b"three\n"));
+        let mode = Change::Mode(git2::FileMode::BlobExecutable);
+        let tree = apply_changes_to_tree(
+            repo,
+            &base,
+            &[
+                (b"a/b/c.txt", &first),
+                (b"a/b/c.txt", &second),
+                (b"a/d.txt", &mode),
+            ],
+        )
+        .unwrap();
+
+        let c = tree.get_path(Path::new("a/b/c.txt")).unwrap();
+        assert_eq!(
+            repo.find_blob(c.id()).unwrap().content(),
+            b"zero\none\ntwo\nthree\n"
+        );
+        let d = tree.get_path(Path::new("a/d.txt")).unwrap();
+        assert_eq!(d.id(), base.get_path(Path::new("a/d.txt")).unwrap().id());
+        assert_eq!(d.filemode(), i32::from(git2::FileMode::BlobExecutable));
+        // everything else is untouched
+        assert_eq!(
+            tree.get_path(Path::new("test-file.txt")).unwrap().id(),
+            base.get_path(Path::new("test-file.txt")).unwrap().id()
+        );
+    }
+
+    #[test]
+    fn applies_changes_at_end_without_newline() {
+        let (ctx, _) = prepare_repo();
+        let repo = &ctx.repo;
+        let path = Path::new("eof.txt");
+        std::fs::write(ctx.join(path), "one\ntwo").unwrap();
+        let base = add(repo, path);
+
+        let block = |start, lines: &[&[u8]], trailing_newline| owned::Block {
+            start,
+            lines: std::rc::Rc::new(lines.iter().map(|line| line.to_vec()).collect()),
+            trailing_newline,
+        };
+        let apply = |hunk: owned::Hunk| {
+            let change = Change::Hunk(hunk);
+            apply_changes_to_tree(repo, &base, &[(b"eof.txt", &change)])
+                .map(|tree| tree.get_path(path).unwrap().id())
+                .map(|id| repo.find_blob(id).unwrap().content().to_vec())
+        };
+
+        // adds the missing newline, and a line after it
+        let added = apply(owned::Hunk {
+            removed: block(2, &[b"two"], false),
+            added: block(2, &[b"two\n", b"three"], false),
+        });
+        assert_eq!(added.unwrap(), b"one\ntwo\nthree");
+        let replaced = apply(owned::Hunk {
+            removed: block(2, &[b"two"], false),
+            added: block(2, &[b"two\n"], true),
+        });
+        assert_eq!(replaced.unwrap(), b"one\ntwo\n");
+        // a line without a newline can't go above other lines
+        let misplaced = apply(owned::Hunk {
+            removed: block(1, &[b"one\n"], true),
+            added: block(1, &[b"ONE"], false),
+        });
+        assert!(misplaced.is_err());
+    }
+
+    #[test]
+    fn dry_run_writes_no_objects() {
+        let ctx = prepare_and_stage();
+        let count_objects = || {
+            let mut count = 0;
+            ctx.repo
+                .odb()
+                .unwrap()
+                .foreach(|_| {
+                    count += 1;
+                    true
+                })
+                .unwrap();
+            count
+        };
+        let before = count_objects();
+
+        let config = Config {
+            dry_run: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+        assert_eq!(count_objects(), before);
+
+        // the fixups' objects are written for real runs
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(count_objects() > before);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo.odb().unwrap().read(head.tree_id()).unwrap();
+    }
+
+    #[test]
+    fn one_reflog_entry() {
+        let ctx = prepare_and_stage();
+        let branch = ctx.repo.head().unwrap().name().unwrap().to_owned();
+        let before = ctx.repo.reflog(&branch).unwrap().len();
+
+        let config = Config {
+            one_reflog_entry: true,
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 3);
+        let reflog = ctx.repo.reflog(&branch).unwrap();
+        assert_eq!(reflog.len(), before + 1);
+        let initial = ctx
+            .repo
+            .find_commit(reflog.get(1).unwrap().id_new())
+            .unwrap();
+        assert_eq!(
+            reflog.get(0).unwrap().message(),
+            Some("absorb: created 2 fixups for HEAD")
+        );
+        let head_reflog = ctx.repo.reflog("HEAD").unwrap();
+        assert_eq!(
+            head_reflog.get(0).unwrap().message(),
+            Some("absorb: created 2 fixups for HEAD")
+        );
+        assert_eq!(
+            ctx.repo
+                .refname_to_id(config::PRE_ABSORB_REF_DEFAULT)
+                .unwrap(),
+            initial.id()
+        );
+    }
+
+    #[test]
+    fn pre_absorb_head_history() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let initial = ctx.repo.head().unwrap().target().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        let first = ctx.repo.head().unwrap().target().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line0\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        let reflog = ctx.repo.reflog(config::PRE_ABSORB_REF_DEFAULT).unwrap();
+        let ids: Vec<_> = reflog.iter().map(|entry| entry.id_new()).collect();
+        assert_eq!(ids, vec![first, initial]);
     }
 
-    /// Stage the changes made to `path`.
-    fn add<'r>(repo: &'r git2::Repository, path: &Path) -> git2::Tree<'r> {
-        let mut index = repo.index().unwrap();
-        index.add_path(&path).unwrap();
-        index.write().unwrap();
+    #[test]
+    fn custom_pre_absorb_ref() {
+        let ctx = prepare_and_stage();
+        let initial = ctx.repo.head().unwrap().target().unwrap();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str(config::PRE_ABSORB_REF_CONFIG_NAME, "refs/absorb/pre-head")
+            .unwrap();
 
-        let tree_id = index.write_tree_to(&repo).unwrap();
-        repo.find_tree(tree_id).unwrap()
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+
+        assert_eq!(
+            ctx.repo.refname_to_id("refs/absorb/pre-head").unwrap(),
+            initial
+        );
+        assert!(ctx
+            .repo
+            .refname_to_id(config::PRE_ABSORB_REF_DEFAULT)
+            .is_err());
     }
 
-    /// Prepare an empty repo, and stage some changes.
-    fn prepare_and_stage() -> Context {
-        let (ctx, file_path) = prepare_repo();
+    #[cfg(unix)]
+    #[test]
+    fn rolls_back_failed_runs() {
+        use std::os::unix::fs::PermissionsExt;
 
-        // add some lines to our file
+        let (ctx, file_path) = prepare_repo();
         let path = ctx.join(&file_path);
         let contents = std::fs::read_to_string(&path).unwrap();
-        let modifications = format!("new_line1\n{contents}\nnew_line2");
-        std::fs::write(&path, &modifications).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}\nnew_line2")).unwrap();
+        // the second fixup fails, after the first one was committed
+        let hooks = ctx.join(Path::new(".git/hooks"));
+        let hook = hooks.join("commit-msg");
+        std::fs::write(
+            &hook,
+            "#!/bin/sh\ntest -e .git/seen && exit 1\ntouch .git/seen\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        let reflog_len = ctx.repo.reflog("HEAD").unwrap().len();
 
-        // stage it
-        add(&ctx.repo, &file_path);
+        let config = Config {
+            run_hooks: Some(true),
+            ..default_config()
+        };
+        let result = run_with_repo(&config, &ctx.repo);
+        assert!(matches!(result, Err(Error::HookFailed { .. })));
 
-        ctx
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
+        assert_eq!(ctx.repo.reflog("HEAD").unwrap().len(), reflog_len);
+        assert!(ctx
+            .repo
+            .refname_to_id(config::PRE_ABSORB_REF_DEFAULT)
+            .is_err());
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
     }
 
     #[test]
-    fn multiple_fixups_per_commit() {
+    fn refuses_concurrent_runs() {
         let ctx = prepare_and_stage();
+        let lock = ctx.repo.path().join("absorb.lock");
+        std::fs::write(&lock, "").unwrap();
+
+        let result = run_with_repo(&default_config(), &ctx.repo);
+        assert!(matches!(result, Err(Error::Locked(_))));
+        assert!(lock.exists());
+
+        std::fs::remove_file(&lock).unwrap();
+        run_with_repo(&default_config(), &ctx.repo).unwrap();
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn refuses_to_run_during_merge() {
+        let ctx = prepare_and_stage();
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        std::fs::write(ctx.repo.path().join("MERGE_HEAD"), format!("{head}\n")).unwrap();
+
+        let result = run_with_repo(&default_config(), &ctx.repo);
+        assert!(matches!(result, Err(Error::OperationInProgress("merge"))));
 
-        // run 'git-absorb'
-        let drain = slog::Discard;
-        let logger = slog::Logger::root(drain, o!());
         let config = Config {
-            dry_run: false,
-            force: false,
-            base: None,
-            and_rebase: false,
-            whole_file: false,
-            one_fixup_per_commit: false,
-            logger: &logger,
+            force_in_progress: true,
+            ..default_config()
         };
         run_with_repo(&config, &ctx.repo).unwrap();
-
         let mut revwalk = ctx.repo.revwalk().unwrap();
         revwalk.push_head().unwrap();
         assert_eq!(revwalk.count(), 3);
-
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
     }
 
     #[test]
-    fn one_fixup_per_commit() {
-        let ctx = prepare_and_stage();
+    fn stops_when_interrupted() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+        let head = ctx.repo.head().unwrap().target().unwrap();
 
-        // run 'git-absorb'
-        let drain = slog::Discard;
-        let logger = slog::Logger::root(drain, o!());
+        let interrupt = AtomicBool::new(true);
         let config = Config {
-            dry_run: false,
-            force: false,
-            base: None,
-            and_rebase: false,
-            whole_file: false,
-            one_fixup_per_commit: true,
-            logger: &logger,
+            interrupt: Some(&interrupt),
+            ..default_config()
         };
-        run_with_repo(&config, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2);
+        let result = run_with_repo(&config, &ctx.repo);
+        assert!(matches!(result, Err(Error::Interrupted)));
 
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert!(!ctx.repo.path().join("absorb.lock").exists());
     }
 
-    fn autostage_common(ctx: &Context, file_path: &PathBuf) -> (PathBuf, PathBuf) {
-        // 1 modification w/o staging
-        let path = ctx.join(&file_path);
-        let contents = std::fs::read_to_string(&path).unwrap();
-        let modifications = format!("{contents}\nnew_line2");
-        std::fs::write(&path, &modifications).unwrap();
+    #[test]
+    fn absorbs_in_linked_worktree() {
+        let (ctx, worktree) = prepare_worktree();
+        let main_head = ctx.repo.head().unwrap().target().unwrap();
 
-        // 1 extra file
-        let fp2 = PathBuf::from("unrel.txt");
-        std::fs::write(ctx.join(&fp2), "foo").unwrap();
+        let worktree_head = worktree.head().unwrap().target().unwrap();
 
-        (path, fp2)
+        run_with_repo(&default_config(), &worktree).unwrap();
+
+        let mut revwalk = worktree.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 4);
+        assert_eq!(worktree.head().unwrap().shorthand(), Some("wt"));
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), main_head);
+        assert!(nothing_left_in_index(&worktree).unwrap());
+        // the backup ref belongs to the worktree
+        assert_eq!(
+            worktree
+                .refname_to_id(config::PRE_ABSORB_REF_DEFAULT)
+                .unwrap(),
+            worktree_head
+        );
+        assert!(ctx
+            .repo
+            .refname_to_id(config::PRE_ABSORB_REF_DEFAULT)
+            .is_err());
     }
 
     #[test]
-    fn autostage_if_index_was_empty() {
-        let (ctx, file_path) = prepare_repo();
+    fn refuses_concurrent_runs_in_other_worktrees() {
+        let (ctx, worktree) = prepare_worktree();
+        let lock = ctx.repo.path().join("absorb.lock");
+        std::fs::write(&lock, "").unwrap();
 
-        // requires enabled config var
+        let result = run_with_repo(&default_config(), &worktree);
+        assert!(matches!(result, Err(Error::Locked(path)) if path == lock));
+
+        std::fs::remove_file(&lock).unwrap();
+        run_with_repo(&default_config(), &worktree).unwrap();
+        assert!(!lock.exists());
+        assert!(!worktree.path().join("absorb.lock").exists());
+    }
+
+    #[test]
+    fn reads_worktree_config() {
+        let (ctx, worktree) = prepare_worktree();
         ctx.repo
             .config()
             .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .set_bool("extensions.worktreeConfig", true)
             .unwrap();
+        std::fs::write(
+            worktree.path().join("config.worktree"),
+            "[absorb]\n\toneFixupPerCommit = true\n",
+        )
+        .unwrap();
+        // config is cached, so the worktree is opened again
+        let worktree = git2::Repository::open(worktree.workdir().unwrap()).unwrap();
 
-        autostage_common(&ctx, &file_path);
-
-        // run 'git-absorb'
-        let drain = slog::Discard;
-        let logger = slog::Logger::root(drain, o!());
-        let config = Config {
-            dry_run: false,
-            force: false,
-            base: None,
-            and_rebase: false,
-            whole_file: false,
-            one_fixup_per_commit: false,
-            logger: &logger,
-        };
-        run_with_repo(&config, &ctx.repo).unwrap();
+        run_with_repo(&default_config(), &worktree).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
+        let mut revwalk = worktree.revwalk().unwrap();
         revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2);
-
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(revwalk.count(), 3);
     }
 
     #[test]
-    fn do_not_autostage_if_index_was_not_empty() {
+    fn fetches_blobs_missing_from_partial_clone() {
         let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{contents}changed\n")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Change the file");
+        let mut config = ctx.repo.config().unwrap();
+        config.set_bool("uploadpack.allowFilter", true).unwrap();
+        config
+            .set_bool("uploadpack.allowAnySHA1InWant", true)
+            .unwrap();
 
-        // enable config var
+        // only the checked out blobs are in the clone, so the blob from
+        // before the last commit is missing
+        let clone_dir = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("git")
+            .args(["clone", "--quiet", "--filter=blob:none"])
+            .arg(format!("file://{}", ctx.dir.path().display()))
+            .arg(clone_dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let clone = git2::Repository::open(clone_dir.path()).unwrap();
+        let parent_tree = clone
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .parent(0)
+            .unwrap()
+            .tree()
+            .unwrap();
+        let old_blob = parent_tree.get_path(&file_path).unwrap().id();
+        assert!(!clone.odb().unwrap().exists(old_blob));
+
+        let path = clone_dir.path().join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{contents}more changes\n")).unwrap();
+        add(&clone, &file_path);
+        run_with_repo(&default_config(), &clone).unwrap();
+
+        assert!(clone.odb().unwrap().exists(old_blob));
+        let head = clone.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("fixup! Change the file\n"));
+    }
+
+    #[test]
+    fn fails_when_missing_blobs_cannot_be_fetched() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{contents}changed\n")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Change the file");
         ctx.repo
             .config()
             .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .set_bool("uploadpack.allowFilter", true)
             .unwrap();
 
-        let (_, fp2) = autostage_common(&ctx, &file_path);
-        // we stage the extra file - should stay in index
-        add(&ctx.repo, &fp2);
-
-        // run 'git-absorb'
-        let drain = slog::Discard;
-        let logger = slog::Logger::root(drain, o!());
-        let config = Config {
-            dry_run: false,
-            force: false,
-            base: None,
-            and_rebase: false,
-            whole_file: false,
-            one_fixup_per_commit: false,
-            logger: &logger,
-        };
-        run_with_repo(&config, &ctx.repo).unwrap();
+        let clone_dir = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("git")
+            .args(["clone", "--quiet", "--filter=blob:none"])
+            .arg(format!("file://{}", ctx.dir.path().display()))
+            .arg(clone_dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let clone = git2::Repository::open(clone_dir.path()).unwrap();
+        // the promisor remote is gone, so the missing blob can't be fetched
+        drop(ctx);
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
+        let path = clone_dir.path().join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{contents}more changes\n")).unwrap();
+        add(&clone, &file_path);
+        let head = clone.head().unwrap().target().unwrap();
+        let err = run_with_repo(&default_config(), &clone).unwrap_err();
 
-        assert_eq!(index_stats(&ctx.repo).unwrap().files_changed(), 1);
+        assert!(err.to_string().contains("could not fetch"), "{}", err);
+        assert_eq!(clone.head().unwrap().target().unwrap(), head);
     }
 
     #[test]
-    fn do_not_autostage_if_not_enabled_by_config_var() {
+    fn absorbs_patch() {
         let (ctx, file_path) = prepare_repo();
+        // like a formatter, which changes the file and hands over a diff
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        // an unrelated staged file, which must stay staged
+        std::fs::write(ctx.join(Path::new("staged.txt")), "staged\n").unwrap();
+        add(&ctx.repo, Path::new("staged.txt"));
+        let patch = b"\
+diff --git a/test-file.txt b/test-file.txt
+--- a/test-file.txt
++++ b/test-file.txt
+@@ -1,2 +1,3 @@
++new_line1
+ 
+ line
+";
+        let config = Config {
+            patch: Some(patch),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
 
-        // disable config var
-        ctx.repo
-            .config()
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+        // the staged file is still all that is staged: the index entry of
+        // the absorbed file moved along with HEAD, so that its change
+        // doesn't seem to be staged for reverting
+        let staged: Vec<_> = ctx
+            .repo
+            .diff_tree_to_index(Some(&head.tree().unwrap()), None, None)
             .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, false)
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_owned())
+            .collect();
+        assert_eq!(staged, vec![PathBuf::from("staged.txt")]);
+        let diff = ctx
+            .repo
+            .diff_index_to_workdir(None, None)
+            .unwrap()
+            .stats()
             .unwrap();
+        assert_eq!(diff.files_changed(), 0);
+    }
 
-        autostage_common(&ctx, &file_path);
+    #[test]
+    fn absorbs_unstaged_changes() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        // an unrelated staged file, which must stay staged
+        std::fs::write(ctx.join(Path::new("staged.txt")), "staged\n").unwrap();
+        let mut index = ctx.repo.index().unwrap();
+        index.add_path(Path::new("staged.txt")).unwrap();
+        index.write().unwrap();
 
-        // run 'git-absorb'
-        let drain = slog::Discard;
-        let logger = slog::Logger::root(drain, o!());
         let config = Config {
-            dry_run: false,
-            force: false,
-            base: None,
-            and_rebase: false,
-            whole_file: false,
-            one_fixup_per_commit: false,
-            logger: &logger,
+            unstaged: true,
+            ..default_config()
         };
         run_with_repo(&config, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+        assert!(head.tree().unwrap().get_name("staged.txt").is_none());
+        let staged = ctx
+            .repo
+            .diff_tree_to_index(Some(&head.tree().unwrap()), None, None)
+            .unwrap();
+        let staged: Vec<_> = staged
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_owned())
+            .collect();
+        assert_eq!(staged, vec![PathBuf::from("staged.txt")]);
+        let unstaged = ctx
+            .repo
+            .diff_index_to_workdir(None, None)
+            .unwrap()
+            .stats()
+            .unwrap();
+        assert_eq!(unstaged.files_changed(), 0);
+    }
 
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    #[test]
+    fn refuses_patch_that_does_not_apply() {
+        let ctx = prepare_and_stage();
+        let patch = b"\
+diff --git a/test-file.txt b/test-file.txt
+--- a/test-file.txt
++++ b/test-file.txt
+@@ -1,1 +1,1 @@
+-not in the file
++new
+";
+        let config = Config {
+            patch: Some(patch),
+            ..default_config()
+        };
+        assert!(run_with_repo(&config, &ctx.repo).is_err());
     }
 
     #[test]
-    fn fixup_message_always_commit_sha_if_configured() {
+    fn empty_patch_is_nothing_to_absorb() {
+        let (ctx, _) = prepare_repo();
+        let config = Config {
+            patch: Some(b""),
+            ..default_config()
+        };
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::NothingStaged)
+        ));
+    }
+
+    #[test]
+    fn target_needs_a_stack() {
         let ctx = prepare_and_stage();
+        let config = Config {
+            base: Some("HEAD"),
+            target: Some("HEAD"),
+            ..default_config()
+        };
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::StackEmpty)
+        ));
+    }
 
-        ctx.repo
-            .config()
-            .unwrap()
-            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, true)
+    #[test]
+    fn does_not_split_merge_commits() {
+        let (ctx, _) = prepare_repo();
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let second = ctx
+            .repo
+            .find_commit(commit_staged(&ctx.repo, "Second commit."))
+            .unwrap();
+        let signature = git2::Signature::now("nobody", "nobody@example.com").unwrap();
+        let merge = ctx
+            .repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "Merge",
+                &second.tree().unwrap(),
+                &[&second, &initial],
+            )
             .unwrap();
+        let config = Config {
+            from: Some("HEAD"),
+            ..default_config()
+        };
+        assert!(matches!(
+            run_with_repo(&config, &ctx.repo),
+            Err(Error::MergeCommitBoundary(id)) if id == merge
+        ));
+    }
+
+    #[test]
+    fn splits_commit_into_fixups() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+        // new files can't be absorbed, so they stay in the split commit
+        let new_file = PathBuf::from("new-file.txt");
+        std::fs::write(ctx.join(&new_file), "new\n").unwrap();
+        add(&ctx.repo, &new_file);
+        let split = commit_staged(&ctx.repo, "Assorted fixes");
+        let split_tree = ctx.repo.find_commit(split).unwrap().tree_id();
 
-        // run 'git-absorb'
-        let drain = slog::Discard;
-        let logger = slog::Logger::root(drain, o!());
         let config = Config {
-            dry_run: false,
-            force: false,
-            base: None,
-            and_rebase: false,
-            whole_file: false,
-            one_fixup_per_commit: true,
-            logger: &logger,
+            from: Some("HEAD"),
+            ..default_config()
         };
         run_with_repo(&config, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Assorted fixes"));
+        assert_eq!(head.tree_id(), split_tree);
+        let fixup = head.parent(0).unwrap();
+        assert_eq!(fixup.summary(), Some("fixup! Initial commit."));
+        assert!(fixup.tree().unwrap().get_path(&new_file).is_err());
+        assert_eq!(
+            ctx.repo
+                .refname_to_id(config::PRE_ABSORB_REF_DEFAULT)
+                .unwrap(),
+            split
+        );
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn drops_fully_absorbed_split_commit() {
+        let (ctx, file_path) = prepare_repo();
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("new_line1\n{contents}")).unwrap();
+        add(&ctx.repo, &file_path);
+        commit_staged(&ctx.repo, "Assorted fixes");
+
+        let config = Config {
+            from: Some("HEAD"),
+            ..default_config()
+        };
+        run_with_repo(&config, &ctx.repo).unwrap();
 
         let mut revwalk = ctx.repo.revwalk().unwrap();
         revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+    }
 
-        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
-        assert_eq!(oids.len(), 2);
-
-        let commit = ctx.repo.find_commit(oids[0]).unwrap();
-        let actual_msg = commit.summary().unwrap();
-        let expected_msg = format!("fixup! {}", oids[1]);
-        assert_eq!(actual_msg, expected_msg);
+    #[test]
+    fn only_splits_head() {
+        let ctx = prepare_and_stage();
+        commit_staged(&ctx.repo, "Assorted fixes");
+        let config = Config {
+            from: Some("HEAD~1"),
+            ..default_config()
+        };
+        assert!(run_with_repo(&config, &ctx.repo).is_err());
     }
 }
@@ -0,0 +1,39 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::Error;
+
+/// Keeps other git-absorb runs out of a repository, including all of its
+/// worktrees, while one is staging, committing or unstaging there.
+/// Released when dropped.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Creates the lock file in `repo`'s common git directory, failing if
+    /// it already exists. Worktrees share their branches (which
+    /// --update-refs can move) and objects, so one lock covers them all.
+    pub fn acquire(repo: &git2::Repository) -> Result<Self, Error> {
+        let path = repo.commondir().join("absorb.lock");
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(Error::Locked(path));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // for whoever finds a stale lock
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Lock { path })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(err = %e, path = %self.path.display(), "could not remove the lock file");
+        }
+    }
+}
@@ -1,28 +1,49 @@
 #[macro_use]
-extern crate slog;
+extern crate tracing;
 
 use clap::{CommandFactory, Parser as _};
 use clap_complete::{generate, Shell};
 use clap_complete_nushell::Nushell;
-use slog::Drain;
-use std::io;
+use std::cell::Cell;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 /// Automatically absorb staged changes into your current branch
 #[derive(Debug, clap::Parser)]
 #[command(version)]
 struct Cli {
-    /// Use this commit as the base of the absorb stack
+    /// Use this commit as the base of the absorb stack: a commit-ish,
+    /// @{upstream}, @{push}, or ~N meaning HEAD~N
     #[clap(long, short)]
     base: Option<String>,
+    /// Use the commit that HEAD forked from the base branch (or from the
+    /// upstream, without --base) as the base, per the branch's reflog
+    #[clap(long)]
+    fork_point: bool,
+    /// Leave this commit, or the refs matching this glob, and everything
+    /// they contain out of the stack. Can be given more than once
+    #[clap(long, value_name = "REF")]
+    not: Vec<String>,
     /// Don't make any actual changes
     #[clap(long, short = 'n')]
     dry_run: bool,
     /// Skip safety checks
     #[clap(long, short)]
     force: bool,
-    /// Display more output
-    #[clap(long, short)]
-    verbose: bool,
+    /// Run even if a rebase, merge, cherry-pick or revert is in progress
+    #[clap(long)]
+    force_in_progress: bool,
+    /// Display more output: -v for debug messages, -vv to also trace
+    /// the parsed diffs
+    #[clap(long, short, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only display warnings and errors
+    #[clap(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Format of the log messages on stderr
+    #[clap(long, value_name = "FORMAT", default_value = "text", value_parser = ["text", "json"])]
+    log_format: String,
     /// Run rebase if successful
     #[clap(long, short = 'r')]
     and_rebase: bool,
@@ -35,18 +56,299 @@ struct Cli {
     /// Only generate one fixup per commit
     #[clap(long, short = 'F')]
     one_fixup_per_commit: bool,
+    /// Don't sign fixup commits, even if commit.gpgSign is set
+    #[clap(long)]
+    no_gpg_sign: bool,
+    /// Consider at most this many commits when no base is given,
+    /// overriding absorb.maxStack
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_stack: Option<u64>,
+    /// Absorb all staged changes into this commit, without searching
+    /// for a destination
+    #[clap(long, value_name = "COMMIT")]
+    target: Option<String>,
+    /// Generate squash! commits instead of fixup! commits
+    #[clap(long, conflicts_with = "amend")]
+    squash: bool,
+    /// Generate amend! commits, whose message replaces the target's
+    /// message when autosquashing (use with --edit to reword)
+    #[clap(long)]
+    amend: bool,
+    /// Edit the message of each generated commit
+    #[clap(long, short)]
+    edit: bool,
+    /// Explain why each change was or was not absorbed
+    #[clap(long)]
+    explain: bool,
+    /// Print a stable, machine-readable report on stdout
+    #[clap(long)]
+    porcelain: bool,
+    /// Exit with 2 if nothing was staged, 3 if nothing could be absorbed,
+    /// and 4 if only some changes could be absorbed
+    #[clap(long)]
+    exit_code: bool,
+    /// With --dry-run, print the patch of each fixup that would be
+    /// created
+    #[clap(long, requires = "dry_run", conflicts_with = "porcelain")]
+    diff: bool,
+    /// Write each fixup as a patch file in DIR, like git format-patch,
+    /// instead of committing it
+    #[clap(long, value_name = "DIR", conflicts_with = "and_rebase")]
+    format_patch: Option<std::path::PathBuf>,
+    /// Absorb the unified diff in FILE (or stdin, if FILE is -) instead of
+    /// the index
+    #[clap(long, value_name = "FILE")]
+    patch_from: Option<std::path::PathBuf>,
+    /// Absorb the changes of COMMIT, which must be HEAD, instead of the
+    /// index, leaving what can't be absorbed in it
+    #[clap(long, value_name = "COMMIT", conflicts_with = "patch_from")]
+    from: Option<String>,
+    /// Absorb the unstaged changes in the working tree instead of the
+    /// index, for files that have nothing staged, leaving what is staged
+    /// as it is
+    #[clap(long, conflicts_with_all = ["patch_from", "from"])]
+    unstaged: bool,
+    /// When auto-staging, add untracked files as intent-to-add (like
+    /// git add -N) instead of leaving them out
+    #[clap(long)]
+    include_untracked: bool,
+    /// Ask which hunks to absorb, one by one, with their proposed
+    /// target commits, and allow editing them
+    #[clap(long = "patch", short = 'p')]
+    select_hunks: bool,
+    /// How to find the commit to absorb each hunk into, overriding
+    /// absorb.strategy
+    #[clap(long, value_parser = ["commute", "blame", "commute-then-blame"])]
+    strategy: Option<String>,
+    /// Only absorb changes into commits whose message matches this
+    /// regex, leaving other changes in the index
+    #[clap(long, value_name = "PATTERN")]
+    commit_grep: Option<String>,
+    /// Let a change commute with a commit that replaced the same lines,
+    /// if the lines that each of them changes are apart from each other
+    #[clap(long)]
+    interleave_hunks: bool,
+    /// Let changes commute with commits that changed lines next to
+    /// them, allowing up to N of the surrounding lines to differ,
+    /// overriding absorb.fuzz
+    #[clap(long, value_name = "N")]
+    fuzz: Option<u64>,
+    /// With --skip-whitespace-only, ignore changes in the amount of
+    /// whitespace, like git diff -b
+    #[clap(long, group = "whitespace", requires = "skip_whitespace_only")]
+    ignore_space_change: bool,
+    /// With --skip-whitespace-only, ignore all whitespace, like git
+    /// diff -w
+    #[clap(long, group = "whitespace", requires = "skip_whitespace_only")]
+    ignore_all_space: bool,
+    /// Leave staged changes that only change whitespace in the index
+    #[clap(long, requires = "whitespace")]
+    skip_whitespace_only: bool,
+    /// Who to author fixup commits as, overriding
+    /// absorb.fixupAuthorIsTargetAuthor
+    #[clap(long, value_name = "AUTHOR", value_parser = ["me", "target"])]
+    fixup_author: Option<String>,
+    /// With --and-rebase, keep each commit's committer date the same as
+    /// its author date
+    #[clap(long, requires = "and_rebase")]
+    committer_date_is_author_date: bool,
+    /// With --and-rebase, reset each rebased commit's author date to now
+    #[clap(long, requires = "and_rebase")]
+    reset_author_date: bool,
+    /// After a successful --and-rebase, force-push to the upstream, unless
+    /// it has changed since it was last fetched
+    #[clap(long, requires = "and_rebase")]
+    and_push: bool,
+    /// With --and-rebase, don't open the rebase's todo list, overriding
+    /// absorb.rebaseNonInteractive
+    #[clap(long, requires = "and_rebase")]
+    rebase_non_interactive: bool,
+    /// With --and-rebase, squash the fixups (the default, unless
+    /// rebase.autoSquash is false)
+    #[clap(long, requires = "and_rebase", overrides_with = "no_autosquash")]
+    autosquash: bool,
+    /// With --and-rebase, leave the fixups unsquashed
+    #[clap(long, requires = "and_rebase", overrides_with = "autosquash")]
+    no_autosquash: bool,
+    /// With --and-rebase, stash unstaged changes around the rebase (the
+    /// default, unless rebase.autoStash is false)
+    #[clap(long, requires = "and_rebase", overrides_with = "no_autostash")]
+    autostash: bool,
+    /// With --and-rebase, don't stash unstaged changes, so the rebase
+    /// refuses to start if there are any
+    #[clap(long, requires = "and_rebase", overrides_with = "autostash")]
+    no_autostash: bool,
+    /// Keep following the first parent past merge commits, without
+    /// fixing up the merges themselves
+    #[clap(long)]
+    cross_merges: bool,
+    /// Include local branches stacked below HEAD in the stack, and move
+    /// them along with --and-rebase (like rebase.updateRefs)
+    #[clap(long)]
+    update_refs: bool,
+    /// Run the pre-commit and commit-msg hooks for each fixup commit,
+    /// overriding absorb.runHooks
+    #[clap(long, overrides_with = "no_verify")]
+    verify: bool,
+    /// Don't run the pre-commit and commit-msg hooks for fixup commits
+    #[clap(long, overrides_with = "verify")]
+    no_verify: bool,
+    /// Move the branch to the last fixup commit in a single step, making
+    /// one reflog entry for the whole run instead of one per fixup
+    #[clap(long)]
+    one_reflog_entry: bool,
+    /// Before committing, check that squashing the fixups into their
+    /// commits gives the same tree as committing them does
+    #[clap(long)]
+    verify_plan: bool,
+    /// Print the plan and ask before creating any fixup commits
+    #[clap(long)]
+    confirm: bool,
+    /// Add a Signed-off-by trailer for the committer to each fixup
+    #[clap(long, short)]
+    signoff: bool,
+    /// Open the plan in an editor, like a rebase todo list, to change
+    /// the commit that changes are absorbed into or drop them
+    #[clap(long)]
+    edit_plan: bool,
+}
+
+/// Set by the first Ctrl-C, so the run can stop and clean up after itself.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Shows what absorb is doing on a progress bar, once it has been
+/// running long enough for that to be useful.
+struct Progress {
+    bar: indicatif::ProgressBar,
+    started: Instant,
+    commits: Cell<u64>,
+    hunks: Cell<u64>,
+    fixups: Cell<u64>,
+}
+
+impl Progress {
+    const DELAY: Duration = Duration::from_secs(1);
+
+    fn new() -> Self {
+        // stays hidden until the delay has passed
+        let bar =
+            indicatif::ProgressBar::with_draw_target(None, indicatif::ProgressDrawTarget::hidden())
+                .with_style(indicatif::ProgressStyle::with_template("{spinner} {msg}").unwrap());
+        Progress {
+            bar,
+            started: Instant::now(),
+            commits: Cell::new(0),
+            hunks: Cell::new(0),
+            fixups: Cell::new(0),
+        }
+    }
+
+    fn update(&self) {
+        if self.bar.is_hidden() && self.started.elapsed() >= Self::DELAY {
+            self.bar
+                .set_draw_target(indicatif::ProgressDrawTarget::stderr());
+        }
+        self.bar.set_message(format!(
+            "{} commits parsed, {} hunks planned, {} fixups committed",
+            self.commits.get(),
+            self.hunks.get(),
+            self.fixups.get(),
+        ));
+        self.bar.tick();
+    }
+}
+
+impl git_absorb::Reporter for Progress {
+    fn on_commit_scanned(&self, _: git2::Oid) {
+        self.commits.set(self.commits.get() + 1);
+        self.update();
+    }
+
+    fn on_hunk_planned(&self, _: &[u8], _: &str, _: Option<git2::Oid>, _: &str) {
+        self.hunks.set(self.hunks.get() + 1);
+        self.update();
+    }
+
+    fn on_fixup_committed(&self, _: Option<git2::Oid>, _: git2::Oid) {
+        self.fixups.set(self.fixups.get() + 1);
+        self.update();
+    }
+
+    fn on_finished(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Writes log messages to stderr without garbling the progress bar.
+#[derive(Clone)]
+struct LogWriter(indicatif::ProgressBar);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
 }
 
 fn main() {
     let Cli {
         base,
+        fork_point,
+        not,
         dry_run,
         force,
+        force_in_progress,
         verbose,
+        quiet,
+        log_format,
         and_rebase,
         gen_completions,
         whole_file,
         one_fixup_per_commit,
+        no_gpg_sign,
+        max_stack,
+        target,
+        squash,
+        amend,
+        edit,
+        explain,
+        porcelain,
+        exit_code,
+        diff,
+        format_patch,
+        patch_from,
+        from,
+        unstaged,
+        include_untracked,
+        select_hunks,
+        strategy,
+        commit_grep,
+        interleave_hunks,
+        fuzz,
+        ignore_space_change,
+        ignore_all_space,
+        skip_whitespace_only,
+        fixup_author,
+        committer_date_is_author_date,
+        reset_author_date,
+        and_push,
+        rebase_non_interactive,
+        autosquash,
+        no_autosquash,
+        autostash,
+        no_autostash,
+        cross_merges,
+        update_refs,
+        verify,
+        no_verify,
+        one_reflog_entry,
+        verify_plan,
+        confirm,
+        signoff,
+        edit_plan,
     } = Cli::parse();
 
     if let Some(shell) = gen_completions {
@@ -64,39 +366,180 @@ fn main() {
         return;
     }
 
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = std::sync::Mutex::new(drain).fuse();
-
-    let drain = slog::LevelFilter::new(
-        drain,
-        if verbose {
-            slog::Level::Debug
+    let json = log_format == "json";
+    // RUST_LOG, if set, takes precedence over -v and -q
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::new(if verbose >= 2 {
+            "trace"
+        } else if verbose == 1 {
+            "debug"
+        } else if quiet {
+            "warn"
         } else {
-            slog::Level::Info
-        },
-    )
-    .fuse();
-    let mut logger = slog::Logger::root(drain, o!());
-    if verbose {
-        logger = logger.new(o!(
-            "module" => slog::FnValue(|record| record.module()),
-            "line" => slog::FnValue(|record| record.line()),
-        ));
+            "info"
+        })
+    });
+    let progress = Progress::new();
+    let log_writer = LogWriter(progress.bar.clone());
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(move || log_writer.clone())
+        .with_target(verbose > 0)
+        .with_file(verbose > 0)
+        .with_line_number(verbose > 0);
+    if json {
+        subscriber.json().init();
+    } else {
+        subscriber.with_ansi(io::stderr().is_terminal()).init();
+    }
+
+    let handler = ctrlc::set_handler(|| {
+        // a second Ctrl-C doesn't wait for the cleanup
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            ::std::process::exit(130);
+        }
+    });
+    if let Err(e) = handler {
+        warn!(err = %e, "could not install a Ctrl-C handler");
     }
 
-    if let Err(e) = git_absorb::run(&mut git_absorb::Config {
+    let patch = match patch_from {
+        Some(path) if path.as_os_str() == "-" => {
+            let mut patch = Vec::new();
+            io::stdin().read_to_end(&mut patch).map(|_| Some(patch))
+        }
+        Some(path) => std::fs::read(path).map(Some),
+        None => Ok(None),
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(e) => {
+            error!(err = %e, "could not read the patch");
+            ::std::process::exit(1);
+        }
+    };
+
+    let result = git_absorb::run(&git_absorb::Config {
         dry_run,
         force,
+        force_in_progress,
         base: base.as_deref(),
+        fork_point,
+        not: &not,
         and_rebase,
         whole_file,
         one_fixup_per_commit,
-        logger: &logger,
-    }) {
-        crit!(logger, "absorb failed"; "err" => e.to_string());
-        // wait for async logger to finish writing messages
-        drop(logger);
-        ::std::process::exit(1);
+        no_gpg_sign,
+        max_stack: max_stack.map(|max_stack| max_stack as usize),
+        target: target.as_deref(),
+        squash,
+        amend,
+        edit,
+        explain,
+        porcelain,
+        exit_code,
+        show_diff: diff,
+        format_patch: format_patch.as_deref(),
+        patch: patch.as_deref(),
+        from: from.as_deref(),
+        unstaged,
+        include_untracked,
+        strategy: strategy
+            .as_deref()
+            .and_then(git_absorb::Strategy::from_name),
+        commit_grep: commit_grep.as_deref(),
+        interleave_hunks,
+        fuzz: fuzz.map(|fuzz| fuzz as usize),
+        ignore_space_change,
+        ignore_all_space,
+        skip_whitespace_only,
+        fixup_author_is_target_author: fixup_author.map(|author| author == "target"),
+        committer_date_is_author_date,
+        reset_author_date,
+        cross_merges,
+        update_refs,
+        // anything but log messages would break up the json
+        quiet: quiet || json,
+        run_hooks: match (verify, no_verify) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        rebase_non_interactive,
+        and_push,
+        autosquash: match (autosquash, no_autosquash) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        autostash: match (autostash, no_autostash) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        one_reflog_entry,
+        verify_plan,
+        confirm,
+        signoff,
+        edit_plan,
+        selector: if select_hunks {
+            Some(&git_absorb::Prompt)
+        } else {
+            None
+        },
+        // only --confirm asks when there is no terminal to answer on
+        ask: if confirm || (io::stdin().is_terminal() && io::stderr().is_terminal()) {
+            Some(&git_absorb::Prompt)
+        } else {
+            None
+        },
+        // the progress bar would only get in the way of other output, or
+        // of the editor and prompts
+        reporter: if quiet
+            || json
+            || edit
+            || edit_plan
+            || select_hunks
+            || confirm
+            || !io::stderr().is_terminal()
+        {
+            None
+        } else {
+            Some(&progress)
+        },
+        interrupt: Some(&INTERRUPTED),
+    });
+    progress.bar.finish_and_clear();
+    match result {
+        Ok(outcome) => {
+            if let Some(summary) = outcome.summary() {
+                eprint!("{}", summary);
+            }
+            let code = outcome.exit_code();
+            if code != 0 {
+                ::std::process::exit(code);
+            }
+        }
+        Err(git_absorb::Error::Interrupted) => {
+            warn!("interrupted, nothing was changed");
+            ::std::process::exit(130);
+        }
+        Err(e) => {
+            error!(err = %e, "absorb failed");
+            ::std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_stays_out_of_porcelain_output() {
+        assert!(Cli::try_parse_from(["git-absorb", "--dry-run", "--diff"]).is_ok());
+        let err =
+            Cli::try_parse_from(["git-absorb", "--dry-run", "--diff", "--porcelain"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
     }
 }
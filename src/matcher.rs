@@ -0,0 +1,129 @@
+//! An external command that decides which commit each staged hunk is
+//! absorbed into, configured with absorb.matcherCommand.
+//!
+//! The command is given the planned hunks and the commits of the stack
+//! as JSON on stdin:
+//!
+//! ```json
+//! {
+//!   "commits": [{"id": "<oid>", "summary": "...", "message": "...", "author": "..."}],
+//!   "hunks": [{"hunk": 0, "path": "...", "header": "-3,0 +4,1",
+//!              "removed": ["..."], "added": ["..."], "target": "<oid>"}]
+//! }
+//! ```
+//!
+//! and prints the hunks it wants to assign differently:
+//!
+//! ```json
+//! {"assignments": [{"hunk": 0, "commit": "<commit-ish>"}, {"hunk": 1, "commit": null}]}
+//! ```
+//!
+//! A null commit leaves the hunk in the index. Hunks that aren't listed
+//! keep their planned target.
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// A planned hunk, as given to the matcher.
+pub struct Hunk<'a> {
+    pub path: &'a [u8],
+    pub header: String,
+    pub removed: &'a [Vec<u8>],
+    pub added: &'a [Vec<u8>],
+    pub target: git2::Oid,
+}
+
+fn lines(lines: &[Vec<u8>]) -> Vec<String> {
+    lines
+        .iter()
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect()
+}
+
+/// Runs `command` through the shell, from the top of the work tree, and
+/// returns the commit that each of the `hunks` should be absorbed into,
+/// or None for the ones it leaves in the index.
+pub fn run(
+    repo: &git2::Repository,
+    command: &str,
+    commits: &[&git2::Commit],
+    hunks: &[Hunk],
+) -> Result<Vec<Option<git2::Oid>>> {
+    let input = json!({
+        "commits": commits
+            .iter()
+            .map(|commit| json!({
+                "id": commit.id().to_string(),
+                "summary": commit.summary().unwrap_or(""),
+                "message": commit.message().unwrap_or(""),
+                "author": commit.author().to_string(),
+            }))
+            .collect::<Vec<_>>(),
+        "hunks": hunks
+            .iter()
+            .enumerate()
+            .map(|(n, hunk)| json!({
+                "hunk": n,
+                "path": String::from_utf8_lossy(hunk.path),
+                "header": hunk.header,
+                "removed": lines(hunk.removed),
+                "added": lines(hunk.added),
+                "target": hunk.target.to_string(),
+            }))
+            .collect::<Vec<_>>(),
+    });
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(repo.workdir().unwrap_or(repo.path()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("could not run matcher command {:?}: {}", command, e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = serde_json::to_vec(&input)?;
+    // written from another thread, so that a matcher that starts
+    // printing before it has read everything can't deadlock
+    let writer = std::thread::spawn(move || {
+        // the matcher doesn't have to read its input
+        let _ = stdin.write_all(&input);
+    });
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(anyhow!(
+            "matcher command {:?} exited with {}",
+            command,
+            output.status
+        ));
+    }
+
+    let output: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow!("matcher command printed invalid JSON: {}", e))?;
+    let mut targets: Vec<_> = hunks.iter().map(|hunk| Some(hunk.target)).collect();
+    let assignments = output["assignments"]
+        .as_array()
+        .ok_or_else(|| anyhow!("matcher command printed no assignments"))?;
+    for assignment in assignments {
+        let n = assignment["hunk"]
+            .as_u64()
+            .and_then(|n| usize::try_from(n).ok())
+            .filter(|&n| n < hunks.len())
+            .ok_or_else(|| anyhow!("matcher command assigned an unknown hunk: {}", assignment))?;
+        targets[n] = match &assignment["commit"] {
+            Value::Null => None,
+            Value::String(commit) => Some(repo.revparse_single(commit)?.peel_to_commit()?.id()),
+            other => {
+                return Err(anyhow!(
+                    "matcher command assigned an invalid commit: {}",
+                    other
+                ))
+            }
+        };
+    }
+    Ok(targets)
+}
@@ -0,0 +1,61 @@
+//! Fixups written as patch files, like `git format-patch` would, instead
+//! of being committed.
+
+use anyhow::Result;
+
+use std::path::{Path, PathBuf};
+
+/// Writes the fixup with `message` and `diff` to `dir` as the `number`th
+/// of `total` patches, returning the file's path.
+pub fn write(
+    dir: &Path,
+    number: usize,
+    total: usize,
+    message: &str,
+    diff: &git2::Diff,
+    author: &git2::Signature,
+) -> Result<PathBuf> {
+    let (subject, body) = message.split_once('\n').unwrap_or((message, ""));
+    // the fixup isn't a commit, so there is no id to put in the From line
+    let email = git2::Email::from_diff(
+        diff,
+        number,
+        total,
+        &git2::Oid::zero(),
+        subject,
+        body.trim(),
+        author,
+        &mut git2::EmailCreateOptions::new(),
+    )?;
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{:04}-{}.patch", number, file_name(subject)));
+    std::fs::write(&path, email.as_slice())?;
+    Ok(path)
+}
+
+/// Turns a subject into a file name the way `git format-patch` does,
+/// eg `fixup! Add a thing` into `fixup-Add-a-thing`.
+fn file_name(subject: &str) -> String {
+    let mut ret = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            ret.push(c);
+        } else if !ret.is_empty() && !ret.ends_with('-') {
+            ret.push('-');
+        }
+    }
+    let ret = ret.trim_end_matches(['-', '.']);
+    ret.chars().take(52).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("fixup! Add a thing"), "fixup-Add-a-thing");
+        assert_eq!(file_name("fixup! v1.2: (fix)..."), "fixup-v1.2-fix");
+        assert_eq!(file_name(&"a".repeat(100)).len(), 52);
+    }
+}
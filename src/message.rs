@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Result};
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::hooks;
+use crate::Error;
+
+/// The file that messages are edited in, like `COMMIT_EDITMSG`.
+const EDIT_MESSAGE_FILE: &str = "ABSORB_EDITMSG";
+
+/// Finds the user's editor the same way git does.
+fn editor(repo: &git2::Repository) -> String {
+    std::env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| {
+            repo.config()
+                .and_then(|config| config.get_string("core.editor"))
+                .ok()
+        })
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .filter(|editor| !editor.is_empty())
+        .unwrap_or_else(|| "vi".to_owned())
+}
+
+/// The line below which an edited message is ignored in
+/// [`Cleanup::Scissors`] mode.
+const SCISSORS: &str = "# ------------------------ >8 ------------------------";
+
+/// How messages are cleaned up before committing, like git's
+/// `commit.cleanup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cleanup {
+    /// Remove comment lines, and whitespace like [`Cleanup::Whitespace`].
+    Strip,
+    /// Remove trailing whitespace and leading, trailing and repeated
+    /// blank lines.
+    Whitespace,
+    /// Leave the message as it is.
+    Verbatim,
+    /// Like [`Cleanup::Whitespace`], but also drop everything below the
+    /// scissors line when the message was edited.
+    Scissors,
+}
+
+impl Cleanup {
+    /// Reads `commit.cleanup`. Like git, the default is to strip
+    /// messages that were edited, and only clean up the whitespace of
+    /// the others. `strip` always strips.
+    pub fn from_config(repo: &git2::Repository, edited: bool) -> Result<Cleanup> {
+        let mode = repo
+            .config()
+            .and_then(|config| config.get_string("commit.cleanup"));
+        Ok(match mode.as_deref().unwrap_or("default") {
+            "default" if edited => Cleanup::Strip,
+            "default" => Cleanup::Whitespace,
+            "strip" => Cleanup::Strip,
+            "whitespace" => Cleanup::Whitespace,
+            "verbatim" => Cleanup::Verbatim,
+            "scissors" if edited => Cleanup::Scissors,
+            "scissors" => Cleanup::Whitespace,
+            other => return Err(anyhow!("invalid commit.cleanup mode {:?}", other)),
+        })
+    }
+}
+
+/// Opens `initial` in the user's editor, and returns the edited
+/// message cleaned up according to `cleanup`.
+pub fn edit(repo: &git2::Repository, initial: &str, cleanup: Cleanup) -> Result<String> {
+    let path = repo.path().join(EDIT_MESSAGE_FILE);
+    let help = match cleanup {
+        Cleanup::Strip => "# Please enter the commit message for the fixup. Lines starting\n\
+             # with '#' will be ignored, and an empty message aborts the absorb.\n"
+            .to_owned(),
+        Cleanup::Scissors => format!(
+            "{}\n\
+             # Do not modify or remove the line above.\n\
+             # Everything below it will be ignored.\n",
+            SCISSORS
+        ),
+        Cleanup::Whitespace | Cleanup::Verbatim => {
+            "# Please enter the commit message for the fixup. Lines starting\n\
+             # with '#' will be kept; you may remove them yourself if you want to.\n\
+             # An empty message aborts the absorb.\n"
+                .to_owned()
+        }
+    };
+    std::fs::write(&path, format!("{}\n{}", initial.trim_end(), help))?;
+
+    launch_editor(repo, &path)?;
+
+    let message = cleanup_message(&std::fs::read_to_string(&path)?, cleanup);
+    if message.trim().is_empty() {
+        return Err(Error::EmptyMessage.into());
+    }
+    Ok(message)
+}
+
+/// Opens the file at `path` in the user's editor, and waits for it to
+/// be closed.
+pub fn launch_editor(repo: &git2::Repository, path: &Path) -> Result<()> {
+    // like git, run the editor through the shell so that it can
+    // contain arguments
+    let editor = editor(repo);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .map_err(|e| anyhow!("could not launch editor {:?}: {}", editor, e))?;
+    if !status.success() {
+        return Err(anyhow!("editor {:?} exited with {}", editor, status));
+    }
+    Ok(())
+}
+
+/// Adds the body of `commit.template` to `message`, unless `template`
+/// is false, and runs the prepare-commit-msg hook on it. The hook can
+/// add to the message, but not change its subject, which autosquash
+/// relies on.
+pub fn prepare(repo: &git2::Repository, message: &str, template: bool) -> Result<String> {
+    let body = if template { template_body(repo)? } else { None };
+    let (prepared, source) = match body {
+        Some(body) => (format!("{}\n\n{}", message.trim_end(), body), "template"),
+        None => (message.to_owned(), "message"),
+    };
+    let prepared = hooks::prepare_commit_msg(repo, &prepared, source)?;
+
+    let subject = message.lines().next().unwrap_or_default();
+    let mut lines = prepared.lines();
+    lines.next();
+    let mut message = subject.to_owned();
+    for line in lines {
+        message.push('\n');
+        message.push_str(line);
+    }
+    message.push('\n');
+    Ok(message)
+}
+
+/// Reads the file that `commit.template` points to, without its
+/// comment lines.
+fn template_body(repo: &git2::Repository) -> Result<Option<String>> {
+    let path = match repo
+        .config()
+        .and_then(|config| config.get_path("commit.template"))
+    {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+    let template = std::fs::read_to_string(&path)
+        .map_err(|e| anyhow!("could not read commit template {:?}: {}", path, e))?;
+    let body = strip_comments(&template);
+    Ok(if body.is_empty() { None } else { Some(body) })
+}
+
+/// Returns the trailers of `message` whose key is one of `keys`
+/// (ignoring case), as `Key: value` lines.
+pub fn trailers(message: &str, keys: &[String]) -> Vec<String> {
+    let trailers = match git2::message_trailers_strs(message) {
+        Ok(trailers) => trailers,
+        Err(_) => return Vec::new(),
+    };
+    trailers
+        .iter()
+        .filter(|(key, _)| keys.iter().any(|k| k.eq_ignore_ascii_case(key)))
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect()
+}
+
+/// Adds the `trailers` that `message` doesn't have yet to the end of
+/// its trailer block.
+pub fn add_trailers(message: &str, trailers: &[String]) -> String {
+    let missing: Vec<&String> = trailers
+        .iter()
+        .filter(|trailer| !message.lines().any(|line| line == trailer.as_str()))
+        .collect();
+    if missing.is_empty() {
+        return message.to_owned();
+    }
+
+    let has_trailer_block = git2::message_trailers_strs(message).is_ok_and(|t| t.len() > 0);
+    let mut message = message.trim_end().to_owned();
+    message.push_str(if has_trailer_block { "\n" } else { "\n\n" });
+    for trailer in missing {
+        message.push_str(trailer);
+        message.push('\n');
+    }
+    message
+}
+
+/// Removes comment lines and surrounding blank lines from a message,
+/// leaving exactly one trailing newline.
+fn strip_comments(message: &str) -> String {
+    cleanup_message(message, Cleanup::Strip)
+}
+
+/// Cleans up `message` the way `git commit` does in the `cleanup`
+/// mode.
+pub fn cleanup_message(message: &str, cleanup: Cleanup) -> String {
+    let message = match cleanup {
+        Cleanup::Verbatim => return message.to_owned(),
+        Cleanup::Scissors => match message.lines().position(|line| line == SCISSORS) {
+            Some(scissors) => message
+                .lines()
+                .take(scissors)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => message.to_owned(),
+        },
+        Cleanup::Strip | Cleanup::Whitespace => message.to_owned(),
+    };
+
+    let mut cleaned = String::new();
+    let mut blank = false;
+    for line in message.lines() {
+        if cleanup == Cleanup::Strip && line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+        // blank lines are collapsed, and dropped at the start
+        if blank && !cleaned.is_empty() {
+            cleaned.push('\n');
+        }
+        blank = false;
+        cleaned.push_str(line);
+        cleaned.push('\n');
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_comments() {
+        assert_eq!(
+            strip_comments("squash! foo\n\nbody  \n# comment\n\n"),
+            "squash! foo\n\nbody\n"
+        );
+        assert_eq!(strip_comments("# only\n# comments\n"), "");
+    }
+
+    #[test]
+    fn test_cleanup_message() {
+        let message = "\nfixup! foo \n\n\n# comment\nbody\n# ------------------------ >8 ------------------------\ndiff\n\n";
+        assert_eq!(
+            cleanup_message(message, Cleanup::Strip),
+            "fixup! foo\n\nbody\ndiff\n"
+        );
+        assert_eq!(
+            cleanup_message(message, Cleanup::Whitespace),
+            "fixup! foo\n\n# comment\nbody\n# ------------------------ >8 ------------------------\ndiff\n"
+        );
+        assert_eq!(
+            cleanup_message(message, Cleanup::Scissors),
+            "fixup! foo\n\n# comment\nbody\n"
+        );
+        assert_eq!(cleanup_message(message, Cleanup::Verbatim), message);
+    }
+
+    #[test]
+    fn test_cleanup_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        let mode = |edited| Cleanup::from_config(&repo, edited).unwrap();
+
+        assert_eq!(mode(true), Cleanup::Strip);
+        assert_eq!(mode(false), Cleanup::Whitespace);
+        config.set_str("commit.cleanup", "strip").unwrap();
+        assert_eq!(mode(true), Cleanup::Strip);
+        assert_eq!(mode(false), Cleanup::Strip);
+        config.set_str("commit.cleanup", "scissors").unwrap();
+        assert_eq!(mode(true), Cleanup::Scissors);
+        assert_eq!(mode(false), Cleanup::Whitespace);
+        config.set_str("commit.cleanup", "bogus").unwrap();
+        assert!(Cleanup::from_config(&repo, true).is_err());
+    }
+
+    #[test]
+    fn test_trailers() {
+        let keys = vec!["change-id".to_owned()];
+        let target = "Subject\n\nBody\n\nChange-Id: I123\nSigned-off-by: A <a@b>\n";
+        let trailers = trailers(target, &keys);
+        assert_eq!(trailers, vec!["Change-Id: I123"]);
+
+        assert_eq!(
+            add_trailers("fixup! Subject\n", &trailers),
+            "fixup! Subject\n\nChange-Id: I123\n"
+        );
+        assert_eq!(add_trailers(target, &trailers), target);
+        assert_eq!(
+            add_trailers("Subject\n\nSigned-off-by: A <a@b>\n", &trailers),
+            "Subject\n\nSigned-off-by: A <a@b>\nChange-Id: I123\n"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::Result;
+
+/// Keeps the objects written while absorbing in memory, so that dry runs
+/// and failed runs don't leave unreferenced objects behind. Objects are
+/// only written to the repository when they are flushed.
+///
+/// The repository goes back to its own object database when this is
+/// dropped, and whatever was not flushed is discarded.
+pub struct Buffered<'a> {
+    repo: &'a git2::Repository,
+    disk: git2::Odb<'a>,
+    memory: &'a git2::Odb<'a>,
+    mempack: git2::Mempack<'a>,
+}
+
+impl<'a> Buffered<'a> {
+    /// Makes `repo` write new objects to `memory`, while still reading
+    /// its existing objects from disk. `memory` is the object database of
+    /// a second handle on the same repository (see `reopen`), so it reads
+    /// from all the places that `repo`'s does, alternates included.
+    pub fn new(repo: &'a git2::Repository, memory: &'a git2::Odb<'a>) -> Result<Self> {
+        // the mempack comes first, so everything new is written to it
+        let mempack = memory.add_new_mempack_backend(1000)?;
+        let disk = repo.odb()?;
+        repo.set_odb(memory)?;
+        Ok(Buffered {
+            repo,
+            disk,
+            memory,
+            mempack,
+        })
+    }
+
+    /// Writes `id`, a commit or tree, and every object in it that isn't
+    /// in the repository yet, to the repository.
+    pub fn flush(&self, id: git2::Oid) -> Result<()> {
+        let mut pending = vec![id];
+        let mut flushed = 0usize;
+        while let Some(id) = pending.pop() {
+            // anything already on disk is complete, including the
+            // objects it refers to
+            if self.disk.exists(id) {
+                continue;
+            }
+            let object = self.memory.read(id)?;
+            match object.kind() {
+                git2::ObjectType::Commit => pending.push(self.repo.find_commit(id)?.tree_id()),
+                git2::ObjectType::Tree => pending.extend(
+                    self.repo
+                        .find_tree(id)?
+                        .iter()
+                        // submodule commits aren't stored here
+                        .filter(|entry| entry.kind() != Some(git2::ObjectType::Commit))
+                        .map(|entry| entry.id()),
+                ),
+                _ => {}
+            }
+            self.disk.write(object.kind(), object.data())?;
+            flushed += 1;
+        }
+        debug!(flushed, "flushed objects");
+        Ok(())
+    }
+}
+
+impl Drop for Buffered<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.repo.set_odb(&self.disk) {
+            warn!(err = %e, "could not restore the object database");
+        }
+        let _ = self.mempack.reset();
+    }
+}
+
+/// Opens `repo` again, reading the same environment variables that
+/// `git2::Repository::open_from_env` does (eg
+/// GIT_ALTERNATE_OBJECT_DIRECTORIES), so that the new handle finds the
+/// same objects without sharing `repo`'s object database.
+pub fn reopen(repo: &git2::Repository) -> Result<git2::Repository> {
+    Ok(git2::Repository::open_ext(
+        repo.path(),
+        git2::RepositoryOpenFlags::NO_SEARCH | git2::RepositoryOpenFlags::FROM_ENV,
+        &[] as &[&std::ffi::OsStr],
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_objects_from_alternates() {
+        let origin_dir = tempfile::tempdir().unwrap();
+        let origin = git2::Repository::init(origin_dir.path()).unwrap();
+        let old_blob = origin.blob(b"old\n").unwrap();
+
+        // the clone has no objects of its own, only an alternates file
+        let clone_dir = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("git")
+            .args(["clone", "--quiet", "--shared"])
+            .arg(origin_dir.path())
+            .arg(clone_dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(clone_dir
+            .path()
+            .join(".git/objects/info/alternates")
+            .exists());
+        let clone = git2::Repository::open(clone_dir.path()).unwrap();
+
+        let reader = reopen(&clone).unwrap();
+        let memory = reader.odb().unwrap();
+        let objects = Buffered::new(&clone, &memory).unwrap();
+        assert_eq!(clone.find_blob(old_blob).unwrap().content(), b"old\n");
+        let new_blob = clone.blob(b"new\n").unwrap();
+        assert!(clone.odb().unwrap().exists(new_blob));
+
+        // nothing was flushed
+        drop(objects);
+        assert!(clone.odb().unwrap().exists(old_blob));
+        assert!(!clone.odb().unwrap().exists(new_blob));
+    }
+}
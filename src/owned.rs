@@ -3,7 +3,7 @@ use anyhow::{anyhow, Result};
 use std::collections::hash_map::HashMap;
 use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Diff {
     patches: Vec<Patch>,
     by_new: HashMap<Vec<u8>, usize>,
@@ -28,11 +28,20 @@ impl Diff {
                 &mut git2::Patch::from_diff(diff, delta_idx)?
                     .ok_or_else(|| anyhow!("got empty delta"))?,
             )?;
-            if ret.by_old.contains_key(&patch.old_path) {
-                // TODO: would this case be hit if the diff was put through copy detection?
-                return Err(anyhow!("old path already occupied"));
+            // the source of a copy is still there, so it can be the old
+            // path of other patches too, and only those claim it
+            if patch.status != git2::Delta::Copied {
+                match ret.by_old.get(&patch.old_path) {
+                    Some(&idx) if ret.patches[idx].status != git2::Delta::Copied => {
+                        return Err(anyhow!("old path already occupied"));
+                    }
+                    _ => {
+                        ret.by_old.insert(patch.old_path.clone(), ret.patches.len());
+                    }
+                }
+            } else if !ret.by_old.contains_key(&patch.old_path) {
+                ret.by_old.insert(patch.old_path.clone(), ret.patches.len());
             }
-            ret.by_old.insert(patch.old_path.clone(), ret.patches.len());
             if ret.by_new.contains_key(&patch.new_path) {
                 return Err(anyhow!("new path already occupied"));
             }
@@ -51,6 +60,8 @@ impl Diff {
 pub struct Block {
     pub start: usize,
     pub lines: Rc<Vec<Vec<u8>>>,
+    /// Whether the last line ends with a newline. Only a block at the
+    /// end of the file can be missing it.
     pub trailing_newline: bool,
 }
 #[derive(Debug, Clone)]
@@ -201,6 +212,96 @@ impl Hunk {
         self.added.start = (self.added.start as isize + by) as usize;
         self
     }
+
+    /// Splits an isolated hunk (one positioned as if no other hunk was
+    /// applied) into the smallest hunks that apply on their own: each
+    /// removed line with the added line that replaces it, then each of
+    /// the other removed lines, then the other added lines together.
+    pub fn split_lines(&self) -> Vec<Self> {
+        let removed = &self.removed.lines;
+        let added = &self.added.lines;
+        let paired = removed.len().min(added.len());
+        let block = |start, lines: &[Vec<u8>], last: bool, trailing_newline: bool| Block {
+            start,
+            lines: Rc::new(lines.to_vec()),
+            trailing_newline: !last || trailing_newline,
+        };
+
+        let mut pieces = Vec::with_capacity(removed.len().max(added.len()));
+        for i in 0..paired {
+            pieces.push(Hunk {
+                removed: block(
+                    self.removed.start + i,
+                    &removed[i..=i],
+                    i + 1 == removed.len(),
+                    self.removed.trailing_newline,
+                ),
+                added: block(
+                    self.added.start + i,
+                    &added[i..=i],
+                    i + 1 == added.len(),
+                    self.added.trailing_newline,
+                ),
+            });
+        }
+        for i in paired..removed.len() {
+            pieces.push(Hunk {
+                removed: block(
+                    self.removed.start + i,
+                    &removed[i..=i],
+                    i + 1 == removed.len(),
+                    self.removed.trailing_newline,
+                ),
+                added: block(self.removed.start + i - 1, &[], true, true),
+            });
+        }
+        if paired < added.len() {
+            // the lines are added below the last replaced line
+            let after = match paired {
+                0 => self.removed.start,
+                paired => self.removed.start + paired - 1,
+            };
+            pieces.push(Hunk {
+                removed: block(after, &[], true, true),
+                added: block(
+                    self.added.start + paired,
+                    &added[paired..],
+                    true,
+                    self.added.trailing_newline,
+                ),
+            });
+        }
+        pieces
+    }
+
+    /// Joins consecutive pieces of a hunk from `split_lines` back into
+    /// one isolated hunk.
+    pub fn join(pieces: &[Hunk]) -> Self {
+        let join = |blocks: Vec<&Block>| {
+            let start = blocks
+                .iter()
+                .find(|block| !block.lines.is_empty())
+                .unwrap_or(&blocks[0])
+                .start;
+            let mut nonempty = blocks.iter().filter(|block| !block.lines.is_empty());
+            Block {
+                start,
+                lines: Rc::new(
+                    nonempty
+                        .clone()
+                        .flat_map(|block| block.lines.iter().cloned())
+                        .collect(),
+                ),
+                trailing_newline: nonempty
+                    .next_back()
+                    .map_or(true, |block| block.trailing_newline),
+            }
+        };
+        Hunk {
+            removed: join(pieces.iter().map(|piece| &piece.removed).collect()),
+            added: join(pieces.iter().map(|piece| &piece.added).collect()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -209,7 +310,13 @@ pub struct Patch {
     pub old_id: git2::Oid,
     pub new_path: Vec<u8>,
     pub new_id: git2::Oid,
+    pub old_mode: git2::FileMode,
+    pub new_mode: git2::FileMode,
     pub status: git2::Delta,
+    /// Whether either side is binary, in which case there are no hunks.
+    pub binary: bool,
+    /// The size in bytes of the larger side.
+    pub size: u64,
     pub hunks: Vec<Hunk>,
 }
 impl Patch {
@@ -229,7 +336,15 @@ impl Patch {
                 .map(Vec::from)
                 .ok_or_else(|| anyhow!("delta with empty new path"))?,
             new_id: patch.delta().new_file().id(),
+            old_mode: patch.delta().old_file().mode(),
+            new_mode: patch.delta().new_file().mode(),
             status: patch.delta().status(),
+            binary: patch.delta().flags().is_binary(),
+            size: patch
+                .delta()
+                .old_file()
+                .size()
+                .max(patch.delta().new_file().size()),
             hunks: Vec::with_capacity(patch.num_hunks()),
         };
         if patch.delta().nfiles() < 1 || patch.delta().nfiles() > 2 {
@@ -242,4 +357,10 @@ impl Patch {
 
         Ok(ret)
     }
+
+    /// Returns true if this patch changes the file mode (eg the
+    /// executable bit) of a file that exists on both sides.
+    pub fn changes_mode(&self) -> bool {
+        self.status == git2::Delta::Modified && self.old_mode != self.new_mode
+    }
 }
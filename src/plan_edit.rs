@@ -0,0 +1,132 @@
+//! The plan file of --edit-plan, which lists the staged changes and
+//! the commits they will be absorbed into, like a rebase todo list.
+
+use anyhow::{anyhow, Result};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{message, select};
+
+/// The file that the plan is edited in, like `git-rebase-todo`.
+const EDIT_PLAN_FILE: &str = "ABSORB_PLAN";
+
+/// A planned change, as listed in the plan file.
+pub struct Entry<'a> {
+    pub target: &'a git2::Commit<'a>,
+    pub path: &'a [u8],
+    pub change: String,
+}
+
+/// Opens the plan in the user's editor, and returns the commit that
+/// each of the `entries` should be absorbed into, or None for the ones
+/// that were dropped.
+pub fn edit(
+    repo: &git2::Repository,
+    entries: &[Entry],
+    leftovers: &[(&[u8], &str, String)],
+) -> Result<Vec<Option<git2::Oid>>> {
+    let mut plan = String::new();
+    for (n, entry) in entries.iter().enumerate() {
+        writeln!(
+            plan,
+            "fixup {} {} {} {} # {}",
+            select::short_id(entry.target),
+            n + 1,
+            String::from_utf8_lossy(entry.path),
+            entry.change,
+            entry.target.summary().unwrap_or(""),
+        )?;
+    }
+    if !leftovers.is_empty() {
+        writeln!(plan, "\n# These changes will be left in the index:")?;
+        for (path, change, reason) in leftovers {
+            writeln!(
+                plan,
+                "#   {} {}: {}",
+                String::from_utf8_lossy(path),
+                change,
+                reason
+            )?;
+        }
+    }
+    plan.push_str(
+        "\n\
+         # Commands:\n\
+         # f, fixup <commit> <n> = absorb change n into the commit\n\
+         # d, drop <commit> <n> = leave change n in the index\n\
+         #\n\
+         # The commit can be changed to any commit of the stack.\n\
+         # If you remove a line, that change is left in the index.\n",
+    );
+    let path = repo.path().join(EDIT_PLAN_FILE);
+    std::fs::write(&path, plan)?;
+    message::launch_editor(repo, &path)?;
+    let edited = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+
+    let mut targets = vec![None; entries.len()];
+    for (n, commit) in parse(&edited, entries.len())? {
+        targets[n] = match commit {
+            Some(commit) => Some(repo.revparse_single(&commit)?.peel_to_commit()?.id()),
+            None => None,
+        };
+    }
+    Ok(targets)
+}
+
+/// Parses an edited plan of `count` changes into the (0-based) changes
+/// that are kept, and the commit given for each, or None if it was
+/// dropped.
+fn parse(plan: &str, count: usize) -> Result<HashMap<usize, Option<String>>> {
+    let mut changes = HashMap::new();
+    for line in plan.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let (commit, n) = match (words.next(), words.next()) {
+            (Some(commit), Some(n)) => (commit, n),
+            _ => return Err(anyhow!("incomplete line in plan: {}", line)),
+        };
+        let n = n
+            .parse::<usize>()
+            .ok()
+            .filter(|n| (1..=count).contains(n))
+            .ok_or_else(|| anyhow!("unknown change {} in plan", n))?;
+        let commit = match command {
+            "f" | "fixup" => Some(commit.to_owned()),
+            "d" | "drop" => None,
+            _ => return Err(anyhow!("unknown command {} in plan", command)),
+        };
+        if changes.insert(n - 1, commit).is_some() {
+            return Err(anyhow!("change {} is listed more than once in plan", n));
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_edited_plan() {
+        let plan = "\
+fixup 1a2b3c4 2 src/lib.rs -3,0 +4,1 # Add foo
+d 1a2b3c4 1 src/main.rs -1,1 +1,1 # Add foo
+
+# fixup 5d6e7f8 3 README.md -1,0 +1,1
+";
+        let changes = parse(plan, 3).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[&1], Some("1a2b3c4".to_owned()));
+        assert_eq!(changes[&0], None);
+
+        assert!(parse("pick 1a2b3c4 1", 1).is_err());
+        assert!(parse("fixup 1a2b3c4 2", 1).is_err());
+        assert!(parse("fixup 1a2b3c4 1\nfixup 1a2b3c4 1", 1).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! The `--porcelain` output format.
+//!
+//! The format is line-oriented and written to stdout, separately from
+//! the human-readable logs on stderr. The first line is always
+//! `version <n>`, and the format will stay backwards compatible until
+//! the version changes. Each following line starts with a keyword:
+//!
+//! - `absorbed <target> <change> <path>`: a staged change will be
+//!   absorbed into the target commit.
+//! - `fixup <commit> <target>`: a fixup commit was created for the
+//!   target commit. The commit is `-` in dry runs.
+//! - `leftover <reason> <change> <path>`: a staged change could not be
+//!   absorbed, and was left in the index.
+//!
+//! The path is always the last field, and can contain spaces. It is the
+//! path of the file in the index, which for a renamed file is its new
+//! path. Paths with unusual characters are quoted like `git diff` quotes
+//! them, in double quotes and with C-style escapes.
+
+use std::io::{self, Write};
+
+use crate::Reason;
+
+pub const VERSION: u32 = 1;
+
+pub fn version(out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "version {}", VERSION)
+}
+
+pub fn absorbed(
+    out: &mut impl Write,
+    target: git2::Oid,
+    change: &str,
+    path: &[u8],
+) -> io::Result<()> {
+    write!(out, "absorbed {} {} ", target, field(change))?;
+    out.write_all(&quote(path))?;
+    writeln!(out)
+}
+
+pub fn fixup(out: &mut impl Write, commit: Option<git2::Oid>, target: git2::Oid) -> io::Result<()> {
+    match commit {
+        Some(commit) => writeln!(out, "fixup {} {}", commit, target),
+        None => writeln!(out, "fixup - {}", target),
+    }
+}
+
+pub fn leftover(out: &mut impl Write, reason: Reason, change: &str, path: &[u8]) -> io::Result<()> {
+    write!(out, "leftover {} {} ", reason.code(), field(change))?;
+    out.write_all(&quote(path))?;
+    writeln!(out)
+}
+
+/// Makes a description safe to use as a single space-separated field.
+fn field(change: &str) -> String {
+    change.replace(' ', "")
+}
+
+/// Quotes `path` like git does with core.quotePath, if it contains
+/// double quotes, backslashes, control characters or non-ASCII bytes.
+fn quote(path: &[u8]) -> Vec<u8> {
+    let needs_quoting = |&byte: &u8| byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte);
+    if !path.iter().any(needs_quoting) {
+        return path.to_vec();
+    }
+    let mut quoted = vec![b'"'];
+    for &byte in path {
+        match byte {
+            b'\x07' => quoted.extend_from_slice(b"\\a"),
+            b'\x08' => quoted.extend_from_slice(b"\\b"),
+            b'\t' => quoted.extend_from_slice(b"\\t"),
+            b'\n' => quoted.extend_from_slice(b"\\n"),
+            b'\x0b' => quoted.extend_from_slice(b"\\v"),
+            b'\x0c' => quoted.extend_from_slice(b"\\f"),
+            b'\r' => quoted.extend_from_slice(b"\\r"),
+            b'"' => quoted.extend_from_slice(b"\\\""),
+            b'\\' => quoted.extend_from_slice(b"\\\\"),
+            byte if needs_quoting(&byte) => {
+                quoted.extend_from_slice(format!("\\{:03o}", byte).as_bytes())
+            }
+            byte => quoted.push(byte),
+        }
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let target = git2::Oid::from_str("0123456789012345678901234567890123456789").unwrap();
+        let mut out = Vec::new();
+        version(&mut out).unwrap();
+        absorbed(&mut out, target, "-3,0 +4,1", b"dir/some file").unwrap();
+        fixup(&mut out, None, target).unwrap();
+        leftover(&mut out, Reason::StackExhausted, "mode 100755", b"f").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "version 1\n\
+             absorbed 0123456789012345678901234567890123456789 -3,0+4,1 dir/some file\n\
+             fixup - 0123456789012345678901234567890123456789\n\
+             leftover stack-exhausted mode100755 f\n"
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(b"dir/some file"), b"dir/some file");
+        assert_eq!(quote(b"a\"b\\c"), br#""a\"b\\c""#);
+        assert_eq!(quote(b"new\nline\ttab"), br#""new\nline\ttab""#);
+        assert_eq!(quote("caf\u{e9}".as_bytes()), br#""caf\303\251""#);
+        assert_eq!(quote(b"\x7f\xff"), br#""\177\377""#);
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+
+use std::io::Write;
+
+const RESET: &str = "\x1b[m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+
+/// Prints the patch that a fixup commit would contain, in the same
+/// format (and colors) as `git show`.
+pub fn print_fixup(
+    out: &mut impl Write,
+    message: &str,
+    target: &git2::Commit,
+    diff: &git2::Diff,
+    color: bool,
+) -> Result<()> {
+    let paint = |color_code: &'static str| if color { color_code } else { "" };
+    let reset = if color { RESET } else { "" };
+
+    writeln!(out, "{}fixup for {}{}", paint(YELLOW), target.id(), reset)?;
+    for line in message.lines() {
+        writeln!(out, "    {}", line)?;
+    }
+    writeln!(out)?;
+
+    let mut result = Ok(());
+    diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+        let (prefix, color_code) = match line.origin() {
+            '+' => ("+", GREEN),
+            '-' => ("-", RED),
+            ' ' => (" ", ""),
+            'F' => ("", BOLD),
+            'H' => ("", CYAN),
+            _ => ("", ""),
+        };
+        let content = String::from_utf8_lossy(line.content());
+        result = write!(
+            out,
+            "{}{}{}{}",
+            paint(color_code),
+            prefix,
+            content.trim_end_matches('\n'),
+            if color_code.is_empty() { "" } else { reset }
+        )
+        .and_then(|_| writeln!(out));
+        result.is_ok()
+    })?;
+    result?;
+    writeln!(out)?;
+    Ok(())
+}
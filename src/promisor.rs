@@ -0,0 +1,83 @@
+//! Partial clones (`git clone --filter`) leave out objects, usually
+//! blobs, that git fetches from a promisor remote when it needs them.
+//! libgit2 can't do that, so they are fetched up front with git.
+
+use anyhow::{anyhow, Result};
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The remote that missing objects can be fetched from, if this is a
+/// partial clone.
+pub fn remote(repo: &git2::Repository) -> Option<String> {
+    let config = repo.config().ok()?;
+    if let Ok(remote) = config.get_string("extensions.partialClone") {
+        return Some(remote);
+    }
+    let remotes = repo.remotes().ok()?;
+    let remote = remotes.iter().flatten().find(|name| {
+        config
+            .get_bool(&format!("remote.{}.promisor", name))
+            .unwrap_or(false)
+    });
+    remote.map(str::to_owned)
+}
+
+/// Adds the blobs on either side of `diff` that aren't in the
+/// repository to `missing`.
+pub fn missing_blobs(
+    repo: &git2::Repository,
+    diff: &git2::Diff,
+    missing: &mut BTreeSet<git2::Oid>,
+) -> Result<()> {
+    let odb = repo.odb()?;
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            let is_blob = matches!(
+                file.mode(),
+                git2::FileMode::Blob | git2::FileMode::BlobExecutable | git2::FileMode::Link
+            );
+            if is_blob && !file.id().is_zero() && !odb.exists(file.id()) {
+                missing.insert(file.id());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetches `ids` from `remote` in one go, the same way git does when it
+/// needs them.
+pub fn fetch(repo: &git2::Repository, remote: &str, ids: &BTreeSet<git2::Oid>) -> Result<()> {
+    let mut child = Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args([
+            "-c",
+            "fetch.negotiationAlgorithm=noop",
+            "fetch",
+            remote,
+            "--no-tags",
+            "--no-write-fetch-head",
+            "--recurse-submodules=no",
+            "--filter=blob:none",
+            "--quiet",
+            "--stdin",
+        ])
+        .current_dir(repo.workdir().unwrap_or(repo.path()))
+        .stdin(Stdio::piped())
+        .spawn()?;
+    {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        for id in ids {
+            writeln!(stdin, "{}", id)?;
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!("git fetch exited with {}", status));
+    }
+    // so that libgit2 sees the new pack
+    repo.odb()?.refresh()?;
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// Finds the remote-tracking branches (eg `origin/main`) that contain
+/// each of `commits`. Commits that no remote-tracking branch contains
+/// are left out.
+pub fn branches_containing(
+    repo: &git2::Repository,
+    commits: &[git2::Oid],
+) -> Result<BTreeMap<git2::Oid, Vec<String>>> {
+    let mut ret: BTreeMap<git2::Oid, Vec<String>> = BTreeMap::new();
+    for branch in repo.branches(Some(git2::BranchType::Remote))? {
+        let (branch, _) = branch?;
+        let tip = match branch.get().target() {
+            Some(tip) => tip,
+            // eg origin/HEAD, which points at another branch
+            None => continue,
+        };
+        let name = String::from_utf8_lossy(branch.name_bytes()?).into_owned();
+        for &commit in commits {
+            if tip == commit || repo.graph_descendant_of(tip, commit)? {
+                ret.entry(commit).or_default().push(name.clone());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// The branch that HEAD's branch is pushed to.
+pub struct Upstream {
+    pub remote: String,
+    /// The branch's name on the remote, eg `refs/heads/main`.
+    pub merge: String,
+    /// What the remote-tracking branch points at, ie the last known
+    /// state of the branch on the remote, if it was ever fetched.
+    pub tracking: Option<git2::Oid>,
+}
+
+/// Finds where HEAD's branch is pushed to, like `@{push}` does: the
+/// push remote, and the branch there that `push.default` picks.
+///
+/// Returns `None` if the branch isn't pushed anywhere, and an error if
+/// it would be pushed to a branch with a different name, which git
+/// refuses to do as well.
+pub fn upstream(repo: &git2::Repository) -> Result<Option<Upstream>> {
+    let head = repo.head()?;
+    let shorthand = match head.shorthand() {
+        Some(shorthand) if head.is_branch() => shorthand,
+        _ => return Ok(None),
+    };
+    let config = repo.config()?;
+    let branch_remote = config
+        .get_string(&format!("branch.{}.remote", shorthand))
+        .ok();
+    let remote = match config
+        .get_string(&format!("branch.{}.pushRemote", shorthand))
+        .or_else(|_| config.get_string("remote.pushDefault"))
+        .ok()
+        .or_else(|| branch_remote.clone())
+    {
+        Some(remote) if remote != "." => remote,
+        _ => return Ok(None),
+    };
+    let current = format!("refs/heads/{}", shorthand);
+    let push_default = config
+        .get_string("push.default")
+        .unwrap_or_else(|_| "simple".to_owned());
+    // pushing to another remote than the one the branch is fetched
+    // from always goes to the branch of the same name
+    let triangular = branch_remote.as_deref() != Some(remote.as_str());
+    let merge = match push_default.as_str() {
+        "nothing" => return Ok(None),
+        "current" | "matching" => current,
+        "simple" if triangular => current,
+        "upstream" | "tracking" if triangular => {
+            return Err(anyhow!(
+                "{} is pushed to {}, which is not the remote it tracks",
+                shorthand,
+                remote
+            ))
+        }
+        "upstream" | "tracking" | "simple" => {
+            let merge = match config.get_string(&format!("branch.{}.merge", shorthand)) {
+                Ok(merge) => merge,
+                Err(_) => return Ok(None),
+            };
+            if merge != current {
+                return Err(anyhow!(
+                    "{} is pushed to {} on {}, which has a different name, \
+                     set push.default = current or push it yourself",
+                    shorthand,
+                    merge,
+                    remote
+                ));
+            }
+            merge
+        }
+        other => return Err(anyhow!("push.default = {} is not supported", other)),
+    };
+    let tracking = merge.strip_prefix("refs/heads/").and_then(|name| {
+        repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, name))
+            .ok()
+    });
+    Ok(Some(Upstream {
+        remote,
+        merge,
+        tracking,
+    }))
+}
+
+/// Force-pushes HEAD to `upstream`, unless the branch on the remote has
+/// changed since it was last fetched and integrated.
+pub fn force_push(repo: &git2::Repository, upstream: &Upstream, quiet: bool) -> Result<()> {
+    let mut command = Command::new("git");
+    if let Some(workdir) = repo.workdir() {
+        command.arg("-C").arg(workdir).current_dir(workdir);
+    }
+    // without a remote-tracking branch, the lease expects the branch
+    // not to exist on the remote
+    let expected = upstream
+        .tracking
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+    command
+        .arg("push")
+        .arg(format!(
+            "--force-with-lease={}:{}",
+            upstream.merge, expected
+        ))
+        .arg("--force-if-includes");
+    if quiet {
+        command.arg("--quiet");
+    }
+    command
+        .arg(&upstream.remote)
+        .arg(format!("HEAD:{}", upstream.merge));
+    let status = command.status()?;
+    if !status.success() {
+        return Err(anyhow!("git push exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branches_containing() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("nobody", "nobody@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let first = repo
+            .commit(Some("HEAD"), &sig, &sig, "first", &tree, &[])
+            .unwrap();
+        let second = repo
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                "second",
+                &tree,
+                &[&repo.find_commit(first).unwrap()],
+            )
+            .unwrap();
+        repo.reference("refs/remotes/origin/main", first, false, "")
+            .unwrap();
+        repo.reference("refs/remotes/origin/feature", second, false, "")
+            .unwrap();
+
+        let branches = branches_containing(&repo, &[first, second]).unwrap();
+        assert_eq!(branches[&first], vec!["origin/feature", "origin/main"]);
+        assert_eq!(branches[&second], vec!["origin/feature"]);
+    }
+
+    #[test]
+    fn test_upstream_follows_push_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("nobody", "nobody@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        repo.set_head("refs/heads/feature").unwrap();
+        let head = repo
+            .commit(Some("HEAD"), &sig, &sig, "first", &tree, &[])
+            .unwrap();
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        repo.reference("refs/remotes/origin/main", head, false, "")
+            .unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("branch.feature.remote", "origin").unwrap();
+        config
+            .set_str("branch.feature.merge", "refs/heads/main")
+            .unwrap();
+
+        // a feature branch that tracks main isn't pushed over it
+        assert!(upstream(&repo).is_err());
+        config.set_str("push.default", "upstream").unwrap();
+        assert!(upstream(&repo).is_err());
+
+        config.set_str("push.default", "current").unwrap();
+        let target = upstream(&repo).unwrap().unwrap();
+        assert_eq!(target.remote, "origin");
+        assert_eq!(target.merge, "refs/heads/feature");
+        assert_eq!(target.tracking, None);
+
+        // pushing to a fork goes to the branch of the same name
+        config.remove("push.default").unwrap();
+        repo.remote("fork", "https://example.com/fork.git").unwrap();
+        repo.reference("refs/remotes/fork/feature", head, false, "")
+            .unwrap();
+        config.set_str("remote.pushDefault", "fork").unwrap();
+        let target = upstream(&repo).unwrap().unwrap();
+        assert_eq!(target.remote, "fork");
+        assert_eq!(target.merge, "refs/heads/feature");
+        assert_eq!(target.tracking, Some(head));
+
+        config.set_str("push.default", "nothing").unwrap();
+        assert!(upstream(&repo).unwrap().is_none());
+    }
+}
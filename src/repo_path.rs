@@ -0,0 +1,55 @@
+//! Conversions from the byte paths that git stores to `Path`s.
+//!
+//! Git paths are arbitrary bytes separated by `/`. On unix, every byte
+//! path is also a valid `Path`. Elsewhere, paths must be utf8, which is
+//! also what git for Windows requires. libgit2 accepts `/` as the
+//! separator on every platform.
+
+use std::path::Path;
+
+/// Converts a path from a tree or the index to a `Path`, or returns
+/// `None` if the platform cannot represent it.
+pub fn to_path(path: &[u8]) -> Option<&Path> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(Path::new(std::ffi::OsStr::from_bytes(path)))
+    }
+    #[cfg(not(unix))]
+    {
+        std::str::from_utf8(path).ok().map(Path::new)
+    }
+}
+
+/// Converts a path relative to the root of the repository back into a
+/// git path.
+pub fn from_path(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().replace('\\', "/").into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_path() {
+        assert_eq!(to_path(b"dir/file.txt"), Some(Path::new("dir/file.txt")));
+        #[cfg(unix)]
+        assert!(to_path(b"caf\xe9.txt").is_some());
+        #[cfg(not(unix))]
+        assert!(to_path(b"caf\xe9.txt").is_none());
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(from_path(Path::new("dir/file.txt")), b"dir/file.txt");
+    }
+}
@@ -0,0 +1,36 @@
+/// Receives events as git-absorb runs, so that frontends can show
+/// progress and collect warnings without parsing logs. Every method
+/// does nothing by default.
+pub trait Reporter {
+    /// A commit of the stack was parsed.
+    fn on_commit_scanned(&self, _commit: git2::Oid) {}
+
+    /// A staged change was planned to be absorbed into `target`, or to
+    /// be left in the index if `target` is None. `change` is a hunk
+    /// header (eg `-3,0 +4,1`) or a mode change, and `reason` explains
+    /// the decision.
+    fn on_hunk_planned(
+        &self,
+        _path: &[u8],
+        _change: &str,
+        _target: Option<git2::Oid>,
+        _reason: &str,
+    ) {
+    }
+
+    /// A fixup commit was created for `target`. `fixup` is None in dry
+    /// runs.
+    fn on_fixup_committed(&self, _fixup: Option<git2::Oid>, _target: git2::Oid) {}
+
+    /// Something may need the user's attention.
+    fn on_warning(&self, _message: &str) {}
+
+    /// All fixups were created, and the summary and any rebase are
+    /// about to be run.
+    fn on_finished(&self) {}
+}
+
+/// The reporter used when none is configured.
+pub(crate) struct NoReporter;
+
+impl Reporter for NoReporter {}
@@ -0,0 +1,257 @@
+use anyhow::Result;
+
+use std::io::{BufRead, Write};
+
+use crate::message;
+
+/// The file that hunks are edited in, like git add -p's `ADD_EDIT.patch`.
+const EDIT_HUNK_FILE: &str = "ABSORB_EDIT.patch";
+
+/// A staged hunk, and the commit it is about to be absorbed into.
+pub struct Proposal<'a> {
+    pub path: &'a [u8],
+    /// The hunk header, eg `-3,0 +4,1`.
+    pub header: String,
+    pub removed: &'a [Vec<u8>],
+    pub added: &'a [Vec<u8>],
+    pub target: &'a git2::Commit<'a>,
+    /// Why the hunk belongs in the target.
+    pub reason: String,
+}
+
+/// What to do with a proposed hunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    Absorb,
+    /// Leave the hunk in the index.
+    Skip,
+    /// Absorb the hunk with these added lines instead.
+    Edit(Vec<Vec<u8>>),
+    /// Leave this hunk and all of the remaining ones in the index.
+    Quit,
+}
+
+/// Decides, hunk by hunk, which staged changes are absorbed, for
+/// --patch.
+pub trait Selector {
+    fn select(&self, repo: &git2::Repository, proposal: &Proposal) -> std::io::Result<Selection>;
+}
+
+/// Asks about every hunk on the terminal, like `git add -p`.
+pub struct Prompt;
+
+impl Selector for Prompt {
+    fn select(&self, repo: &git2::Repository, proposal: &Proposal) -> std::io::Result<Selection> {
+        let mut stderr = std::io::stderr();
+        let mut stdin = std::io::stdin().lock();
+        loop {
+            writeln!(
+                stderr,
+                "{}\n@@ {} @@",
+                String::from_utf8_lossy(proposal.path),
+                proposal.header
+            )?;
+            write_lines(&mut stderr, proposal)?;
+            write!(
+                stderr,
+                "Absorb this hunk into {} ({})? [y,n,e,q,?] ",
+                describe(proposal.target),
+                proposal.reason,
+            )?;
+            stderr.flush()?;
+
+            let mut answer = String::new();
+            if stdin.read_line(&mut answer)? == 0 {
+                // like git add -p, running out of input ends the selection
+                return Ok(Selection::Quit);
+            }
+            match answer.trim() {
+                "y" => return Ok(Selection::Absorb),
+                "n" => return Ok(Selection::Skip),
+                "q" => return Ok(Selection::Quit),
+                "e" => match edit(repo, proposal) {
+                    Ok(Some(added)) => return Ok(Selection::Edit(added)),
+                    Ok(None) => writeln!(
+                        stderr,
+                        "The edited hunk must remove the same lines, try again."
+                    )?,
+                    Err(e) => writeln!(stderr, "Could not edit the hunk: {}", e)?,
+                },
+                _ => writeln!(
+                    stderr,
+                    "y - absorb this hunk\n\
+                     n - leave this hunk in the index\n\
+                     e - manually edit the lines this hunk adds\n\
+                     q - leave this hunk and all remaining ones in the index\n\
+                     ? - print help"
+                )?,
+            }
+        }
+    }
+}
+
+/// Answers yes/no questions, for --confirm and the offer to stage
+/// everything when nothing is staged.
+pub trait Ask {
+    fn ask(&self, question: &str) -> std::io::Result<bool>;
+
+    /// Shows `plan`, one line for each staged change and where it would
+    /// go, before asking `question` about it.
+    fn ask_with_plan(&self, plan: &[String], question: &str) -> std::io::Result<bool>;
+}
+
+impl Ask for Prompt {
+    fn ask(&self, question: &str) -> std::io::Result<bool> {
+        ask(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stderr(),
+            question,
+        )
+    }
+
+    fn ask_with_plan(&self, plan: &[String], question: &str) -> std::io::Result<bool> {
+        let mut stderr = std::io::stderr();
+        for line in plan {
+            writeln!(stderr, "{}", line)?;
+        }
+        self.ask(question)
+    }
+}
+
+/// Asks a yes/no `question`. Anything but yes, including no answer, is
+/// a no.
+pub fn ask(
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+    question: &str,
+) -> std::io::Result<bool> {
+    write!(out, "{} [y/N] ", question)?;
+    out.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// The abbreviated id and summary of `commit`, eg `1a2b3c4 Add foo`.
+pub fn describe(commit: &git2::Commit) -> String {
+    format!("{} {}", short_id(commit), commit.summary().unwrap_or(""))
+}
+
+/// The abbreviated id of `commit`.
+pub fn short_id(commit: &git2::Commit) -> String {
+    commit
+        .as_object()
+        .short_id()
+        .ok()
+        .and_then(|id| id.as_str().map(str::to_owned))
+        .unwrap_or_else(|| commit.id().to_string())
+}
+
+/// Writes the removed and added lines of the hunk, with a newline
+/// after lines that don't have one.
+fn write_lines(out: &mut dyn Write, proposal: &Proposal) -> std::io::Result<()> {
+    for (prefix, lines) in [(b'-', proposal.removed), (b'+', proposal.added)] {
+        for line in lines {
+            out.write_all(&[prefix])?;
+            out.write_all(line)?;
+            if !line.ends_with(b"\n") {
+                out.write_all(b"\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Opens the hunk in the user's editor, and returns its new added
+/// lines. None if the removed lines were changed, since the hunk then
+/// no longer applies.
+fn edit(repo: &git2::Repository, proposal: &Proposal) -> Result<Option<Vec<Vec<u8>>>> {
+    let path = repo.path().join(EDIT_HUNK_FILE);
+    let mut content = format!("# Manual hunk edit mode\n@@ {} @@\n", proposal.header).into_bytes();
+    write_lines(&mut content, proposal)?;
+    content.extend_from_slice(
+        b"# ---\n\
+          # To leave a '+' line out of the fixup, delete it.\n\
+          # To add a line to the fixup, add a '+' line.\n\
+          # The '-' lines must be left as they are.\n\
+          # Lines starting with # will be removed.\n",
+    );
+    std::fs::write(&path, content)?;
+    message::launch_editor(repo, &path)?;
+    let edited = std::fs::read(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(parse_edited(&edited, proposal.removed, proposal.added))
+}
+
+/// Parses an edited hunk into its added lines, or None if its removed
+/// lines don't match `removed`.
+fn parse_edited(edited: &[u8], removed: &[Vec<u8>], added: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+    let mut new_removed = Vec::new();
+    let mut new_added = Vec::new();
+    for line in edited.split_inclusive(|&byte| byte == b'\n') {
+        match line.first() {
+            Some(b'-') => new_removed.push(&line[1..]),
+            Some(b'+') => new_added.push(line[1..].to_vec()),
+            _ => {}
+        }
+    }
+    // lines without a newline at the end of the file were given one
+    // for editing
+    let trim = |line: &[u8]| line.strip_suffix(b"\n").unwrap_or(line).to_vec();
+    let removed_matches = new_removed.len() == removed.len()
+        && new_removed
+            .iter()
+            .zip(removed)
+            .all(|(new, old)| trim(new) == trim(old));
+    if !removed_matches {
+        return None;
+    }
+    if added.last().is_some_and(|line| !line.ends_with(b"\n")) {
+        if let Some(last) = new_added.last_mut() {
+            if last.ends_with(b"\n") {
+                last.pop();
+            }
+        }
+    }
+    Some(new_added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<Vec<u8>> {
+        lines.iter().map(|line| line.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn asks_for_yes() {
+        let mut out = Vec::new();
+        assert!(ask(&mut &b"y\n"[..], &mut out, "Continue?").unwrap());
+        assert_eq!(out, b"Continue? [y/N] ");
+        assert!(!ask(&mut &b"n\n"[..], &mut Vec::new(), "Continue?").unwrap());
+        assert!(!ask(&mut &b"\n"[..], &mut Vec::new(), "Continue?").unwrap());
+        assert!(!ask(&mut &b""[..], &mut Vec::new(), "Continue?").unwrap());
+    }
+
+    #[test]
+    fn parses_edited_hunk() {
+        let removed = lines(&["old\n"]);
+        let added = lines(&["new\n", "other\n"]);
+        let edited = b"# comment\n@@ -1,1 +1,2 @@\n-old\n+new\n+added\n# ---\n";
+        assert_eq!(
+            parse_edited(edited, &removed, &added),
+            Some(lines(&["new\n", "added\n"]))
+        );
+        // the removed lines can't be changed
+        let edited = b"@@ -1,1 +1,2 @@\n-changed\n+new\n";
+        assert_eq!(parse_edited(edited, &removed, &added), None);
+        // a missing newline at the end of the file is kept
+        let added = lines(&["new"]);
+        let edited = b"-old\n+newer\n";
+        assert_eq!(
+            parse_edited(edited, &removed, &added),
+            Some(lines(&["newer"]))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+/// Signs commits the same way `git commit -S` would, using the
+/// signing program and key from the git config.
+#[derive(Debug)]
+pub struct Signer {
+    format: Format,
+    program: String,
+    key: String,
+}
+
+impl Signer {
+    /// Returns a signer if `commit.gpgSign` is enabled, or `None` if
+    /// commits should not be signed.
+    pub fn from_config(
+        repo: &git2::Repository,
+        committer: &git2::Signature,
+    ) -> Result<Option<Self>> {
+        let config = repo.config()?;
+        if !config.get_bool("commit.gpgSign").unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let format = match config.get_string("gpg.format").ok().as_deref() {
+            None | Some("openpgp") => Format::OpenPgp,
+            Some("x509") => Format::X509,
+            Some("ssh") => Format::Ssh,
+            Some(other) => return Err(anyhow!("unsupported gpg.format {:?}", other)),
+        };
+        let program = match format {
+            Format::OpenPgp => config
+                .get_string("gpg.openpgp.program")
+                .or_else(|_| config.get_string("gpg.program"))
+                .unwrap_or_else(|_| "gpg".to_owned()),
+            Format::X509 => config
+                .get_string("gpg.x509.program")
+                .unwrap_or_else(|_| "gpgsm".to_owned()),
+            Format::Ssh => config
+                .get_string("gpg.ssh.program")
+                .unwrap_or_else(|_| "ssh-keygen".to_owned()),
+        };
+        let key = match (config.get_string("user.signingKey"), format) {
+            (Ok(key), _) => key,
+            (Err(_), Format::Ssh) => {
+                return Err(anyhow!(
+                    "commit.gpgSign is set with gpg.format=ssh, but user.signingKey is not"
+                ))
+            }
+            // like git, fall back to the committer identity as the key id
+            (Err(_), _) => format!(
+                "{} <{}>",
+                committer.name().unwrap_or(""),
+                committer.email().unwrap_or("")
+            ),
+        };
+
+        Ok(Some(Signer {
+            format,
+            program,
+            key,
+        }))
+    }
+
+    /// Creates a signed commit, without updating any references.
+    pub fn commit(
+        &self,
+        repo: &git2::Repository,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<git2::Oid> {
+        let buffer = repo.commit_create_buffer(author, committer, message, tree, parents)?;
+        let buffer = buffer
+            .as_str()
+            .ok_or_else(|| anyhow!("commit buffer was not valid utf8"))?;
+        let signature = self.sign(buffer)?;
+        Ok(repo.commit_signed(buffer, &signature, None)?)
+    }
+
+    fn sign(&self, buffer: &str) -> Result<String> {
+        let mut command = Command::new(&self.program);
+        // keeps a temporary copy of a literal ssh key alive until the
+        // signing program has exited
+        let mut literal_key_file = None;
+        match self.format {
+            Format::OpenPgp | Format::X509 => {
+                command.args(["--status-fd=2", "-bsau", &self.key]);
+            }
+            Format::Ssh => {
+                command.args(["-Y", "sign", "-n", "git", "-f"]);
+                // like git, accept either a path to a key file or
+                // the public key itself (whose private half must then
+                // be available from ssh-agent)
+                let literal_key = match self.key.strip_prefix("key::") {
+                    Some(literal) => Some(literal),
+                    None if self.key.starts_with("ssh-") => Some(self.key.as_str()),
+                    None => None,
+                };
+                match literal_key {
+                    Some(literal) => {
+                        let mut file = tempfile::Builder::new()
+                            .prefix("git-absorb-signing-key-")
+                            .suffix(".pub")
+                            .tempfile()?;
+                        file.write_all(literal.as_bytes())?;
+                        command.arg(file.path()).arg("-U");
+                        literal_key_file = Some(file);
+                    }
+                    None => {
+                        command.arg(&self.key);
+                    }
+                }
+            }
+        }
+
+        let result = run_signing_program(command, buffer);
+        drop(literal_key_file);
+        result
+    }
+}
+
+fn run_signing_program(mut command: Command, buffer: &str) -> Result<String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("could not run signing program {:?}: {}", command, e))?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("signing program has no stdin"))?
+        .write_all(buffer.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "signing program failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let signature = String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("signing program produced a non-utf8 signature"))?;
+    if signature.trim().is_empty() {
+        return Err(anyhow!("signing program produced an empty signature"));
+    }
+    Ok(signature)
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+
+use std::collections::hash_map::{Entry, HashMap};
+
+/// A fixup commit, as the trees it changes and the commit that it will
+/// be squashed into.
+pub struct Fixup<'r> {
+    pub target: git2::Oid,
+    pub parent_tree: git2::Tree<'r>,
+    pub tree: git2::Tree<'r>,
+}
+
+/// How squashing the fixups into the stack would end.
+pub enum Squashed {
+    /// Every commit and fixup applied cleanly, with this final tree.
+    Tree(git2::Oid),
+    /// Picking this commit, or a fixup for it, would conflict in these
+    /// paths.
+    Conflict {
+        commit: git2::Oid,
+        fixup: bool,
+        paths: Vec<Vec<u8>>,
+    },
+}
+
+/// Simulates `git rebase --autosquash` of `stack` (newest first), with
+/// each of `fixups` picked right after its target, by merging trees like
+/// the rebase's cherry-picks do. Nothing is written but trees.
+///
+/// Returns `None` if the stack has merge commits, which the rebase would
+/// not recreate the same way.
+pub fn simulate<'r>(
+    repo: &'r git2::Repository,
+    stack: &[&git2::Commit<'r>],
+    fixups: &[Fixup<'r>],
+) -> Result<Option<Squashed>> {
+    if stack.iter().any(|commit| commit.parent_count() > 1) {
+        return Ok(None);
+    }
+    let parent_tree = |commit: &git2::Commit<'r>| -> Result<git2::Tree<'r>> {
+        Ok(match commit.parent_count() {
+            0 => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+            _ => commit.parent(0)?.tree()?,
+        })
+    };
+    let mut current = match stack.last() {
+        Some(oldest) => parent_tree(oldest)?,
+        None => return Ok(None),
+    };
+    for commit in stack.iter().rev() {
+        let picks = std::iter::once((parent_tree(commit)?, commit.tree()?, false)).chain(
+            fixups
+                .iter()
+                .filter(|fixup| fixup.target == commit.id())
+                .map(|fixup| (fixup.parent_tree.clone(), fixup.tree.clone(), true)),
+        );
+        for (ancestor, theirs, fixup) in picks {
+            current = match pick(repo, &ancestor, &current, &theirs)? {
+                Ok(tree) => tree,
+                Err(paths) => {
+                    return Ok(Some(Squashed::Conflict {
+                        commit: commit.id(),
+                        fixup,
+                        paths,
+                    }))
+                }
+            };
+        }
+    }
+    Ok(Some(Squashed::Tree(current.id())))
+}
+
+/// Applies the changes from `ancestor` to `theirs` onto `ours`, or
+/// returns the paths that conflict.
+fn pick<'r>(
+    repo: &'r git2::Repository,
+    ancestor: &git2::Tree,
+    ours: &git2::Tree<'r>,
+    theirs: &git2::Tree<'r>,
+) -> Result<std::result::Result<git2::Tree<'r>, Vec<Vec<u8>>>> {
+    // nothing to merge if nothing else changed since
+    if ancestor.id() == ours.id() {
+        return Ok(Ok(theirs.clone()));
+    }
+    let mut index = repo.merge_trees(ancestor, ours, theirs, None)?;
+    if index.has_conflicts() {
+        let mut paths = Vec::new();
+        for conflict in index.conflicts()? {
+            let conflict = conflict?;
+            let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+            paths.extend(entry.map(|entry| entry.path));
+        }
+        return Ok(Err(paths));
+    }
+    Ok(Ok(repo.find_tree(index.write_tree_to(repo)?)?))
+}
+
+/// Picks each of `fixups` onto its target, after the earlier fixups for
+/// the same target, like the rebase will right after picking the target,
+/// and returns the targets whose fixups would conflict, with the
+/// conflicting paths.
+pub fn conflicts<'r>(
+    repo: &'r git2::Repository,
+    fixups: &[Fixup<'r>],
+) -> Result<Vec<(git2::Oid, Vec<Vec<u8>>)>> {
+    let mut conflicts = Vec::new();
+    // each target's tree with its fixups so far squashed in, or None
+    // once one of them conflicted, which would stop the rebase there
+    let mut squashed: HashMap<git2::Oid, Option<git2::Tree<'r>>> = HashMap::new();
+    for fixup in fixups {
+        let current = match squashed.entry(fixup.target) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Some(repo.find_commit(fixup.target)?.tree()?)),
+        };
+        let tree = match current {
+            Some(tree) => tree.clone(),
+            None => continue,
+        };
+        match pick(repo, &fixup.parent_tree, &tree, &fixup.tree)? {
+            Ok(tree) => *current = Some(tree),
+            Err(paths) => {
+                conflicts.push((fixup.target, paths));
+                *current = None;
+            }
+        }
+    }
+    Ok(conflicts)
+}
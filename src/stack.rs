@@ -1,101 +1,583 @@
 use anyhow::{anyhow, Result};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::config;
+use crate::{config, Error, Reporter};
+
+/// What to walk for the stack of commits that can be fixed up.
+#[derive(Clone, Copy)]
+pub struct StackOptions<'a> {
+    /// The commit the stack starts after, if the user gave one.
+    pub base: Option<&'a str>,
+    /// Commit-ishes and ref globs whose history is left out.
+    pub not: &'a [String],
+    /// The most commits to walk without a base.
+    pub max_stack: usize,
+    /// Whether to walk past commits by other authors, or from a
+    /// detached HEAD.
+    pub force: bool,
+    /// Whether to walk past merge commits.
+    pub cross_merges: bool,
+    /// Whether branches stacked below HEAD are part of the stack.
+    pub update_refs: bool,
+    /// Whether HEAD itself is left out, for --from.
+    pub skip_head: bool,
+}
 
 pub fn working_stack<'repo>(
     repo: &'repo git2::Repository,
-    user_provided_base: Option<&str>,
-    force: bool,
-    logger: &slog::Logger,
+    options: &StackOptions,
+    reporter: &dyn Reporter,
 ) -> Result<Vec<git2::Commit<'repo>>> {
+    let StackOptions {
+        base: user_provided_base,
+        not,
+        max_stack,
+        force,
+        cross_merges,
+        update_refs,
+        skip_head,
+    } = *options;
     let head = repo.head()?;
-    debug!(logger, "head found"; "head" => head.name());
+    debug!(head = ?head.name(), "head found");
 
     if !head.is_branch() {
         if !force {
-            return Err(anyhow!("HEAD is not a branch, use --force to override"));
+            return Err(Error::DetachedHead.into());
         } else {
-            warn!(
-                logger,
-                "HEAD is not a branch, but --force used to continue."
-            );
+            warn!("HEAD is not a branch, but --force used to continue.");
+            reporter.on_warning("HEAD is not a branch, but --force used to continue");
         }
     }
 
+    // the first-parent walk is linear, so it doesn't need to be sorted,
+    // and can stream commits without first walking the whole history
     let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
-    revwalk.push_head()?;
+    match head.target() {
+        // with --from, HEAD is what is being absorbed, not part of the
+        // stack
+        Some(target) if skip_head => {
+            for parent in repo.find_commit(target)?.parent_ids().take(1) {
+                revwalk.push(parent)?;
+            }
+        }
+        _ => revwalk.push_head()?,
+    }
     revwalk.simplify_first_parent()?;
-    debug!(logger, "head pushed"; "head" => head.name());
+    debug!(head = ?head.name(), "head pushed");
 
     let base_commit = match user_provided_base {
-        // https://github.com/rust-lang/rfcs/issues/1815
-        // user_provided_base isn't guaranteed to be a commit hash, so peel until a
-        // commit is found.
-        Some(commitish) => Some(repo.revparse_single(commitish)?.peel_to_commit()?),
+        Some(commitish) => Some(resolve_base(repo, commitish)?),
         None => None,
     };
 
     if let Some(base_commit) = base_commit {
         revwalk.hide(base_commit.id())?;
-        debug!(logger, "commit hidden"; "commit" => base_commit.id().to_string());
+        debug!(commit = %base_commit.id(), "commit hidden");
     } else {
+        // without a base, the stack can't go past the first max_stack + 1
+        // first-parent commits, so a branch that doesn't contain the
+        // oldest of them can't hide any of them. skipping such branches
+        // avoids walking their history, which is slow in repositories
+        // with many branches
+        let oldest_candidate = match head.target() {
+            Some(head) => {
+                let mut commit = repo.find_commit(head)?;
+                for _ in 0..max_stack {
+                    match commit.parents().next() {
+                        Some(parent) => commit = parent,
+                        None => break,
+                    }
+                }
+                Some(commit.id())
+            }
+            None => None,
+        };
+        // with --update-refs, branches stacked between the base and HEAD
+        // are rewritten along with it, so they are part of the stack. the
+        // base itself still bounds the walk
+        let stack_base = match update_refs {
+            true => stack_base(repo, &head)?,
+            false => None,
+        };
+        if let Some((base, _)) = &stack_base {
+            revwalk.hide(*base)?;
+            debug!(commit = %base, "stack base hidden");
+        }
         for branch in repo.branches(Some(git2::BranchType::Local))? {
             let (branch, _) = branch?;
             let branch = branch.get().name();
 
+            let stacked = match (
+                &stack_base,
+                branch.and_then(|name| repo.refname_to_id(name).ok()),
+                head.target(),
+            ) {
+                (Some((base, base_branches)), Some(branch_id), Some(head)) => {
+                    !branch.is_some_and(|name| base_branches.iter().any(|base| base == name))
+                        && branch_id != *base
+                        && repo.graph_descendant_of(branch_id, *base)?
+                        && (branch_id == head || repo.graph_descendant_of(head, branch_id)?)
+                }
+                _ => false,
+            };
+
             match branch {
+                Some(name) if stacked => {
+                    debug!(branch = %name, "stacked branch not hidden");
+                }
                 Some(name) if Some(name) != head.name() => {
-                    revwalk.hide_ref(name)?;
-                    debug!(logger, "branch hidden"; "branch" => branch);
+                    if can_hide(repo, repo.refname_to_id(name).ok(), oldest_candidate)? {
+                        revwalk.hide_ref(name)?;
+                        debug!(?branch, "branch hidden");
+                    } else {
+                        debug!(?branch, "branch does not contain the stack, not hidden");
+                    }
                 }
                 _ => {
-                    debug!(logger, "branch not hidden"; "branch" => branch);
+                    debug!(?branch, "branch not hidden");
                 }
             };
         }
+
+        if config::auto_detect_base(repo) {
+            match upstream_commit(repo, &head)? {
+                Some(upstream) => {
+                    // hiding the upstream also hides everything below
+                    // its merge-base with HEAD
+                    revwalk.hide(upstream)?;
+                    debug!(commit = %upstream, "upstream hidden");
+                }
+                None => {
+                    debug!("no upstream or push branch found to hide");
+                }
+            }
+        }
+    }
+
+    for spec in not {
+        hide_not(repo, &mut revwalk, spec)?;
     }
 
     let mut ret = Vec::new();
     let mut commits_considered = 0usize;
+    let mut limit_reached = false;
     let sig = repo.signature();
+    let author_match = config::author_match(repo);
+    let allowed_authors = config::allowed_authors(repo);
+    let stop_at_signed_commits = config::stop_at_signed_commits(repo);
+    let shallow = shallow_commits(repo)?;
     for rev in revwalk {
         commits_considered += 1;
         let commit = repo.find_commit(rev?)?;
-        if commit.parents().len() > 1 {
-            warn!(logger, "Will not fix up past the merge commit"; "commit" => commit.id().to_string());
+        // the parents of these commits aren't in the clone, so what they
+        // changed can't be worked out
+        if shallow.contains(&commit.id()) {
+            warn!(
+                commit = %commit.id(),
+                "Will not fix up past the shallow clone's boundary, deepen it with \
+                 git fetch --deepen if more history is needed",
+            );
+            reporter.on_warning(&format!(
+                "Will not fix up past the shallow clone's boundary at {}",
+                commit.id()
+            ));
             break;
         }
+        if commit.parents().len() > 1 {
+            if !cross_merges {
+                warn!(commit = %commit.id(), "Will not fix up past the merge commit");
+                reporter.on_warning(&format!(
+                    "Will not fix up past the merge commit {}",
+                    commit.id()
+                ));
+                break;
+            }
+            // merges are kept in the stack so that changes can't commute
+            // past what they brought in, but are never fixed up
+            debug!(commit = %commit.id(), "crossing merge commit");
+        }
         if let Ok(ref sig) = sig {
-            if !force
-                && (commit.author().name_bytes() != sig.name_bytes()
-                    || commit.author().email_bytes() != sig.email_bytes())
-            {
-                warn!(logger, "Will not fix up past commits not authored by you, use --force to override";
-                      "commit" => commit.id().to_string());
+            if !force && !is_allowed_author(&commit.author(), sig, author_match, &allowed_authors) {
+                warn!(
+                    commit = %commit.id(),
+                    "Will not fix up past commits not authored by you, use --force to override",
+                );
+                reporter.on_warning(&format!(
+                    "Will not fix up past commit {}, which was not authored by you",
+                    commit.id()
+                ));
                 break;
             }
         }
-        if ret.len() == config::max_stack(repo) && user_provided_base.is_none() {
-            warn!(logger, "stack limit reached, use --base or configure absorb.maxStack to override";
-                  "limit" => ret.len());
+        if stop_at_signed_commits && is_signed(&commit) {
+            warn!(
+                commit = %commit.id(),
+                "Will not fix up past the signed commit, since rebasing it would drop its signature",
+            );
+            reporter.on_warning(&format!(
+                "Will not fix up past the signed commit {}",
+                commit.id()
+            ));
+            break;
+        }
+        if ret.len() == max_stack && user_provided_base.is_none() {
+            limit_reached = true;
             break;
         }
-        debug!(logger, "commit pushed onto stack"; "commit" => commit.id().to_string());
+        debug!(commit = %commit.id(), "commit pushed onto stack");
         ret.push(commit);
     }
+
+    // when the branches don't bound the stack, the branch most likely
+    // starts where it forked from the remote's default branch. a stack
+    // that they hide completely was hidden on purpose
+    let unbounded = limit_reached || ret.last().is_some_and(|commit| commit.parent_count() == 0);
+    if user_provided_base.is_none() && not.is_empty() && head.is_branch() && unbounded {
+        if let Some(base) = remote_default_base(repo, &head)? {
+            debug!(commit = %base, "using the merge-base with origin/HEAD as the base");
+            let mut ret = working_stack(
+                repo,
+                &StackOptions {
+                    base: Some(&base.to_string()),
+                    ..*options
+                },
+                reporter,
+            )?;
+            // the base is only a guess, so the limit still applies
+            if ret.len() > max_stack {
+                ret.truncate(max_stack);
+                warn_stack_limit(max_stack, reporter);
+            }
+            return Ok(ret);
+        }
+    }
+
+    if limit_reached {
+        warn_stack_limit(ret.len(), reporter);
+    }
     if commits_considered == 0 {
         if user_provided_base.is_none() {
-            warn!(logger, "Please use --base to specify a base commit.");
+            warn!("Please use --base to specify a base commit.");
+            reporter.on_warning("Please use --base to specify a base commit");
         } else {
-            warn!(logger, "Please try a different --base");
+            warn!("Please try a different --base");
+            reporter.on_warning("Please try a different --base");
         }
     }
     Ok(ret)
 }
 
+fn warn_stack_limit(limit: usize, reporter: &dyn Reporter) {
+    warn!(
+        limit,
+        "stack limit reached, use --base or --max-stack or configure absorb.maxStack to override",
+    );
+    reporter.on_warning(&format!("Stack limit of {} commits reached", limit));
+}
+
+/// The commits at the boundary of a shallow clone, whose parents were
+/// not fetched.
+fn shallow_commits(repo: &git2::Repository) -> Result<HashSet<git2::Oid>> {
+    if !repo.is_shallow() {
+        return Ok(HashSet::new());
+    }
+    let shallow = std::fs::read_to_string(repo.commondir().join("shallow"))?;
+    let commits = shallow
+        .lines()
+        .map(git2::Oid::from_str)
+        .collect::<Result<_, _>>()?;
+    Ok(commits)
+}
+
+/// Whether hiding `branch` can hide any commit of a stack whose oldest
+/// possible commit is `oldest_candidate`. A branch that doesn't contain
+/// that commit can't, so its history doesn't need to be walked.
+fn can_hide(
+    repo: &git2::Repository,
+    branch: Option<git2::Oid>,
+    oldest_candidate: Option<git2::Oid>,
+) -> Result<bool> {
+    Ok(match (branch, oldest_candidate) {
+        (Some(branch), Some(oldest)) => {
+            branch == oldest || repo.graph_descendant_of(branch, oldest)?
+        }
+        _ => true,
+    })
+}
+
+fn is_signed(commit: &git2::Commit) -> bool {
+    // sha256 repositories sign with a different header
+    ["gpgsig", "gpgsig-sha256"]
+        .iter()
+        .any(|field| commit.header_field_bytes(field).is_ok())
+}
+
+/// Whether commits by `author` can be fixed up by `sig`: either they
+/// are the same person (according to absorb.authorMatch), or the author
+/// is listed in absorb.allowedAuthors by name, email or `name <email>`.
+fn is_allowed_author(
+    author: &git2::Signature,
+    sig: &git2::Signature,
+    author_match: config::AuthorMatch,
+    allowed: &[String],
+) -> bool {
+    let same_name = author.name_bytes() == sig.name_bytes();
+    let same_email = author.email_bytes() == sig.email_bytes();
+    let same_author = match author_match {
+        config::AuthorMatch::Email => same_email,
+        config::AuthorMatch::Name => same_name,
+        config::AuthorMatch::Both => same_name && same_email,
+    };
+    if same_author {
+        return true;
+    }
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.as_bytes();
+        allowed == author.name_bytes()
+            || allowed == author.email_bytes()
+            || allowed == [author.name_bytes(), b" <", author.email_bytes(), b">"].concat()
+    })
+}
+
+/// Resolves the commit that --base names: `~N` for a number of commits,
+/// meaning `HEAD~N`, `@{push}` or `<branch>@{push}`, or any other
+/// commit-ish.
+pub fn resolve_base<'repo>(
+    repo: &'repo git2::Repository,
+    commitish: &str,
+) -> Result<git2::Commit<'repo>> {
+    // https://github.com/rust-lang/rfcs/issues/1815
+    // commitish isn't guaranteed to be a commit hash, so peel until a
+    // commit is found.
+    if let Some(refname) = push_refname(repo, commitish)? {
+        return Ok(repo.find_reference(&refname)?.peel_to_commit()?);
+    }
+    // a bare number could also be a short commit hash, so counts need
+    // the ~
+    let commitish = match commitish.strip_prefix('~') {
+        Some(count) if !count.is_empty() && count.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("HEAD~{}", count)
+        }
+        _ => commitish.to_owned(),
+    };
+    Ok(repo.revparse_single(&commitish)?.peel_to_commit()?)
+}
+
+/// Finds the commit that HEAD forked from `base` (or from HEAD's
+/// upstream branch), like `git merge-base --fork-point`: the newest
+/// of the commits that the branch ever pointed to, according to its
+/// reflog, that HEAD contains. This finds the right base even after the
+/// upstream was rebased.
+pub fn fork_point(repo: &git2::Repository, base: Option<&str>) -> Result<git2::Oid> {
+    let head = repo.head()?;
+    let refname = match base {
+        Some(spec) => match push_refname(repo, spec)? {
+            Some(refname) => refname,
+            None => {
+                let (_, reference) = repo.revparse_ext(spec)?;
+                reference
+                    .and_then(|reference| reference.name().map(str::to_owned))
+                    .ok_or_else(|| {
+                        anyhow!("--fork-point needs a branch as the base, not {}", spec)
+                    })?
+            }
+        },
+        None => upstream_refname(repo, &head)?
+            .ok_or_else(|| anyhow!("--fork-point needs a --base or an upstream branch"))?,
+    };
+    let head = head.peel_to_commit()?.id();
+    let tip = repo.refname_to_id(&refname)?;
+
+    // the branch may have been reset to an older commit since, so the
+    // newest commit in the graph wins, not the newest in the reflog
+    let reflog = repo.reflog(&refname)?;
+    let mut fork_point = None;
+    for candidate in std::iter::once(tip).chain(reflog.iter().map(|entry| entry.id_new())) {
+        if candidate != head && !repo.graph_descendant_of(head, candidate)? {
+            continue;
+        }
+        fork_point = match fork_point {
+            Some(best) if !repo.graph_descendant_of(candidate, best)? => Some(best),
+            _ => Some(candidate),
+        };
+    }
+    match fork_point {
+        Some(fork_point) => {
+            debug!(branch = %refname, commit = %fork_point, "fork point found");
+            Ok(fork_point)
+        }
+        // like git rebase --fork-point, fall back to the merge-base
+        None => Ok(repo.merge_base(head, tip)?),
+    }
+}
+
+/// Hides what --not names from the walk: a commit-ish, or a glob of
+/// refs, which is looked up like a ref name would be.
+fn hide_not(repo: &git2::Repository, revwalk: &mut git2::Revwalk, spec: &str) -> Result<()> {
+    if !spec.contains(['*', '?', '[']) {
+        let commit = resolve_base(repo, spec)?;
+        revwalk.hide(commit.id())?;
+        debug!(commit = %commit.id(), "--not commit hidden");
+        return Ok(());
+    }
+
+    let mut found = false;
+    for prefix in ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"] {
+        for reference in repo.references_glob(&format!("{}{}", prefix, spec))? {
+            let reference = reference?;
+            if let Ok(commit) = reference.peel_to_commit() {
+                revwalk.hide(commit.id())?;
+                debug!(reference = ?reference.name(), "--not ref hidden");
+                found = true;
+            }
+        }
+    }
+    if !found {
+        return Err(anyhow!("--not {} matches no refs", spec));
+    }
+    Ok(())
+}
+
+/// The merge-base of HEAD and the remote's default branch
+/// (`origin/HEAD`), unless HEAD is already on it.
+fn remote_default_base(
+    repo: &git2::Repository,
+    head: &git2::Reference,
+) -> Result<Option<git2::Oid>> {
+    let (head, default) = match (
+        head.target(),
+        repo.refname_to_id("refs/remotes/origin/HEAD"),
+    ) {
+        (Some(head), Ok(default)) => (head, default),
+        _ => return Ok(None),
+    };
+    Ok(match repo.merge_base(head, default) {
+        Ok(base) if base != head => Some(base),
+        _ => None,
+    })
+}
+
+/// The commit that the branches stacked below HEAD start from, for
+/// --update-refs: the merge-base with HEAD's upstream or with the
+/// remote's default branch, or failing those, the oldest local branch
+/// that HEAD contains. Also returns the local branches that are bases
+/// themselves, which are never part of the stack.
+fn stack_base(
+    repo: &git2::Repository,
+    head: &git2::Reference,
+) -> Result<Option<(git2::Oid, Vec<String>)>> {
+    let head_id = match head.target() {
+        Some(head_id) => head_id,
+        None => return Ok(None),
+    };
+    let mut base_branches = Vec::new();
+    if let Some(upstream) = upstream_refname(repo, head)? {
+        if upstream.starts_with("refs/heads/") {
+            base_branches.push(upstream);
+        }
+    }
+    if let Ok(default) = repo.find_reference("refs/remotes/origin/HEAD") {
+        if let Some(name) = default
+            .symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/remotes/origin/"))
+        {
+            base_branches.push(format!("refs/heads/{}", name));
+        }
+    }
+
+    if let Some(upstream) = upstream_commit(repo, head)? {
+        if let Ok(base) = repo.merge_base(head_id, upstream) {
+            return Ok(Some((base, base_branches)));
+        }
+    }
+    if let Some(base) = remote_default_base(repo, head)? {
+        return Ok(Some((base, base_branches)));
+    }
+    let mut oldest = None;
+    for branch in repo.branches(Some(git2::BranchType::Local))? {
+        let (branch, _) = branch?;
+        let branch = branch.get();
+        let id = match branch.target() {
+            Some(id) if branch.name() != head.name() => id,
+            _ => continue,
+        };
+        if id != head_id && !repo.graph_descendant_of(head_id, id)? {
+            continue;
+        }
+        oldest = match oldest {
+            Some(oldest) if !repo.graph_descendant_of(oldest, id)? => Some(oldest),
+            _ => Some(id),
+        };
+    }
+    Ok(oldest.map(|base| (base, base_branches)))
+}
+
+/// Finds the commit of HEAD's upstream branch (`@{upstream}`), or
+/// failing that, of the branch it would be pushed to (`@{push}`).
+fn upstream_commit(repo: &git2::Repository, head: &git2::Reference) -> Result<Option<git2::Oid>> {
+    Ok(match upstream_refname(repo, head)? {
+        Some(refname) => Some(repo.refname_to_id(&refname)?),
+        None => None,
+    })
+}
+
+/// The name of HEAD's upstream branch, or failing that, of the branch
+/// it would be pushed to.
+fn upstream_refname(repo: &git2::Repository, head: &git2::Reference) -> Result<Option<String>> {
+    let (head_name, branch_name) = match (head.name(), head.shorthand()) {
+        (Some(name), Some(shorthand)) if head.is_branch() => (name, shorthand),
+        _ => return Ok(None),
+    };
+
+    if let Ok(upstream) = repo.branch_upstream_name(head_name) {
+        if let Some(upstream) = upstream.as_str() {
+            if repo.refname_to_id(upstream).is_ok() {
+                return Ok(Some(upstream.to_owned()));
+            }
+        }
+    }
+    push_branch(repo, branch_name)
+}
+
+/// The name of the branch that `<branch>@{push}` (or `@{push}`, for
+/// HEAD) names, or None if `spec` isn't one.
+fn push_refname(repo: &git2::Repository, spec: &str) -> Result<Option<String>> {
+    let branch = match spec.strip_suffix("@{push}") {
+        Some("") => {
+            let head = repo.head()?;
+            match head.shorthand() {
+                Some(shorthand) if head.is_branch() => shorthand.to_owned(),
+                _ => return Err(anyhow!("HEAD is not a branch, so it has no @{{push}}")),
+            }
+        }
+        Some(branch) => branch.to_owned(),
+        None => return Ok(None),
+    };
+    match push_branch(repo, &branch)? {
+        Some(refname) => Ok(Some(refname)),
+        None => Err(anyhow!("no push branch found for {}", branch)),
+    }
+}
+
+/// Finds the branch that `branch_name` would be pushed to.
+fn push_branch(repo: &git2::Repository, branch_name: &str) -> Result<Option<String>> {
+    // libgit2 does not understand @{push}, so approximate it the way
+    // push.default=current would resolve it
+    let config = repo.config()?;
+    let push_remote = config
+        .get_string(&format!("branch.{}.pushRemote", branch_name))
+        .or_else(|_| config.get_string("remote.pushDefault"))
+        .or_else(|_| config.get_string(&format!("branch.{}.remote", branch_name)));
+    Ok(match push_remote {
+        Ok(remote) => {
+            let refname = format!("refs/remotes/{}/{}", remote, branch_name);
+            repo.refname_to_id(&refname).ok().map(|_| refname)
+        }
+        Err(_) => None,
+    })
+}
+
 pub fn summary_counts<'repo, 'a, I>(commits: I) -> HashMap<String, u64>
 where
     I: IntoIterator<Item = &'a git2::Commit<'repo>>,
@@ -118,13 +600,9 @@ where
 
 #[cfg(test)]
 mod tests {
-    use tempfile;
 
     use super::*;
-
-    fn empty_slog() -> slog::Logger {
-        slog::Logger::root(slog::Discard, o!())
-    }
+    use crate::report::NoReporter;
 
     fn init_repo() -> (tempfile::TempDir, git2::Repository) {
         // the repo will be deleted when the tempdir gets dropped
@@ -179,6 +657,18 @@ mod tests {
         ret
     }
 
+    fn default_options() -> StackOptions<'static> {
+        StackOptions {
+            base: None,
+            not: &[],
+            max_stack: config::MAX_STACK,
+            force: false,
+            cross_merges: false,
+            update_refs: false,
+            skip_head: false,
+        }
+    }
+
     fn assert_stack_matches_chain(length: usize, stack: &[git2::Commit], chain: &[git2::Commit]) {
         assert_eq!(stack.len(), length);
         for (chain_commit, stack_commit) in chain.iter().rev().take(length).zip(stack) {
@@ -194,7 +684,67 @@ mod tests {
 
         assert_stack_matches_chain(
             1,
-            &working_stack(&repo, None, false, &empty_slog()).unwrap(),
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_hides_diverged_branches() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        empty_commit(&repo, "refs/heads/side", "side", &[&commits[1]]);
+        // too old to affect a stack of 1 commit
+        repo.branch("old", &commits[0], false).unwrap();
+
+        assert_stack_matches_chain(
+            1,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    max_stack: 1,
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap(),
+            &commits,
+        );
+        assert_stack_matches_chain(
+            1,
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_skips_branches_older_than_the_stack() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        let side = empty_commit(&repo, "refs/heads/side", "side", &[&commits[0]]);
+        let oldest = Some(commits[1].id());
+
+        assert!(can_hide(&repo, Some(commits[1].id()), oldest).unwrap());
+        assert!(can_hide(&repo, Some(commits[2].id()), oldest).unwrap());
+        // neither contains commits[1], so hiding them can't change the
+        // stack
+        assert!(!can_hide(&repo, Some(commits[0].id()), oldest).unwrap());
+        assert!(!can_hide(&repo, Some(side.id()), oldest).unwrap());
+        // without a head to count from, every branch is hidden
+        assert!(can_hide(&repo, Some(side.id()), None).unwrap());
+
+        // the skipped branches don't hide anything either way
+        assert_stack_matches_chain(
+            2,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    max_stack: 2,
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap(),
             &commits,
         );
     }
@@ -209,15 +759,149 @@ mod tests {
             2,
             &working_stack(
                 &repo,
-                Some(&commits[0].id().to_string()),
-                false,
-                &empty_slog(),
+                &StackOptions {
+                    base: Some(&commits[0].id().to_string()),
+                    ..default_options()
+                },
+                &NoReporter,
             )
             .unwrap(),
             &commits,
         );
     }
 
+    #[test]
+    fn test_stack_resolves_base_counts_and_push() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        let stack = |base| {
+            working_stack(
+                &repo,
+                &StackOptions {
+                    base: Some(base),
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap()
+        };
+
+        assert_stack_matches_chain(1, &stack("~1"), &commits);
+        assert_stack_matches_chain(2, &stack("~2"), &commits);
+        assert!(resolve_base(&repo, "1").is_err());
+
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        repo.reference(
+            &format!("refs/remotes/origin/{}", branch),
+            commits[0].id(),
+            false,
+            "",
+        )
+        .unwrap();
+        repo.config()
+            .unwrap()
+            .set_str(&format!("branch.{}.pushRemote", branch), "origin")
+            .unwrap();
+        assert_stack_matches_chain(2, &stack("@{push}"), &commits);
+    }
+
+    #[test]
+    fn test_stack_hides_not_refs() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        let stack = |not: &[&str]| {
+            let not: Vec<String> = not.iter().map(|spec| spec.to_string()).collect();
+            working_stack(
+                &repo,
+                &StackOptions {
+                    not: &not,
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+        };
+        repo.reference("refs/remotes/origin/release/1", commits[1].id(), false, "")
+            .unwrap();
+        repo.reference("refs/tags/v1", commits[0].id(), false, "")
+            .unwrap();
+
+        assert_stack_matches_chain(4, &stack(&[]).unwrap(), &commits);
+        assert_stack_matches_chain(3, &stack(&["v*"]).unwrap(), &commits);
+        assert_stack_matches_chain(2, &stack(&["v1", "origin/release/*"]).unwrap(), &commits);
+        assert_stack_matches_chain(1, &stack(&["HEAD~1", "v*"]).unwrap(), &commits);
+        assert!(stack(&["nothing/*"]).is_err());
+    }
+
+    #[test]
+    fn test_stack_falls_back_to_remote_default_branch() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        let stack = |max_stack| {
+            working_stack(
+                &repo,
+                &StackOptions {
+                    max_stack,
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap()
+        };
+        assert_stack_matches_chain(4, &stack(config::MAX_STACK), &commits);
+
+        repo.reference("refs/remotes/origin/main", commits[1].id(), false, "")
+            .unwrap();
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            false,
+            "",
+        )
+        .unwrap();
+        // the walk would reach the root commit, or the stack limit,
+        // which still applies
+        assert_stack_matches_chain(2, &stack(config::MAX_STACK), &commits);
+        assert_stack_matches_chain(1, &stack(1), &commits);
+
+        // but a branch that bounds the stack wins
+        repo.branch("hide", &commits[2], false).unwrap();
+        assert_stack_matches_chain(1, &stack(config::MAX_STACK), &commits);
+
+        // even if it hides all of it
+        repo.branch("all", &commits[3], false).unwrap();
+        assert_stack_matches_chain(0, &stack(config::MAX_STACK), &commits);
+    }
+
+    #[test]
+    fn test_fork_point_survives_rewritten_upstream() {
+        let (_dir, repo) = init_repo();
+        // main: 0 - 1 - 2, then rewritten to 0 - 3
+        let main = empty_commit_chain(&repo, "refs/heads/main", &[], 3);
+        let feature = empty_commit(&repo, "refs/heads/feature", "feature", &[&main[2]]);
+        repo.set_head("refs/heads/feature").unwrap();
+        repo.reference("refs/heads/main", main[0].id(), true, "reset")
+            .unwrap();
+        empty_commit(&repo, "refs/heads/main", "3", &[&main[0]]);
+
+        let fork_point = fork_point(&repo, Some("main")).unwrap().to_string();
+        assert_eq!(resolve_base(&repo, &fork_point).unwrap().id(), main[2].id());
+        let stack = |base| {
+            working_stack(
+                &repo,
+                &StackOptions {
+                    base: Some(base),
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap()
+        };
+        assert_eq!(stack(&fork_point)[0].id(), feature.id());
+        assert_eq!(stack(&fork_point).len(), 1);
+        // the rewritten branch itself would take its old commits along
+        assert_eq!(stack("main").len(), 3);
+    }
+
     #[test]
     fn test_stack_stops_at_configured_limit() {
         let (_dir, repo) = init_repo();
@@ -232,7 +916,15 @@ mod tests {
 
         assert_stack_matches_chain(
             config::MAX_STACK + 1,
-            &working_stack(&repo, None, false, &empty_slog()).unwrap(),
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    max_stack: config::max_stack(&repo),
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap(),
             &commits,
         );
     }
@@ -249,9 +941,86 @@ mod tests {
 
         assert_stack_matches_chain(
             2,
-            &working_stack(&repo, None, false, &empty_slog()).unwrap(),
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &new_commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_matches_author_by_email() {
+        let (_dir, repo) = init_repo();
+        let old_commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "renamed").unwrap();
+        let new_commits = empty_commit_chain(&repo, "HEAD", &[old_commits.last().unwrap()], 2);
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
             &new_commits,
         );
+
+        config
+            .set_str(config::AUTHOR_MATCH_CONFIG_NAME, "email")
+            .unwrap();
+        let stack = working_stack(&repo, &default_options(), &NoReporter).unwrap();
+        assert_eq!(stack.len(), 5);
+    }
+
+    #[test]
+    fn test_stack_includes_allowed_authors() {
+        let (_dir, repo) = init_repo();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "teammate").unwrap();
+        config
+            .set_str("user.email", "teammate@example.com")
+            .unwrap();
+        let old_commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        config.set_str("user.name", "nobody").unwrap();
+        config.set_str("user.email", "nobody@example.com").unwrap();
+        let new_commits = empty_commit_chain(&repo, "HEAD", &[old_commits.last().unwrap()], 2);
+
+        config
+            .set_str(config::ALLOWED_AUTHORS_CONFIG_NAME, "teammate@example.com")
+            .unwrap();
+        let stack = working_stack(&repo, &default_options(), &NoReporter).unwrap();
+        assert_eq!(stack.len(), 5);
+        assert_eq!(stack[0].id(), new_commits[1].id());
+        assert_eq!(stack[4].id(), old_commits[0].id());
+    }
+
+    #[test]
+    fn test_stack_hides_upstream_if_configured() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        repo.reference("refs/remotes/origin/main", commits[0].id(), false, "")
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{}.remote", branch), "origin")
+            .unwrap();
+        config
+            .set_str(&format!("branch.{}.merge", branch), "refs/heads/main")
+            .unwrap();
+
+        // without the config, there is nothing to stop the walk
+        assert_stack_matches_chain(
+            3,
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &commits,
+        );
+
+        config
+            .set_bool(config::AUTO_DETECT_BASE_CONFIG_NAME, true)
+            .unwrap();
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &commits,
+        );
     }
 
     #[test]
@@ -267,8 +1036,147 @@ mod tests {
 
         assert_stack_matches_chain(
             2,
-            &working_stack(&repo, None, false, &empty_slog()).unwrap(),
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
             &commits,
         );
     }
+
+    #[test]
+    fn test_stack_crosses_merges_if_configured() {
+        let (_dir, repo) = init_repo();
+        let first = empty_commit(&repo, "HEAD", "first", &[]);
+        repo.set_head("refs/heads/new").unwrap();
+        let second = empty_commit(&repo, "HEAD", "second", &[]);
+        let merge = empty_commit(&repo, "HEAD", "merge", &[&second, &first]);
+        let commits = empty_commit_chain(&repo, "HEAD", &[&merge], 2);
+
+        let stack = working_stack(
+            &repo,
+            &StackOptions {
+                cross_merges: true,
+                ..default_options()
+            },
+            &NoReporter,
+        )
+        .unwrap();
+        let ids: Vec<_> = stack.iter().map(|commit| commit.id()).collect();
+        // the walk follows the first parent only
+        assert_eq!(
+            ids,
+            vec![commits[1].id(), commits[0].id(), merge.id(), second.id()]
+        );
+    }
+
+    #[test]
+    fn test_stack_stops_at_signed_commits_if_configured() {
+        let (_dir, repo) = init_repo();
+        let first = empty_commit(&repo, "HEAD", "first", &[]);
+        let signed = {
+            let sig = repo.signature().unwrap();
+            let buffer = repo
+                .commit_create_buffer(&sig, &sig, "signed", &first.tree().unwrap(), &[&first])
+                .unwrap();
+            let id = repo
+                .commit_signed(
+                    buffer.as_str().unwrap(),
+                    "-----BEGIN PGP SIGNATURE-----\nfake\n-----END PGP SIGNATURE-----",
+                    None,
+                )
+                .unwrap();
+            repo.head()
+                .unwrap()
+                .set_target(id, "signed commit")
+                .unwrap();
+            repo.find_commit(id).unwrap()
+        };
+        let commits = empty_commit_chain(&repo, "HEAD", &[&signed], 2);
+
+        assert_eq!(
+            working_stack(&repo, &default_options(), &NoReporter)
+                .unwrap()
+                .len(),
+            4
+        );
+
+        repo.config()
+            .unwrap()
+            .set_bool(config::STOP_AT_SIGNED_COMMITS_CONFIG_NAME, true)
+            .unwrap();
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_includes_stacked_branches_with_update_refs() {
+        let (_dir, repo) = init_repo();
+        repo.set_head("refs/heads/feature").unwrap();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        repo.branch("main", &commits[0], false).unwrap();
+        repo.branch("stacked", &commits[2], false).unwrap();
+
+        assert_stack_matches_chain(
+            1,
+            &working_stack(&repo, &default_options(), &NoReporter).unwrap(),
+            &commits,
+        );
+        assert_stack_matches_chain(
+            3,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    update_refs: true,
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap(),
+            &commits,
+        );
+        // the base branch is never part of the stack, even if nothing
+        // is below it
+        repo.find_branch("main", git2::BranchType::Local)
+            .unwrap()
+            .delete()
+            .unwrap();
+        assert_stack_matches_chain(
+            1,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    update_refs: true,
+                    ..default_options()
+                },
+                &NoReporter,
+            )
+            .unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_stops_at_shallow_boundary() {
+        let (dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        std::fs::write(
+            repo.path().join("shallow"),
+            format!("{}\n", commits[1].id()),
+        )
+        .unwrap();
+        let repo = git2::Repository::open(dir.path()).unwrap();
+        assert!(repo.is_shallow());
+
+        let stack = working_stack(
+            &repo,
+            &StackOptions {
+                max_stack: 10,
+                ..default_options()
+            },
+            &NoReporter,
+        )
+        .unwrap();
+        assert_stack_matches_chain(2, &stack, &commits);
+    }
 }
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::Reason;
+
+/// A human-readable summary of a run, printed at its end.
+pub struct Summary {
+    dry_run: bool,
+    targets: Vec<Target>,
+    leftovers: BTreeMap<String, usize>,
+    rebase_command: Option<String>,
+}
+
+struct Target {
+    id: git2::Oid,
+    short_id: String,
+    summary: String,
+    hunks: usize,
+    fixups: usize,
+    conflicts: bool,
+}
+
+impl Summary {
+    pub fn new(dry_run: bool) -> Self {
+        Summary {
+            dry_run,
+            targets: Vec::new(),
+            leftovers: BTreeMap::new(),
+            rebase_command: None,
+        }
+    }
+
+    fn target(&mut self, commit: &git2::Commit) -> &mut Target {
+        let index = match self.targets.iter().position(|t| t.id == commit.id()) {
+            Some(index) => index,
+            None => {
+                let short_id = commit
+                    .as_object()
+                    .short_id()
+                    .ok()
+                    .and_then(|id| id.as_str().map(str::to_owned))
+                    .unwrap_or_else(|| commit.id().to_string());
+                self.targets.push(Target {
+                    id: commit.id(),
+                    short_id,
+                    summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or(b""))
+                        .into_owned(),
+                    hunks: 0,
+                    fixups: 0,
+                    conflicts: false,
+                });
+                self.targets.len() - 1
+            }
+        };
+        &mut self.targets[index]
+    }
+
+    /// Records that a hunk will be absorbed into `commit`.
+    pub fn absorbed(&mut self, commit: &git2::Commit) {
+        self.target(commit).hunks += 1;
+    }
+
+    /// Records that a fixup was (or would have been) created for
+    /// `commit`.
+    pub fn fixup(&mut self, commit: &git2::Commit) {
+        self.target(commit).fixups += 1;
+    }
+
+    /// Records that squashing the fixups for `commit` will likely
+    /// conflict.
+    pub fn conflict(&mut self, commit: &git2::Commit) {
+        self.target(commit).conflicts = true;
+    }
+
+    pub fn leftover(&mut self, reason: Reason) {
+        *self.leftovers.entry(reason.to_string()).or_default() += 1;
+    }
+
+    /// Records that the fixups still have to be squashed with
+    /// `command`.
+    pub fn rebase_required(&mut self, command: String) {
+        self.rebase_command = Some(command);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty() && self.leftovers.is_empty()
+    }
+}
+
+fn plural(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.targets.is_empty() {
+            let hunks = self.targets.iter().map(|t| t.hunks).sum();
+            writeln!(
+                f,
+                "{} {} into {}:",
+                if self.dry_run {
+                    "Would absorb"
+                } else {
+                    "Absorbed"
+                },
+                plural(hunks, "hunk"),
+                plural(self.targets.len(), "commit"),
+            )?;
+            for target in &self.targets {
+                writeln!(
+                    f,
+                    "  {} {} ({}, {}{})",
+                    target.short_id,
+                    target.summary,
+                    plural(target.hunks, "hunk"),
+                    plural(target.fixups, "fixup"),
+                    if target.conflicts {
+                        ", likely to conflict"
+                    } else {
+                        ""
+                    },
+                )?;
+            }
+        }
+        if !self.leftovers.is_empty() {
+            let hunks = self.leftovers.values().sum();
+            writeln!(f, "Left {} in the index:", plural(hunks, "hunk"))?;
+            for (reason, count) in &self.leftovers {
+                writeln!(f, "  {}: {}", plural(*count, "hunk"), reason)?;
+            }
+        }
+        if let Some(command) = &self.rebase_command {
+            writeln!(f, "Run `{}` to squash the fixups.", command)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("nobody", "nobody@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let id = repo
+            .commit(Some("HEAD"), &sig, &sig, "Add a thing", &tree, &[])
+            .unwrap();
+        let commit = repo.find_commit(id).unwrap();
+        let short_id = commit.as_object().short_id().unwrap();
+
+        let mut summary = Summary::new(false);
+        assert!(summary.is_empty());
+        summary.absorbed(&commit);
+        summary.absorbed(&commit);
+        summary.fixup(&commit);
+        summary.leftover(Reason::StackExhausted);
+        summary.rebase_required("git rebase -i --autosquash --root".to_owned());
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "Absorbed 2 hunks into 1 commit:\n  \
+                 {} Add a thing (2 hunks, 1 fixup)\n\
+                 Left 1 hunk in the index:\n  \
+                 1 hunk: {}\n\
+                 Run `git rebase -i --autosquash --root` to squash the fixups.\n",
+                short_id.as_str().unwrap(),
+                Reason::StackExhausted,
+            )
+        );
+
+        summary.conflict(&commit);
+        assert!(summary
+            .to_string()
+            .contains("Add a thing (2 hunks, 1 fixup, likely to conflict)\n"));
+    }
+}